@@ -4,7 +4,9 @@ qemu-system-x86_64 \
     -device ahci,id=ahci \
     -device ide-hd,drive=disk,bus=ahci.0 \
     -m 1G \
+    -smp 4 \
     -serial stdio \
+    -debugcon file:debugcon.log \
     -no-reboot \
     -no-shutdown \
     "$@"
\ No newline at end of file
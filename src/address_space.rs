@@ -0,0 +1,192 @@
+use core::ops::{Deref, DerefMut};
+
+use crate::pager::Pager;
+use crate::percpu;
+use crate::sync::{SpinLock, SpinLockGuard};
+use crate::PAGE_TABLE;
+
+pub const MAX_ADDRESS_SPACES: usize = 64;
+
+// PCID 0 belongs to the kernel page table
+const NUM_PCIDS: usize = 4096;
+
+// Address space with a private user half and the kernel half shared with every other one
+pub struct AddressSpace {
+    pager: Pager
+}
+
+impl AddressSpace {
+    // Get page table of address space
+    pub fn pager(&mut self) -> &mut Pager {
+        &mut self.pager
+    }
+}
+
+// Address space locked for as long as this is held. Its user half mustn't be touched meanwhile, faults there
+// lock the address space too
+pub struct AddressSpaceGuard {
+    guard: SpinLockGuard<'static, Option<AddressSpace>>
+}
+
+impl Deref for AddressSpaceGuard {
+    type Target = AddressSpace;
+
+    fn deref(&self) -> &AddressSpace {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for AddressSpaceGuard {
+    fn deref_mut(&mut self) -> &mut AddressSpace {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ADDRESS_SPACE: SpinLock<Option<AddressSpace>> = SpinLock::new(None);
+
+// Each slot has a lock of its own, so address spaces used on different CPUs don't wait for each other
+static ADDRESS_SPACES: [SpinLock<Option<AddressSpace>>; MAX_ADDRESS_SPACES] = [NO_ADDRESS_SPACE; MAX_ADDRESS_SPACES];
+static PCID_MAP: SpinLock<[u64; NUM_PCIDS / 64]> = SpinLock::new([0; NUM_PCIDS / 64]);
+percpu! {
+    // Address space loaded on this CPU, None while the kernel page table is loaded
+    static CURRENT: Option<usize> = None;
+}
+
+// Reserve free PCID, returns None if all are taken
+fn allocate_pcid() -> Option<u16> {
+    let mut map = PCID_MAP.lock();
+    let pcid = (1..NUM_PCIDS).find(|&p| map[p / 64] & (1 << (p % 64)) == 0)?;
+    map[pcid / 64] |= 1 << (pcid % 64);
+    Some(pcid as u16)
+}
+
+fn free_pcid(pcid: u16) {
+    let pcid = pcid as usize;
+    PCID_MAP.lock()[pcid / 64] &= !(1 << (pcid % 64));
+}
+
+// Store page table in free slot, returning its id
+fn insert(mut pager: Pager) -> Option<usize> {
+    let pcid = match allocate_pcid() {
+        None => {
+            unsafe {
+                pager.destroy();
+            }
+            return None
+        },
+        Some(p) => p
+    };
+    pager.set_pcid(pcid);
+
+    for (id, slot) in ADDRESS_SPACES.iter().enumerate() {
+        let mut slot = slot.lock();
+        if slot.is_none() {
+            *slot = Some(AddressSpace { pager });
+            return Some(id)
+        }
+    }
+
+    unsafe {
+        pager.destroy();
+    }
+    free_pcid(pcid);
+    None
+}
+
+// Create address space with empty user half
+pub fn create() -> Option<usize> {
+    // The kernel page table is unlocked before taking a slot, which copying address spaces lock the other way round
+    let pager = unsafe { Pager::new_user(&PAGE_TABLE.lock())? };
+    insert(pager)
+}
+
+// Create copy of address space, owned user pages are copied
+pub fn clone(id: usize) -> Option<usize> {
+    let pager = unsafe { get(id)?.pager.clone_user_half(&PAGE_TABLE.lock())? };
+    insert(pager)
+}
+
+// Create copy of address space sharing owned user pages copy-on-write
+pub fn fork(id: usize) -> Option<usize> {
+    let pager = unsafe { get(id)?.pager.fork_user_half(&PAGE_TABLE.lock())? };
+    insert(pager)
+}
+
+// Lock address space by id
+pub fn get(id: usize) -> Option<AddressSpaceGuard> {
+    let guard = ADDRESS_SPACES.get(id)?.lock();
+    match guard.is_some() {
+        false => None,
+        true => Some(AddressSpaceGuard { guard })
+    }
+}
+
+// Get id of address space loaded on this CPU
+pub fn current() -> Option<usize> {
+    CURRENT.get()
+}
+
+// Iterate ids of every live address space
+pub fn ids() -> impl Iterator<Item = usize> {
+    (0..MAX_ADDRESS_SPACES).filter(|&i| ADDRESS_SPACES[i].lock().is_some())
+}
+
+// Load address space on this CPU
+pub fn switch_to(id: usize) -> bool {
+    match get(id) {
+        None => false,
+        Some(mut s) => unsafe {
+            s.pager.activate();
+            CURRENT.set(Some(id));
+            true
+        }
+    }
+}
+
+// Load kernel page table on this CPU
+pub fn switch_to_kernel() {
+    unsafe {
+        PAGE_TABLE.lock().activate();
+        CURRENT.set(None);
+    }
+}
+
+// Run f with address space loaded on this CPU, such as to copy into its user half, then load the one that
+// was loaded before. Returns None if there is no such address space
+pub fn with<R>(id: usize, f: impl FnOnce() -> R) -> Option<R> {
+    let previous = current();
+    if !switch_to(id) {
+        return None
+    }
+    let result = f();
+    match previous {
+        None => switch_to_kernel(),
+        Some(p) => {
+            switch_to(p);
+        }
+    }
+    Some(result)
+}
+
+// Destroy address space, returning its frames and tables, fails for the loaded address space
+pub fn destroy(id: usize) -> bool {
+    if current() == Some(id) {
+        return false
+    }
+
+    let space = match ADDRESS_SPACES.get(id) {
+        None => return false,
+        Some(slot) => slot.lock().take()
+    };
+
+    match space {
+        None => false,
+        Some(mut s) => unsafe {
+            s.pager.destroy();
+            // Stale translations tagged with the PCID are gone, so it may be reused
+            free_pcid(s.pager.pcid());
+            true
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::asm_wrappers::{rdmsr, wrmsr, MSR_APIC_BASE};
+use crate::constants::PAGE_SIZE;
+use crate::vma::{self, Backing};
+use crate::PAGE_TABLE;
+
+// Vectors used by local APIC interrupts
+pub const VECTOR_TIMER:         u8 = 0xF0;
+pub const VECTOR_RESCHEDULE:    u8 = 0xFC;
+pub const VECTOR_TLB_SHOOTDOWN: u8 = 0xFD;
+pub const VECTOR_SPURIOUS:      u8 = 0xFF;
+
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR:   u64 = 0x000F_FFFF_FFFF_F000;
+
+// Register offsets in the xAPIC page, x2APIC has them as MSRs at 0x800 + offset / 16
+const REG_TPR:      usize = 0x080;
+const REG_EOI:      usize = 0x0B0;
+const REG_SVR:      usize = 0x0F0;
+const REG_ICR_LOW:  usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER:     usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE:  usize = 0x3E0;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const SVR_ENABLE:          u32 = 1 << 8;
+const ICR_LEVEL_ASSERT:    u32 = 1 << 14;
+const ICR_DELIVERY_STATUS: u32 = 1 << 12;
+const LVT_MASKED:          u32 = 1 << 16;
+const LVT_TIMER_PERIODIC:  u32 = 1 << 17;
+// Timer counts down at bus clock divided by 16
+const TIMER_DIVIDE_16:     u32 = 0x3;
+
+static mut X2APIC: bool = false;
+static mut MMIO: usize = 0;
+
+// Choose register access mode and map the xAPIC page if needed, must run on the bootstrap processor first
+pub unsafe fn init(x2apic: bool) -> bool {
+    X2APIC = x2apic;
+    if x2apic {
+        return true
+    }
+
+    let paddr = (rdmsr(MSR_APIC_BASE) & APIC_BASE_ADDR) as usize;
+    match PAGE_TABLE.lock().mmap(None, PAGE_SIZE, vma::VMA_READ | vma::VMA_WRITE, Backing::Device { paddr }) {
+        None => false,
+        Some(p) => {
+            MMIO = p as usize;
+            true
+        }
+    }
+}
+
+pub fn is_x2apic() -> bool {
+    unsafe { X2APIC }
+}
+
+unsafe fn read(reg: usize) -> u32 {
+    match X2APIC {
+        true => rdmsr(X2APIC_MSR_BASE + (reg >> 4) as u32) as u32,
+        false => core::ptr::read_volatile((MMIO + reg) as *const u32)
+    }
+}
+
+unsafe fn write(reg: usize, value: u32) {
+    match X2APIC {
+        true => wrmsr(X2APIC_MSR_BASE + (reg >> 4) as u32, value as u64),
+        false => core::ptr::write_volatile((MMIO + reg) as *mut u32, value)
+    }
+}
+
+// Enable local APIC of this CPU and let every interrupt priority through
+pub unsafe fn init_cpu() {
+    let mut base = rdmsr(MSR_APIC_BASE) | APIC_BASE_ENABLE;
+    if X2APIC {
+        base |= APIC_BASE_X2APIC;
+    }
+    wrmsr(MSR_APIC_BASE, base);
+
+    write(REG_TPR, 0);
+    write(REG_SVR, read(REG_SVR) | SVR_ENABLE | VECTOR_SPURIOUS as u32);
+}
+
+// Signal end of interrupt, every handler of an APIC interrupt but the spurious one must call this
+pub fn eoi() {
+    unsafe {
+        write(REG_EOI, 0);
+    }
+}
+
+// Send fixed interrupt to CPU with APIC ID
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    unsafe {
+        match X2APIC {
+            // Writing the 64-bit ICR sends at once
+            true => wrmsr(X2APIC_MSR_BASE + (REG_ICR_LOW >> 4) as u32,
+                          ((apic_id as u64) << 32) | (ICR_LEVEL_ASSERT | vector as u32) as u64),
+            false => {
+                write(REG_ICR_HIGH, apic_id << 24);
+                write(REG_ICR_LOW, ICR_LEVEL_ASSERT | vector as u32);
+                while read(REG_ICR_LOW) & ICR_DELIVERY_STATUS != 0 {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+
+// Start timer of this CPU counting down from initial, raising vector when it reaches zero unless None.
+// Periodic timers reload initial and keep going
+pub fn start_timer(vector: Option<u8>, initial: u32, periodic: bool) {
+    let mut lvt = match vector {
+        None => LVT_MASKED,
+        Some(v) => v as u32
+    };
+    if periodic {
+        lvt |= LVT_TIMER_PERIODIC;
+    }
+
+    unsafe {
+        write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+        write(REG_LVT_TIMER, lvt);
+        write(REG_TIMER_INITIAL, initial);
+    }
+}
+
+pub fn stop_timer() {
+    unsafe {
+        write(REG_LVT_TIMER, LVT_MASKED);
+        write(REG_TIMER_INITIAL, 0);
+    }
+}
+
+// Get current count of the timer of this CPU
+pub fn timer_count() -> u32 {
+    unsafe { read(REG_TIMER_CURRENT) }
+}
@@ -1,4 +1,106 @@
-use core::arch::asm;
+use core::arch::{asm, global_asm};
+use core::ops::BitOr;
+
+// Model specific registers
+pub const MSR_APIC_BASE:      u32 = 0x0000_001B;
+pub const MSR_EFER:           u32 = 0xC000_0080;
+pub const MSR_STAR:           u32 = 0xC000_0081;
+pub const MSR_LSTAR:          u32 = 0xC000_0082;
+pub const MSR_SFMASK:         u32 = 0xC000_0084;
+pub const MSR_GS_BASE:        u32 = 0xC000_0101;
+pub const MSR_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+// Interrupt enable flag in RFLAGS
+pub const RFLAGS_IF: u64 = 1 << 9;
+
+// Extended control register holding the XSAVE feature mask
+pub const XCR0: u32 = 0;
+
+// Newtype over register bits with named flags, so callers never spell out bit positions
+macro_rules! register_flags {
+    ($(#[$meta:meta])* $name:ident: $t:ty { $($(#[$fmeta:meta])* $flag:ident = $bit:expr;)* }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        pub struct $name($t);
+
+        impl $name {
+            $($(#[$fmeta])* pub const $flag: $name = $name(1 << $bit);)*
+
+            // Keeps reserved and unnamed bits, which must be written back unchanged
+            pub const fn from_bits(bits: $t) -> Self {
+                $name(bits)
+            }
+
+            pub const fn bits(&self) -> $t {
+                self.0
+            }
+
+            pub const fn with(self, other: $name) -> Self {
+                $name(self.0 | other.0)
+            }
+        }
+
+        impl BitOr for $name {
+            type Output = $name;
+
+            fn bitor(self, other: $name) -> $name {
+                self.with(other)
+            }
+        }
+    };
+}
+
+// Testing and clearing bits, for the registers that get read back and changed in place
+macro_rules! register_masks {
+    ($($name:ident),*) => {
+        $(impl $name {
+            pub const fn contains(&self, other: $name) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            pub const fn without(self, other: $name) -> Self {
+                $name(self.0 & !other.0)
+            }
+        })*
+    };
+}
+
+register_flags! {
+    Cr0: usize {
+        // Monitor coprocessor, makes WAIT honour TS
+        MP = 1;
+        // Emulate FPU, every x87/SSE instruction faults with #NM
+        EM = 2;
+        // Task switched, next FPU instruction faults with #NM
+        TS = 3;
+        // Native x87 error reporting
+        NE = 5;
+    }
+}
+
+register_flags! {
+    Cr4: usize {
+        // Global pages
+        PGE = 7;
+        // FXSAVE and SSE enabled
+        OSFXSR = 9;
+        OSXMMEXCPT = 10;
+        UMIP = 11;
+        PCIDE = 17;
+        OSXSAVE = 18;
+        SMEP = 20;
+        SMAP = 21;
+    }
+}
+
+register_flags! {
+    Efer: u64 {
+        // SYSCALL and SYSRET
+        SCE = 0;
+    }
+}
+
+register_masks!(Cr0, Cr4);
 
 pub unsafe extern "C" fn inb(port: u16) -> u8 {
     let mut _data: u8 = 0x00;
@@ -30,6 +132,283 @@ pub unsafe extern "C" fn outd(port: u16, data: u32) {
     asm!("out dx, eax", in("dx") port, in("eax") data);
 }
 
+pub unsafe extern "C" fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    ((high as u64) << 32) | low as u64
+}
+
+pub unsafe extern "C" fn wrmsr(msr: u32, value: u64) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack));
+}
+
+pub unsafe extern "C" fn rcr0() -> usize {
+    let mut _data: usize = 0;
+    asm!("mov {p}, cr0", p = out(reg) _data);
+    _data
+}
+
+pub unsafe extern "C" fn lcr0(data: usize) {
+    asm!("mov cr0, {p}", p = in(reg) data);
+}
+
 pub unsafe extern "C" fn lcr3(pml4t_phys_addr: usize) {
     asm!("mov cr3, {p}", p = in(reg) pml4t_phys_addr);
-}
\ No newline at end of file
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32
+}
+
+pub unsafe extern "C" fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    // rbx is reserved by LLVM, so preserve it around cpuid
+    asm!("mov {t:r}, rbx", "cpuid", "xchg {t:r}, rbx",
+         t = out(reg) ebx,
+         inout("eax") leaf => eax,
+         inout("ecx") subleaf => ecx,
+         out("edx") edx);
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+pub unsafe extern "C" fn rcr2() -> usize {
+    let mut _data: usize = 0;
+    asm!("mov {p}, cr2", p = out(reg) _data);
+    _data
+}
+
+pub unsafe extern "C" fn rcr3() -> usize {
+    let mut _data: usize = 0;
+    asm!("mov {p}, cr3", p = out(reg) _data);
+    _data
+}
+
+pub unsafe extern "C" fn rcr4() -> usize {
+    let mut _data: usize = 0;
+    asm!("mov {p}, cr4", p = out(reg) _data);
+    _data
+}
+
+pub unsafe extern "C" fn lcr4(data: usize) {
+    asm!("mov cr4, {p}", p = in(reg) data);
+}
+
+pub unsafe fn read_cr0() -> Cr0 {
+    Cr0::from_bits(rcr0())
+}
+
+pub unsafe fn write_cr0(flags: Cr0) {
+    lcr0(flags.bits());
+}
+
+pub unsafe fn read_cr4() -> Cr4 {
+    Cr4::from_bits(rcr4())
+}
+
+pub unsafe fn write_cr4(flags: Cr4) {
+    lcr4(flags.bits());
+}
+
+pub unsafe fn read_efer() -> Efer {
+    Efer::from_bits(rdmsr(MSR_EFER))
+}
+
+pub unsafe fn write_efer(flags: Efer) {
+    wrmsr(MSR_EFER, flags.bits());
+}
+
+pub unsafe extern "C" fn xsetbv(xcr: u32, value: u64) {
+    asm!("xsetbv", in("ecx") xcr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack));
+}
+
+// Clear CR0.TS so FPU instructions stop raising #NM
+pub unsafe extern "C" fn clts() {
+    asm!("clts", options(nomem, nostack));
+}
+
+// Legacy 512 byte FPU and SSE state, area must be 16 byte aligned
+pub unsafe extern "C" fn fxsave(area: *mut u8) {
+    asm!("fxsave64 [{a}]", a = in(reg) area, options(nostack));
+}
+
+pub unsafe extern "C" fn fxrstor(area: *const u8) {
+    asm!("fxrstor64 [{a}]", a = in(reg) area, options(nostack));
+}
+
+// Extended state components selected by mask, area must be 64 byte aligned
+pub unsafe extern "C" fn xsave(area: *mut u8, mask: u64) {
+    asm!("xsave64 [{a}]", a = in(reg) area, in("eax") mask as u32, in("edx") (mask >> 32) as u32, options(nostack));
+}
+
+pub unsafe extern "C" fn xrstor(area: *const u8, mask: u64) {
+    asm!("xrstor64 [{a}]", a = in(reg) area, in("eax") mask as u32, in("edx") (mask >> 32) as u32, options(nostack));
+}
+
+pub unsafe extern "C" fn invlpg(vaddr: usize) {
+    asm!("invlpg [{p}]", p = in(reg) vaddr, options(nostack));
+}
+
+#[repr(C, align(16))]
+struct InvpcidDescriptor {
+    pcid: u64,
+    vaddr: u64
+}
+
+// Types: 0 = single address, 1 = single context, 2 = all contexts including globals, 3 = all contexts
+pub unsafe extern "C" fn invpcid(kind: u64, pcid: u16, vaddr: usize) {
+    let descriptor = InvpcidDescriptor { pcid: pcid as u64, vaddr: vaddr as u64 };
+    asm!("invpcid {k}, [{d}]", k = in(reg) kind, d = in(reg) &descriptor, options(nostack));
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64
+}
+
+pub unsafe extern "C" fn lidt(base: usize, limit: u16) {
+    let pointer = DescriptorTablePointer { limit, base: base as u64 };
+    asm!("lidt [{p}]", p = in(reg) &pointer, options(nostack));
+}
+
+pub unsafe extern "C" fn lgdt(base: usize, limit: u16) {
+    let pointer = DescriptorTablePointer { limit, base: base as u64 };
+    asm!("lgdt [{p}]", p = in(reg) &pointer, options(nostack));
+}
+
+pub unsafe extern "C" fn ltr(selector: u16) {
+    asm!("ltr {s:x}", s = in(reg) selector, options(nostack));
+}
+
+// Reload CS through a far return and every data segment register
+pub unsafe extern "C" fn load_segments(code: u16, data: u16) {
+    asm!("push {c}",
+         "lea {t}, [rip + 2f]",
+         "push {t}",
+         "retfq",
+         "2:",
+         "mov ds, {d:x}",
+         "mov es, {d:x}",
+         "mov fs, {d:x}",
+         "mov gs, {d:x}",
+         "mov ss, {d:x}",
+         c = in(reg) code as u64,
+         d = in(reg) data,
+         t = out(reg) _);
+}
+
+// Continue on new stack by calling function that never returns
+pub unsafe extern "C" fn switch_stack(top: usize, f: extern "C" fn() -> !) -> ! {
+    asm!("mov rsp, {t}",
+         "xor rbp, rbp",
+         "call {f}",
+         t = in(reg) top,
+         f = in(reg) f,
+         options(noreturn));
+}
+
+pub unsafe extern "C" fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+    ((high as u64) << 32) | low as u64
+}
+
+// Read quadword at offset from GS base, which points at the per-CPU area in kernel mode
+pub unsafe extern "C" fn read_gs_u64(offset: usize) -> u64 {
+    let value: u64;
+    asm!("mov {v}, gs:[{o}]", v = out(reg) value, o = in(reg) offset, options(readonly, nostack, preserves_flags));
+    value
+}
+
+pub unsafe extern "C" fn read_rflags() -> u64 {
+    let value: u64;
+    asm!("pushfq", "pop {v}", v = out(reg) value, options(nomem, preserves_flags));
+    value
+}
+
+// Exchange GS base with KERNEL_GS_BASE, on every entry from and exit to user mode
+pub unsafe extern "C" fn swapgs() {
+    asm!("swapgs", options(nostack));
+}
+
+// Allow supervisor accesses to user pages while SMAP is on, faults with #UD if SMAP isn't supported
+pub unsafe extern "C" fn stac() {
+    asm!("stac", options(nomem, nostack));
+}
+
+pub unsafe extern "C" fn clac() {
+    asm!("clac", options(nomem, nostack));
+}
+
+pub unsafe extern "C" fn sti() {
+    asm!("sti", options(nomem, nostack));
+}
+
+pub unsafe extern "C" fn cli() {
+    asm!("cli", options(nomem, nostack));
+}
+
+pub unsafe extern "C" fn hlt() {
+    asm!("hlt", options(nomem, nostack));
+}
+
+// Enable interrupts and halt until the next one. STI only takes effect after HLT, so an interrupt
+// arriving in between still wakes us up
+pub unsafe extern "C" fn enable_and_hlt() {
+    asm!("sti", "hlt", options(nomem, nostack));
+}
+
+// Push callee-saved registers, store stack pointer to *old_rsp, load new_rsp and pop the registers
+// saved there. Returns once something switches back to the old stack
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret"
+);
+
+extern "C" {
+    pub fn switch_context(old_rsp: *mut usize, new_rsp: usize);
+}
+
+// Registers switch_context pops off a stack before returning into it
+pub const SWITCH_CONTEXT_REGISTERS: usize = 6;
+
+// Returns None when the generator had no value ready, callers should retry a few times
+pub unsafe fn rdrand() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    asm!("rdrand {v}", "setc {o}", v = out(reg) value, o = out(reg_byte) ok, options(nomem, nostack));
+    match ok {
+        0 => None,
+        _ => Some(value)
+    }
+}
+
+pub unsafe fn rdseed() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    asm!("rdseed {v}", "setc {o}", v = out(reg) value, o = out(reg_byte) ok, options(nomem, nostack));
+    match ok {
+        0 => None,
+        _ => Some(value)
+    }
+}
@@ -0,0 +1,232 @@
+use crate::console::LogLevel;
+use crate::sync::Once;
+
+pub const MAX_CMDLINE_LEN: usize = 1024;
+pub const MAX_OPTIONS:     usize = 64;
+
+// Bits of KernelOptions::consoles, one per console sink
+pub const CONSOLE_SERIAL:      u8 = 0x01;
+pub const CONSOLE_DEBUGCON:    u8 = 0x02;
+pub const CONSOLE_TERMINAL:    u8 = 0x04;
+pub const CONSOLE_FRAMEBUFFER: u8 = 0x08;
+pub const CONSOLE_ALL:         u8 = 0x0F;
+
+// Single command line option, either a bare flag or key=value
+#[derive(Clone, Copy)]
+pub struct CmdlineOption {
+    pub key: &'static str,
+    pub value: Option<&'static str>
+}
+
+// Parsed command line
+pub struct Cmdline {
+    options: [Option<CmdlineOption>; MAX_OPTIONS],
+    num_options: usize
+}
+
+// Typed settings derived from the command line, consulted by subsystems at boot
+#[derive(Clone, Copy)]
+pub struct KernelOptions {
+    // loglevel=<error|warn|info|debug|trace|0-4>
+    pub log_level: LogLevel,
+    // console=<serial|debugcon|terminal|fb|all>[,...]
+    pub consoles: u8,
+    // nosmp
+    pub nosmp: bool,
+    // mem=<size>[K|M|G], ignore physical memory above this limit
+    pub mem_limit: Option<usize>,
+    // selftest
+    pub selftest: bool,
+    // debugshell, read debug commands from serial port once booted
+    pub debug_shell: bool,
+    // nokaslr, keep kernel regions at fixed bases (the kernel image slide is up to the bootloader)
+    pub kaslr: bool
+}
+
+impl KernelOptions {
+    pub const fn default() -> Self {
+        KernelOptions {
+            log_level: LogLevel::Info,
+            consoles: CONSOLE_ALL,
+            nosmp: false,
+            mem_limit: None,
+            selftest: false,
+            debug_shell: false,
+            kaslr: true
+        }
+    }
+
+    // Check if console sink with provided name is enabled
+    pub fn console_enabled(&self, sink_name: &str) -> bool {
+        let bit = match sink_name {
+            "serial"          => CONSOLE_SERIAL,
+            "debugcon"        => CONSOLE_DEBUGCON,
+            "limine-terminal" => CONSOLE_TERMINAL,
+            "fbcon"           => CONSOLE_FRAMEBUFFER,
+            _ => return true
+        };
+        self.consoles & bit != 0
+    }
+}
+
+// Copy of the command line, which the parsed options point into
+struct CmdlineCopy {
+    buf: [u8; MAX_CMDLINE_LEN],
+    len: usize
+}
+
+static CMDLINE_COPY: Once<CmdlineCopy> = Once::new();
+static OPTIONS: Once<KernelOptions> = Once::new();
+// What is in effect until init ran
+static DEFAULT_OPTIONS: KernelOptions = KernelOptions::default();
+
+const NO_OPTION: Option<CmdlineOption> = None;
+
+// Parse size with optional K/M/G suffix
+pub fn parse_size(s: &str) -> Option<usize> {
+    let (digits, shift) = match s.as_bytes().last() {
+        None => return None,
+        Some(b'K') | Some(b'k') => (&s[..s.len() - 1], 10),
+        Some(b'M') | Some(b'm') => (&s[..s.len() - 1], 20),
+        Some(b'G') | Some(b'g') => (&s[..s.len() - 1], 30),
+        Some(_) => (s, 0)
+    };
+
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<usize>().ok()?
+    };
+    value.checked_mul(1 << shift)
+}
+
+// Parse boolean written as 1/0, yes/no, on/off or true/false
+pub fn parse_bool(s: &str) -> Option<bool> {
+    match s {
+        "1" | "yes" | "on"  | "true"  => Some(true),
+        "0" | "no"  | "off" | "false" => Some(false),
+        _ => None
+    }
+}
+
+impl Cmdline {
+    pub const fn new() -> Self {
+        Cmdline {
+            options: [NO_OPTION; MAX_OPTIONS],
+            num_options: 0
+        }
+    }
+
+    // Split command line into whitespace separated options, values may be wrapped in double quotes
+    pub fn parse(s: &'static str) -> Self {
+        let mut cmdline = Cmdline::new();
+        let bytes = s.as_bytes();
+        let mut i: usize = 0;
+
+        while i < bytes.len() && cmdline.num_options < MAX_OPTIONS {
+            // Skip whitespace between options
+            if bytes[i].is_ascii_whitespace() {
+                i += 1;
+                continue;
+            }
+
+            // Find end of option, whitespace inside quotes doesn't terminate it
+            let start = i;
+            let mut quoted = false;
+            while i < bytes.len() && (quoted || !bytes[i].is_ascii_whitespace()) {
+                if bytes[i] == b'"' {
+                    quoted = !quoted;
+                }
+                i += 1;
+            }
+
+            let option = &s[start..i];
+            cmdline.options[cmdline.num_options] = Some(match option.split_once('=') {
+                None => CmdlineOption { key: option, value: None },
+                Some((k, v)) => CmdlineOption {
+                    key: k,
+                    value: Some(v.trim_matches('"'))
+                }
+            });
+            cmdline.num_options += 1;
+        }
+        cmdline
+    }
+
+    // Iterate options in command line order
+    pub fn iter(&self) -> impl Iterator<Item = &CmdlineOption> {
+        self.options[..self.num_options].iter().filter_map(|o| o.as_ref())
+    }
+
+    // Get value of last occurrence of key, Some("") for flags given as bare keys
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.iter().filter(|o| o.key == key).last().map(|o| o.value.unwrap_or(""))
+    }
+
+    // Check if flag is set, either bare or with a true boolean value
+    pub fn has_flag(&self, key: &str) -> bool {
+        match self.get(key) {
+            None => false,
+            Some("") => true,
+            Some(v) => parse_bool(v).unwrap_or(false)
+        }
+    }
+
+    // Get size value of key
+    pub fn get_size(&self, key: &str) -> Option<usize> {
+        parse_size(self.get(key)?)
+    }
+
+    // Build typed settings, unrecognised values leave defaults in place
+    pub fn kernel_options(&self) -> KernelOptions {
+        let mut options = KernelOptions::default();
+
+        if let Some(l) = self.get("loglevel").and_then(LogLevel::parse) {
+            options.log_level = l;
+        }
+
+        if let Some(c) = self.get("console") {
+            let mut consoles: u8 = 0;
+            for name in c.split(',') {
+                consoles |= match name {
+                    "serial"   | "ttyS0" => CONSOLE_SERIAL,
+                    "debugcon"           => CONSOLE_DEBUGCON,
+                    "terminal"           => CONSOLE_TERMINAL,
+                    "fb"       | "fbcon" => CONSOLE_FRAMEBUFFER,
+                    "all"                => CONSOLE_ALL,
+                    _ => 0
+                };
+            }
+            if consoles != 0 {
+                options.consoles = consoles;
+            }
+        }
+
+        options.nosmp       = self.has_flag("nosmp");
+        options.mem_limit   = self.get_size("mem");
+        options.selftest    = self.has_flag("selftest");
+        options.debug_shell = self.has_flag("debugshell");
+        options.kaslr       = !self.has_flag("nokaslr");
+        options
+    }
+}
+
+// Copy command line out of bootloader memory and parse it
+pub fn init(s: &str) {
+    let len = core::cmp::min(s.len(), MAX_CMDLINE_LEN);
+    // Don't cut a multi-byte character in half
+    let len = (0..=len).rev().find(|&l| s.is_char_boundary(l)).unwrap_or(0);
+    let mut copy = CmdlineCopy { buf: [0; MAX_CMDLINE_LEN], len };
+    copy.buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+    if !CMDLINE_COPY.set(copy) {
+        return
+    }
+
+    let copy = CMDLINE_COPY.get().unwrap();
+    let cmdline = Cmdline::parse(unsafe { core::str::from_utf8_unchecked(&copy.buf[..copy.len]) });
+    OPTIONS.set(cmdline.kernel_options());
+}
+
+// Get typed kernel settings
+pub fn options() -> &'static KernelOptions {
+    OPTIONS.get().unwrap_or(&DEFAULT_OPTIONS)
+}
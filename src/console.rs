@@ -0,0 +1,122 @@
+use core::fmt;
+
+use crate::sync::SpinLock;
+
+// Maximum number of sinks that can be registered at once
+const MAX_SINKS: usize = 8;
+
+// Destination for kernel console output (serial port, debugcon, terminal, framebuffer...), any CPU may write to it
+pub trait ConsoleSink: Send {
+    // Unique name used to identify the sink when unregistering it
+    fn name(&self) -> &'static str;
+
+    // Write string to the sink
+    fn write_str(&mut self, s: &str);
+}
+
+// Message severity, messages above the configured level are dropped
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl LogLevel {
+    // Parse level from its name or numeric value (0 = error .. 4 = trace)
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" | "0" => Some(LogLevel::Error),
+            "warn"  | "1" => Some(LogLevel::Warn),
+            "info"  | "2" => Some(LogLevel::Info),
+            "debug" | "3" => Some(LogLevel::Debug),
+            "trace" | "4" => Some(LogLevel::Trace),
+            _ => None
+        }
+    }
+}
+
+static mut LOG_LEVEL: LogLevel = LogLevel::Info;
+
+// Set maximum level of messages that get printed
+pub fn set_log_level(level: LogLevel) {
+    unsafe {
+        LOG_LEVEL = level;
+    }
+}
+
+// Check if messages of provided level are printed
+pub fn is_enabled(level: LogLevel) -> bool {
+    unsafe { level <= LOG_LEVEL }
+}
+
+type Sinks = [Option<&'static mut dyn ConsoleSink>; MAX_SINKS];
+
+const NO_SINK: Option<&'static mut dyn ConsoleSink> = None;
+
+// Interrupt handlers print too, so the lock is always taken with interrupts disabled
+static SINKS: SpinLock<Sinks> = SpinLock::new([NO_SINK; MAX_SINKS]);
+
+// Register sink, returns false if a sink with the same name already exists or no slot is free
+pub fn register(sink: &'static mut dyn ConsoleSink) -> bool {
+    let mut sinks = SINKS.lock_irqsave();
+    if sinks.iter().flatten().any(|s| s.name() == sink.name()) {
+        return false
+    }
+
+    for slot in sinks.iter_mut() {
+        if slot.is_none() {
+            *slot = Some(sink);
+            return true
+        }
+    }
+    false
+}
+
+// Unregister sink with provided name, returning it if it was registered
+pub fn unregister(name: &str) -> Option<&'static mut dyn ConsoleSink> {
+    let mut sinks = SINKS.lock_irqsave();
+    for slot in sinks.iter_mut() {
+        match slot {
+            Some(s) if s.name() == name => return slot.take(),
+            _ => { }
+        }
+    }
+    None
+}
+
+fn write_sinks(sinks: &mut Sinks, s: &str) {
+    for sink in sinks.iter_mut().flatten() {
+        sink.write_str(s);
+    }
+}
+
+// Write string to every registered sink
+pub fn write_str(s: &str) {
+    write_sinks(&mut SINKS.lock_irqsave(), s);
+}
+
+// Write formatted arguments to every registered sink, a message from one CPU is never split by another's
+pub fn write_fmt(args: fmt::Arguments) {
+    let mut sinks = SINKS.lock_irqsave();
+    let _ = fmt::Write::write_fmt(&mut Writer(&mut sinks), args);
+}
+
+// Write formatted arguments without taking the lock, the CPU holding it may be the one panicking
+pub fn write_fmt_panic(args: fmt::Arguments) {
+    unsafe {
+        let _ = fmt::Write::write_fmt(&mut Writer(&mut *SINKS.get_unchecked()), args);
+    }
+}
+
+// Handle allowing core::fmt machinery to target the locked sinks
+struct Writer<'a>(&'a mut Sinks);
+
+impl fmt::Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_sinks(self.0, s);
+        Ok(())
+    }
+}
@@ -1,4 +1,22 @@
 pub const VMEM_MAX:  usize = 8 * 1024 * 1024 * 1024;
-pub const MAX_PAGES: usize = VMEM_MAX / 4096;
 pub const PAGE_SIZE: usize = 4096;
-pub const PT_SIZE:   usize = 512 * PAGE_SIZE;
\ No newline at end of file
+
+// Highest physical address tracked by the frame allocator
+pub const PMEM_MAX:   usize = 16 * 1024 * 1024 * 1024;
+pub const MAX_FRAMES: usize = PMEM_MAX / PAGE_SIZE;
+
+// Virtual address space layout
+pub const USER_MMAP_BASE:         usize = 0x0000_0000_1000_0000;
+pub const USER_HALF_END:          usize = 0x0000_8000_0000_0000;
+pub const KERNEL_HALF_BASE:       usize = 0xFFFF_8000_0000_0000;
+// Randomized direct maps go above the bootloader's, which stays mapped for bootloader memory
+pub const DIRECT_MAP_WINDOW_BASE: usize = 0xFFFF_8800_0000_0000;
+pub const KERNEL_HEAP_BASE:       usize = 0xFFFF_C000_0000_0000;
+pub const KERNEL_STACK_BASE:      usize = 0xFFFF_E000_0000_0000;
+pub const KERNEL_STACK_END:       usize = 0xFFFF_F000_0000_0000;
+
+// Pages in each kernel stack, not counting its guard pages
+pub const KERNEL_STACK_PAGES: usize = 16;
+
+// CPU masks are 64 bits wide
+pub const MAX_CPUS: usize = 64;
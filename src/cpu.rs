@@ -0,0 +1,378 @@
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::asm_wrappers::{cpuid, read_cr4, write_cr4, Cr4, CpuidResult};
+use crate::console;
+use crate::sync::Once;
+
+pub const MAX_CACHES: usize = 8;
+
+// CPU capabilities the kernel cares about
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Fpu,
+    Tsc,
+    Msr,
+    Apic,
+    Pge,
+    Pat,
+    Fxsr,
+    Sse,
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    Pcid,
+    X2apic,
+    Xsave,
+    Osxsave,
+    Avx,
+    Rdrand,
+    Hypervisor,
+    Fsgsbase,
+    Smep,
+    Avx2,
+    Invpcid,
+    Rdseed,
+    Smap,
+    Umip,
+    Syscall,
+    Nx,
+    Page1Gb,
+    Rdtscp,
+    InvariantTsc
+}
+
+#[derive(Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx
+}
+
+// Where CPUID reports a feature
+struct FeatureBit {
+    feature: Feature,
+    name: &'static str,
+    leaf: u32,
+    register: Register,
+    bit: u32
+}
+
+const FEATURE_BITS: &[FeatureBit] = &[
+    FeatureBit { feature: Feature::Fpu,          name: "fpu",           leaf: 0x1,        register: Register::Edx, bit: 0 },
+    FeatureBit { feature: Feature::Tsc,          name: "tsc",           leaf: 0x1,        register: Register::Edx, bit: 4 },
+    FeatureBit { feature: Feature::Msr,          name: "msr",           leaf: 0x1,        register: Register::Edx, bit: 5 },
+    FeatureBit { feature: Feature::Apic,         name: "apic",          leaf: 0x1,        register: Register::Edx, bit: 9 },
+    FeatureBit { feature: Feature::Pge,          name: "pge",           leaf: 0x1,        register: Register::Edx, bit: 13 },
+    FeatureBit { feature: Feature::Pat,          name: "pat",           leaf: 0x1,        register: Register::Edx, bit: 16 },
+    FeatureBit { feature: Feature::Fxsr,         name: "fxsr",          leaf: 0x1,        register: Register::Edx, bit: 24 },
+    FeatureBit { feature: Feature::Sse,          name: "sse",           leaf: 0x1,        register: Register::Edx, bit: 25 },
+    FeatureBit { feature: Feature::Sse2,         name: "sse2",          leaf: 0x1,        register: Register::Edx, bit: 26 },
+    FeatureBit { feature: Feature::Sse3,         name: "sse3",          leaf: 0x1,        register: Register::Ecx, bit: 0 },
+    FeatureBit { feature: Feature::Ssse3,        name: "ssse3",         leaf: 0x1,        register: Register::Ecx, bit: 9 },
+    FeatureBit { feature: Feature::Sse41,        name: "sse4.1",        leaf: 0x1,        register: Register::Ecx, bit: 19 },
+    FeatureBit { feature: Feature::Sse42,        name: "sse4.2",        leaf: 0x1,        register: Register::Ecx, bit: 20 },
+    FeatureBit { feature: Feature::Pcid,         name: "pcid",          leaf: 0x1,        register: Register::Ecx, bit: 17 },
+    FeatureBit { feature: Feature::X2apic,       name: "x2apic",        leaf: 0x1,        register: Register::Ecx, bit: 21 },
+    FeatureBit { feature: Feature::Xsave,        name: "xsave",         leaf: 0x1,        register: Register::Ecx, bit: 26 },
+    FeatureBit { feature: Feature::Osxsave,      name: "osxsave",       leaf: 0x1,        register: Register::Ecx, bit: 27 },
+    FeatureBit { feature: Feature::Avx,          name: "avx",           leaf: 0x1,        register: Register::Ecx, bit: 28 },
+    FeatureBit { feature: Feature::Rdrand,       name: "rdrand",        leaf: 0x1,        register: Register::Ecx, bit: 30 },
+    FeatureBit { feature: Feature::Hypervisor,   name: "hypervisor",    leaf: 0x1,        register: Register::Ecx, bit: 31 },
+    FeatureBit { feature: Feature::Fsgsbase,     name: "fsgsbase",      leaf: 0x7,        register: Register::Ebx, bit: 0 },
+    FeatureBit { feature: Feature::Avx2,         name: "avx2",          leaf: 0x7,        register: Register::Ebx, bit: 5 },
+    FeatureBit { feature: Feature::Smep,         name: "smep",          leaf: 0x7,        register: Register::Ebx, bit: 7 },
+    FeatureBit { feature: Feature::Invpcid,      name: "invpcid",       leaf: 0x7,        register: Register::Ebx, bit: 10 },
+    FeatureBit { feature: Feature::Rdseed,       name: "rdseed",        leaf: 0x7,        register: Register::Ebx, bit: 18 },
+    FeatureBit { feature: Feature::Smap,         name: "smap",          leaf: 0x7,        register: Register::Ebx, bit: 20 },
+    FeatureBit { feature: Feature::Umip,         name: "umip",          leaf: 0x7,        register: Register::Ecx, bit: 2 },
+    FeatureBit { feature: Feature::Syscall,      name: "syscall",       leaf: 0x80000001, register: Register::Edx, bit: 11 },
+    FeatureBit { feature: Feature::Nx,           name: "nx",            leaf: 0x80000001, register: Register::Edx, bit: 20 },
+    FeatureBit { feature: Feature::Page1Gb,      name: "pdpe1gb",       leaf: 0x80000001, register: Register::Edx, bit: 26 },
+    FeatureBit { feature: Feature::Rdtscp,       name: "rdtscp",        leaf: 0x80000001, register: Register::Edx, bit: 27 },
+    FeatureBit { feature: Feature::InvariantTsc, name: "invariant_tsc", leaf: 0x80000007, register: Register::Edx, bit: 8 }
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified
+}
+
+#[derive(Clone, Copy)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    pub size: usize,
+    pub line_size: usize,
+    pub ways: usize,
+    pub shared_by: usize
+}
+
+impl fmt::Display for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            CacheKind::Data => "d",
+            CacheKind::Instruction => "i",
+            CacheKind::Unified => ""
+        };
+        write!(f, "L{}{} {} KiB ({}-way, {} B lines, {} threads)",
+               self.level, kind, self.size / 1024, self.ways, self.line_size, self.shared_by)
+    }
+}
+
+// Everything CPUID told us about the boot CPU
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    features: u64,
+    caches: [Option<Cache>; MAX_CACHES],
+    pub threads_per_core: usize,
+    pub cores_per_package: usize
+}
+
+impl CpuInfo {
+    const fn new() -> Self {
+        CpuInfo {
+            vendor: [0; 12],
+            brand: [0; 48],
+            family: 0,
+            model: 0,
+            stepping: 0,
+            max_leaf: 0,
+            max_extended_leaf: 0,
+            features: 0,
+            caches: [None; MAX_CACHES],
+            threads_per_core: 1,
+            cores_per_package: 1
+        }
+    }
+
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    // Brand string without the padding some CPUs put around it, empty if unsupported
+    pub fn brand(&self) -> &str {
+        let len = self.brand.iter().position(|b| *b == 0).unwrap_or(self.brand.len());
+        core::str::from_utf8(&self.brand[..len]).unwrap_or("").trim()
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features & (1 << feature as u64) != 0
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.caches.iter().filter_map(|c| c.as_ref())
+    }
+
+    // Names of supported features, in the order CPUID reports them
+    pub fn feature_names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        FEATURE_BITS.iter().filter(|b| self.has(b.feature)).map(|b| b.name)
+    }
+
+    fn is_intel(&self) -> bool {
+        &self.vendor == b"GenuineIntel"
+    }
+
+    fn is_amd(&self) -> bool {
+        &self.vendor == b"AuthenticAMD"
+    }
+
+    // Query leaf only if CPU has it, unsupported leaves return data of the highest one on Intel
+    unsafe fn query(&self, leaf: u32, subleaf: u32) -> Option<CpuidResult> {
+        let max = match leaf >= 0x80000000 {
+            true => self.max_extended_leaf,
+            false => self.max_leaf
+        };
+        match leaf <= max {
+            true => Some(cpuid(leaf, subleaf)),
+            false => None
+        }
+    }
+
+    unsafe fn read_identity(&mut self) {
+        let r = cpuid(0, 0);
+        self.max_leaf = r.eax;
+        // Vendor string is spread over EBX, EDX, ECX in that order
+        self.vendor[0..4].copy_from_slice(&r.ebx.to_le_bytes());
+        self.vendor[4..8].copy_from_slice(&r.edx.to_le_bytes());
+        self.vendor[8..12].copy_from_slice(&r.ecx.to_le_bytes());
+        self.max_extended_leaf = cpuid(0x80000000, 0).eax;
+
+        let eax = cpuid(1, 0).eax;
+        let base_family = (eax >> 8) & 0xF;
+        let base_model  = (eax >> 4) & 0xF;
+        self.stepping = eax & 0xF;
+        self.family = match base_family {
+            0xF => base_family + ((eax >> 20) & 0xFF),
+            _ => base_family
+        };
+        self.model = match base_family {
+            0x6 | 0xF => base_model | (((eax >> 16) & 0xF) << 4),
+            _ => base_model
+        };
+
+        for (i, leaf) in (0x80000002..=0x80000004).enumerate() {
+            if let Some(r) = self.query(leaf, 0) {
+                for (j, reg) in [r.eax, r.ebx, r.ecx, r.edx].iter().enumerate() {
+                    let at = i * 16 + j * 4;
+                    self.brand[at..at + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    unsafe fn read_features(&mut self) {
+        for b in FEATURE_BITS.iter() {
+            let r = match self.query(b.leaf, 0) {
+                None => continue,
+                Some(r) => r
+            };
+            let reg = match b.register {
+                Register::Ebx => r.ebx,
+                Register::Ecx => r.ecx,
+                Register::Edx => r.edx
+            };
+            if reg & (1 << b.bit) != 0 {
+                self.features |= 1 << b.feature as u64;
+            }
+        }
+    }
+
+    // Deterministic cache parameters, leaf 4 on Intel and 0x8000001D on AMD share a layout
+    unsafe fn read_caches(&mut self) {
+        let leaf = match (self.is_intel(), self.is_amd()) {
+            (true, _) => 0x4,
+            (false, true) => 0x8000001D,
+            (false, false) => return
+        };
+
+        // Subleaves list one cache each until the first of type 0
+        for subleaf in 0..MAX_CACHES {
+            let r = match self.query(leaf, subleaf as u32) {
+                None => break,
+                Some(r) => r
+            };
+            let kind = match r.eax & 0x1F {
+                1 => CacheKind::Data,
+                2 => CacheKind::Instruction,
+                3 => CacheKind::Unified,
+                _ => break
+            };
+
+            let ways       = ((r.ebx >> 22) & 0x3FF) as usize + 1;
+            let partitions = ((r.ebx >> 12) & 0x3FF) as usize + 1;
+            let line_size  = (r.ebx & 0xFFF) as usize + 1;
+            let sets       = r.ecx as usize + 1;
+            self.caches[subleaf] = Some(Cache {
+                level: ((r.eax >> 5) & 0x7) as u8,
+                kind,
+                size: ways * partitions * line_size * sets,
+                line_size,
+                ways,
+                shared_by: ((r.eax >> 14) & 0xFFF) as usize + 1
+            });
+        }
+    }
+
+    unsafe fn read_topology(&mut self) {
+        // Extended topology leaf, level 1 counts threads per core and level 2 threads per package
+        if let Some(smt) = self.query(0xB, 0) {
+            let package = cpuid(0xB, 1);
+            let threads = (smt.ebx & 0xFFFF) as usize;
+            let logical = (package.ebx & 0xFFFF) as usize;
+            if threads != 0 && logical >= threads {
+                self.threads_per_core  = threads;
+                self.cores_per_package = logical / threads;
+                return
+            }
+        }
+
+        // Older CPUs only report logical processors per package, if hyper-threading is flagged at all
+        let r = cpuid(1, 0);
+        if r.edx & (1 << 28) != 0 {
+            self.cores_per_package = core::cmp::max(((r.ebx >> 16) & 0xFF) as usize, 1);
+        }
+    }
+}
+
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} family {:#x} model {:#x} stepping {}", self.vendor(), self.family, self.model, self.stepping)?;
+        match self.brand() {
+            "" => Ok(()),
+            brand => write!(f, ", {}", brand)
+        }
+    }
+}
+
+static INFO: Once<CpuInfo> = Once::new();
+// Reported until init ran, every feature reads as missing
+static UNKNOWN: CpuInfo = CpuInfo::new();
+// Bits of Cr4 enable_protections set
+static PROTECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+// Enumerate CPUID of boot CPU, the rest are assumed to match
+pub fn init() {
+    INFO.call_once(|| unsafe {
+        let mut info = CpuInfo::new();
+        info.read_identity();
+        info.read_features();
+        info.read_caches();
+        info.read_topology();
+        info
+    });
+}
+
+pub fn info() -> &'static CpuInfo {
+    INFO.get().unwrap_or(&UNKNOWN)
+}
+
+pub fn has(feature: Feature) -> bool {
+    info().has(feature)
+}
+
+// Stop the kernel from executing (SMEP) or touching (SMAP) user pages and user mode from reading descriptor
+// tables (UMIP), as far as the CPU supports it. Returns the bits that were set
+pub unsafe fn enable_protections() -> Cr4 {
+    let mut flags = Cr4::from_bits(0);
+    for (feature, flag) in [(Feature::Smep, Cr4::SMEP), (Feature::Smap, Cr4::SMAP), (Feature::Umip, Cr4::UMIP)] {
+        if has(feature) {
+            flags = flags | flag;
+        }
+    }
+    write_cr4(read_cr4() | flags);
+    PROTECTIONS.store(flags.bits(), Ordering::Relaxed);
+    flags
+}
+
+// Check if user pages may only be accessed between stac and clac
+pub fn smap_enabled() -> bool {
+    Cr4::from_bits(PROTECTIONS.load(Ordering::Relaxed)).contains(Cr4::SMAP)
+}
+
+// Print identity, topology, caches and features, one line each
+pub fn print_summary() {
+    let info = info();
+    console::write_fmt(format_args!("CPU: {}\n", info));
+    console::write_fmt(format_args!("Topology: {} cores per package, {} threads per core\n",
+                                    info.cores_per_package, info.threads_per_core));
+    console::write_str("Caches:");
+    for c in info.caches() {
+        console::write_fmt(format_args!(" {}", c));
+    }
+    console::write_str("\nFeatures:");
+    for name in info.feature_names() {
+        console::write_fmt(format_args!(" {}", name));
+    }
+    console::write_str("\n");
+}
@@ -0,0 +1,30 @@
+use crate::asm_wrappers::{inb, outb};
+use crate::console::ConsoleSink;
+
+// QEMU/Bochs debug console port
+pub const DEBUGCON_PORT: u16 = 0xE9;
+
+pub static mut DEBUGCON: Debugcon = Debugcon;
+
+pub struct Debugcon;
+
+impl Debugcon {
+    // Emulators with debugcon enabled read back the port number from the port
+    pub fn is_present(&self) -> bool {
+        unsafe { inb(DEBUGCON_PORT) == DEBUGCON_PORT as u8 }
+    }
+}
+
+impl ConsoleSink for Debugcon {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn write_str(&mut self, s: &str) {
+        for &c in s.as_bytes().iter() {
+            unsafe {
+                outb(DEBUGCON_PORT, c);
+            }
+        }
+    }
+}
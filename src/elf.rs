@@ -0,0 +1,474 @@
+use core::fmt;
+
+use crate::address_space;
+use crate::constants::PAGE_SIZE;
+use crate::random;
+use crate::user::{USER_STACK_SIZE, USER_STACK_TOP};
+use crate::usercopy;
+use crate::vma::{self, Backing};
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64:  u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT:  u8 = 1;
+
+const ET_EXEC: u16 = 2;
+const ET_DYN:  u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const HEADER_SIZE:  usize = 64;
+const PHENT_SIZE:   usize = 56;
+const MAX_SEGMENTS: usize = 64;
+
+// Program header types
+const PT_LOAD:   u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR:   u32 = 6;
+
+// Segment permissions
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+// Auxiliary vector entries passed on the initial stack
+const AT_NULL:   u64 = 0;
+const AT_PHDR:   u64 = 3;
+const AT_PHENT:  u64 = 4;
+const AT_PHNUM:  u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE:   u64 = 7;
+const AT_ENTRY:  u64 = 9;
+const AT_RANDOM: u64 = 25;
+const AUXV_ENTRIES: usize = 8;
+
+// Where position independent programs are placed, segments go at their addresses plus this
+pub const DYN_LOAD_BASE: usize = 0x0000_5555_5555_0000;
+
+// Arguments and environment go in the top page of the stack, with at most this many strings each
+pub const MAX_ARGS: usize = 32;
+const ARGS_SIZE: usize = PAGE_SIZE;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    BadType,
+    BadMachine,
+    BadProgramHeaders,
+    BadSegment,
+    // Segment, or a page shared by two of them, would have to be writable and executable
+    WritableCode,
+    // Dynamically linked programs need an interpreter, there is none to run
+    Interpreter,
+    BadEntry,
+    TooManyArgs,
+    NoMemory
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "file truncated"),
+            ElfError::BadMagic => write!(f, "not an ELF file"),
+            ElfError::NotElf64 => write!(f, "not a 64-bit ELF file"),
+            ElfError::NotLittleEndian => write!(f, "not little endian"),
+            ElfError::BadVersion => write!(f, "unknown ELF version"),
+            ElfError::BadType => write!(f, "not an executable"),
+            ElfError::BadMachine => write!(f, "not an x86-64 program"),
+            ElfError::BadProgramHeaders => write!(f, "bad program headers"),
+            ElfError::BadSegment => write!(f, "bad loadable segment"),
+            ElfError::WritableCode => write!(f, "writable and executable memory"),
+            ElfError::Interpreter => write!(f, "dynamically linked programs are not supported"),
+            ElfError::BadEntry => write!(f, "entry point outside executable segments"),
+            ElfError::TooManyArgs => write!(f, "arguments and environment too long"),
+            ElfError::NoMemory => write!(f, "out of memory")
+        }
+    }
+}
+
+// Fields of the file header the loader needs
+#[derive(Clone, Copy)]
+struct Header {
+    typ: u16,
+    entry: usize,
+    phoff: usize,
+    phnum: usize
+}
+
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    typ: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    filesz: usize,
+    memsz: usize
+}
+
+impl ProgramHeader {
+    // Page range covering segment in memory, without load bias
+    const fn pages(&self) -> (usize, usize) {
+        (self.vaddr & !(PAGE_SIZE - 1), (self.vaddr + self.memsz + PAGE_SIZE - 1) & !(PAGE_SIZE - 1))
+    }
+
+    const fn prot(&self) -> u64 {
+        let mut prot = vma::VMA_USER;
+        if self.flags & PF_R != 0 {
+            prot |= vma::VMA_READ;
+        }
+        if self.flags & PF_W != 0 {
+            prot |= vma::VMA_WRITE;
+        }
+        if self.flags & PF_X != 0 {
+            prot |= vma::VMA_EXEC;
+        }
+        prot
+    }
+}
+
+// Program loaded into a new address space, ready for thread::spawn_user
+#[derive(Clone, Copy)]
+pub struct Program {
+    pub space: usize,
+    pub entry: usize,
+    pub stack: usize
+}
+
+fn read_u16(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([image[offset], image[offset + 1]])
+}
+
+fn read_u32(image: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&image[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(image: &[u8], offset: usize) -> usize {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&image[offset..offset + 8]);
+    u64::from_le_bytes(bytes) as usize
+}
+
+// Check file header and that the program headers are within the file
+fn parse_header(image: &[u8]) -> Result<Header, ElfError> {
+    if image.len() < HEADER_SIZE {
+        return Err(ElfError::Truncated)
+    }
+    if image[..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic)
+    }
+    if image[4] != ELFCLASS64 {
+        return Err(ElfError::NotElf64)
+    }
+    if image[5] != ELFDATA2LSB {
+        return Err(ElfError::NotLittleEndian)
+    }
+    if image[6] != EV_CURRENT || read_u32(image, 20) != EV_CURRENT as u32 {
+        return Err(ElfError::BadVersion)
+    }
+
+    let header = Header {
+        typ: read_u16(image, 16),
+        entry: read_u64(image, 24),
+        phoff: read_u64(image, 32),
+        phnum: read_u16(image, 56) as usize
+    };
+    if header.typ != ET_EXEC && header.typ != ET_DYN {
+        return Err(ElfError::BadType)
+    }
+    if read_u16(image, 18) != EM_X86_64 {
+        return Err(ElfError::BadMachine)
+    }
+    if read_u16(image, 54) as usize != PHENT_SIZE || header.phnum == 0 || header.phnum > MAX_SEGMENTS {
+        return Err(ElfError::BadProgramHeaders)
+    }
+    match header.phoff.checked_add(header.phnum * PHENT_SIZE) {
+        Some(end) if end <= image.len() => Ok(header),
+        _ => Err(ElfError::Truncated)
+    }
+}
+
+fn program_headers<'a>(image: &'a [u8], header: &Header) -> impl Iterator<Item = ProgramHeader> + 'a {
+    let phoff = header.phoff;
+    (0..header.phnum).map(move |i| {
+        let offset = phoff + i * PHENT_SIZE;
+        ProgramHeader {
+            typ: read_u32(image, offset),
+            flags: read_u32(image, offset + 4),
+            offset: read_u64(image, offset + 8),
+            vaddr: read_u64(image, offset + 16),
+            filesz: read_u64(image, offset + 32),
+            memsz: read_u64(image, offset + 40)
+        }
+    })
+}
+
+// Loadable segments taking up memory
+fn segments<'a>(image: &'a [u8], header: &Header) -> impl Iterator<Item = ProgramHeader> + 'a {
+    program_headers(image, header).filter(|ph| ph.typ == PT_LOAD && ph.memsz != 0)
+}
+
+// Check loadable segments are within the file and the user half below the stack, in ascending order and not
+// overlapping. Neighbours may share a page. Nothing may end up writable and executable
+fn check_segments(image: &[u8], header: &Header, bias: usize) -> Result<(), ElfError> {
+    let mut previous: Option<(usize, u32)> = None;
+    for ph in program_headers(image, header) {
+        match ph.typ {
+            PT_INTERP => return Err(ElfError::Interpreter),
+            PT_PHDR => match ph.vaddr.checked_add(bias).and_then(|v| v.checked_add(ph.memsz)) {
+                Some(e) if e <= USER_STACK_TOP => continue,
+                _ => return Err(ElfError::BadProgramHeaders)
+            },
+            PT_LOAD if ph.memsz != 0 => { },
+            _ => continue
+        }
+
+        let previous_end = previous.map_or(0, |(end, _)| end);
+        let in_file = ph.offset.checked_add(ph.filesz).is_some_and(|end| end <= image.len());
+        let end = ph.vaddr.checked_add(bias).and_then(|v| v.checked_add(ph.memsz));
+        match end {
+            Some(e) if in_file && ph.filesz <= ph.memsz && ph.vaddr + bias >= PAGE_SIZE
+                && ph.vaddr + bias >= previous_end && e <= USER_STACK_TOP - USER_STACK_SIZE => { },
+            _ => return Err(ElfError::BadSegment)
+        }
+
+        // A page shared with the previous segment gets the permissions of both
+        let flags = match previous {
+            Some((previous_end, previous_flags)) if (ph.vaddr + bias) & !(PAGE_SIZE - 1) < previous_end =>
+                ph.flags | previous_flags,
+            _ => ph.flags
+        };
+        if flags & PF_W != 0 && flags & PF_X != 0 {
+            return Err(ElfError::WritableCode)
+        }
+        previous = Some((ph.vaddr + bias + ph.memsz, ph.flags));
+    }
+
+    match previous {
+        None => Err(ElfError::BadSegment),
+        Some(_) => Ok(())
+    }
+}
+
+// Check entry point lies in an executable segment
+fn check_entry(image: &[u8], header: &Header) -> Result<(), ElfError> {
+    match segments(image, header)
+        .any(|ph| ph.flags & PF_X != 0 && header.entry >= ph.vaddr && header.entry < ph.vaddr + ph.memsz) {
+        false => Err(ElfError::BadEntry),
+        true => Ok(())
+    }
+}
+
+// Create writable areas for every loadable segment, pages shared by neighbours are mapped once
+unsafe fn map_segments(space: usize, image: &[u8], header: &Header, bias: usize) -> Result<(), ElfError> {
+    let mut space = address_space::get(space).ok_or(ElfError::NoMemory)?;
+    let pager = space.pager();
+    let mut mapped_end = 0;
+    for ph in segments(image, header) {
+        let (start, end) = ph.pages();
+        let start = core::cmp::max(start + bias, mapped_end);
+        let end = end + bias;
+        if start < end {
+            pager.mmap(Some(start as *const ()), end - start, vma::VMA_READ | vma::VMA_WRITE | vma::VMA_USER,
+                       Backing::Anonymous).ok_or(ElfError::NoMemory)?;
+            mapped_end = end;
+        }
+    }
+    Ok(())
+}
+
+// Copy file contents of every segment and clear the rest of their last file page. Anything past that is in
+// pages nothing touched yet, which are zero filled when first accessed. Needs the address space loaded
+fn copy_segments(image: &[u8], header: &Header, bias: usize) -> Result<(), ElfError> {
+    let zeroes = [0u8; 256];
+    for ph in segments(image, header) {
+        let vaddr = ph.vaddr + bias;
+        usercopy::copy_to_user(vaddr, &image[ph.offset..ph.offset + ph.filesz]).map_err(|_| ElfError::NoMemory)?;
+
+        let mut bss = vaddr + ph.filesz;
+        let bss_end = core::cmp::min(vaddr + ph.memsz, (bss + PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+        while bss < bss_end {
+            let n = core::cmp::min(zeroes.len(), bss_end - bss);
+            usercopy::copy_to_user(bss, &zeroes[..n]).map_err(|_| ElfError::NoMemory)?;
+            bss += n;
+        }
+    }
+    Ok(())
+}
+
+// Give segments their final permissions. A page shared by two segments gets the permissions of both, which
+// check_segments made sure are never writable and executable at once
+unsafe fn protect_segments(space: usize, image: &[u8], header: &Header, bias: usize) -> Result<(), ElfError> {
+    let mut space = address_space::get(space).ok_or(ElfError::NoMemory)?;
+    let pager = space.pager();
+    let mut previous: Option<(usize, u64)> = None;
+    for ph in segments(image, header) {
+        let (start, end) = ph.pages();
+        let (start, end) = (start + bias, end + bias);
+        pager.mprotect(start as *const (), end - start, ph.prot()).ok_or(ElfError::NoMemory)?;
+        if let Some((previous_end, previous_prot)) = previous {
+            if previous_end > start {
+                pager.mprotect(start as *const (), PAGE_SIZE, ph.prot() | previous_prot).ok_or(ElfError::NoMemory)?;
+            }
+        }
+        previous = Some((end, ph.prot()));
+    }
+    Ok(())
+}
+
+// Find where program headers are in memory, for AT_PHDR. Zero if no segment loads them
+fn phdr_address(image: &[u8], header: &Header, bias: usize) -> usize {
+    match program_headers(image, header).find(|ph| ph.typ == PT_PHDR) {
+        Some(ph) => ph.vaddr.checked_add(bias).unwrap_or(0),
+        None => program_headers(image, header)
+            .find(|ph| ph.typ == PT_LOAD && header.phoff >= ph.offset
+                && ph.offset.checked_add(ph.filesz).is_some_and(|end| header.phoff < end))
+            .and_then(|ph| ph.vaddr.checked_add(header.phoff - ph.offset)?.checked_add(bias))
+            .unwrap_or(0)
+    }
+}
+
+// Initial stack contents, built in the top page of the stack and copied out at once
+struct StackBuilder {
+    data: [u8; ARGS_SIZE],
+    // Offset of lowest byte in use
+    top: usize
+}
+
+impl StackBuilder {
+    const BASE: usize = USER_STACK_TOP - ARGS_SIZE;
+
+    // User address of offset into data
+    const fn address(offset: usize) -> usize {
+        Self::BASE + offset
+    }
+
+    // Put bytes below what is there already, returns their user address
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<usize, ElfError> {
+        self.top = self.top.checked_sub(bytes.len()).ok_or(ElfError::TooManyArgs)?;
+        self.data[self.top..self.top + bytes.len()].copy_from_slice(bytes);
+        Ok(Self::address(self.top))
+    }
+
+    fn push_string(&mut self, s: &str) -> Result<usize, ElfError> {
+        self.push_bytes(&[0])?;
+        self.push_bytes(s.as_bytes())
+    }
+
+    // Put strings below what is there already, storing their user addresses
+    fn push_strings(&mut self, strings: &[&str], addresses: &mut [usize; MAX_ARGS]) -> Result<(), ElfError> {
+        if strings.len() > MAX_ARGS {
+            return Err(ElfError::TooManyArgs)
+        }
+        for (i, s) in strings.iter().enumerate() {
+            addresses[i] = self.push_string(s)?;
+        }
+        Ok(())
+    }
+}
+
+// Set up the stack the System V ABI expects at the entry point: argc at the stack pointer, followed by the
+// argv and envp pointers, each ending with a null one, and the auxiliary vector. Needs the address space
+// loaded, returns the stack pointer
+fn setup_stack(argv: &[&str], envp: &[&str], auxv: &[(u64, u64); AUXV_ENTRIES - 2]) -> Result<usize, ElfError> {
+    let mut stack = StackBuilder { data: [0; ARGS_SIZE], top: ARGS_SIZE };
+
+    let mut random_bytes = [0u8; 16];
+    random_bytes[..8].copy_from_slice(&random::next_u64().to_le_bytes());
+    random_bytes[8..].copy_from_slice(&random::next_u64().to_le_bytes());
+    let random_address = stack.push_bytes(&random_bytes)?;
+
+    let mut envp_addresses = [0; MAX_ARGS];
+    let mut argv_addresses = [0; MAX_ARGS];
+    stack.push_strings(envp, &mut envp_addresses)?;
+    stack.push_strings(argv, &mut argv_addresses)?;
+
+    let mut words = [0u64; 1 + MAX_ARGS + 1 + MAX_ARGS + 1 + 2 * AUXV_ENTRIES];
+    let mut n = 0;
+    let mut push = |w: u64| {
+        words[n] = w;
+        n += 1;
+    };
+    push(argv.len() as u64);
+    argv_addresses[..argv.len()].iter().for_each(|&a| push(a as u64));
+    push(0);
+    envp_addresses[..envp.len()].iter().for_each(|&a| push(a as u64));
+    push(0);
+    for &(key, value) in auxv.iter() {
+        push(key);
+        push(value);
+    }
+    push(AT_RANDOM);
+    push(random_address as u64);
+    push(AT_NULL);
+    push(0);
+
+    // The stack pointer must be 16 byte aligned at the entry point
+    let size = n * 8;
+    let rsp = stack.top.checked_sub(size).ok_or(ElfError::TooManyArgs)? & !15;
+    for (i, w) in words[..n].iter().enumerate() {
+        stack.data[rsp + i * 8..rsp + i * 8 + 8].copy_from_slice(&w.to_le_bytes());
+    }
+
+    usercopy::copy_to_user(StackBuilder::address(rsp), &stack.data[rsp..]).map_err(|_| ElfError::NoMemory)?;
+    Ok(StackBuilder::address(rsp))
+}
+
+// Map, fill and protect segments and set up the stack of a program in an empty address space
+unsafe fn load_into(space: usize, image: &[u8], header: &Header, bias: usize, argv: &[&str], envp: &[&str])
+        -> Result<usize, ElfError> {
+    map_segments(space, image, header, bias)?;
+    address_space::get(space).ok_or(ElfError::NoMemory)?.pager()
+        .mmap(Some((USER_STACK_TOP - USER_STACK_SIZE) as *const ()), USER_STACK_SIZE,
+              vma::VMA_READ | vma::VMA_WRITE | vma::VMA_USER, Backing::Anonymous).ok_or(ElfError::NoMemory)?;
+
+    let auxv = [
+        (AT_PHDR, phdr_address(image, header, bias) as u64),
+        (AT_PHENT, PHENT_SIZE as u64),
+        (AT_PHNUM, header.phnum as u64),
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        // Base of the interpreter, there is none
+        (AT_BASE, 0),
+        (AT_ENTRY, (header.entry + bias) as u64)
+    ];
+    let stack = address_space::with(space, || {
+        copy_segments(image, header, bias)?;
+        setup_stack(argv, envp, &auxv)
+    }).ok_or(ElfError::NoMemory)??;
+
+    protect_segments(space, image, header, bias)?;
+    Ok(stack)
+}
+
+// Check program is one we can run without loading anything, returns its header and load bias
+fn check(image: &[u8]) -> Result<(Header, usize), ElfError> {
+    let header = parse_header(image)?;
+    let bias = match header.typ {
+        ET_DYN => DYN_LOAD_BASE,
+        _ => 0
+    };
+    check_segments(image, &header, bias)?;
+    check_entry(image, &header)?;
+    Ok((header, bias))
+}
+
+pub fn validate(image: &[u8]) -> Result<(), ElfError> {
+    check(image).map(|_| ())
+}
+
+// Load statically linked program into a new address space, with argv and envp on its stack
+pub fn load(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Program, ElfError> {
+    let (header, bias) = check(image)?;
+    let space = address_space::create().ok_or(ElfError::NoMemory)?;
+    match unsafe { load_into(space, image, &header, bias, argv, envp) } {
+        Ok(stack) => Ok(Program { space, entry: header.entry + bias, stack }),
+        Err(e) => {
+            address_space::destroy(space);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use crate::console::ConsoleSink;
+use crate::font::Font;
+use crate::framebuffer::{Color, Framebuffer};
+
+const MAX_ANSI_PARAMS: usize = 8;
+const TAB_WIDTH:       usize = 8;
+
+// Standard VGA-like palette used for ANSI colours 0-7 and their bright variants 8-15
+const PALETTE: [Color; 16] = [
+    Color::new(0x00, 0x00, 0x00), Color::new(0xAA, 0x00, 0x00),
+    Color::new(0x00, 0xAA, 0x00), Color::new(0xAA, 0x55, 0x00),
+    Color::new(0x00, 0x00, 0xAA), Color::new(0xAA, 0x00, 0xAA),
+    Color::new(0x00, 0xAA, 0xAA), Color::new(0xAA, 0xAA, 0xAA),
+    Color::new(0x55, 0x55, 0x55), Color::new(0xFF, 0x55, 0x55),
+    Color::new(0x55, 0xFF, 0x55), Color::new(0xFF, 0xFF, 0x55),
+    Color::new(0x55, 0x55, 0xFF), Color::new(0xFF, 0x55, 0xFF),
+    Color::new(0x55, 0xFF, 0xFF), Color::new(0xFF, 0xFF, 0xFF)
+];
+
+const DEFAULT_FG: usize = 7;
+const DEFAULT_BG: usize = 0;
+
+pub static mut FBCON: FramebufferConsole = FramebufferConsole::new();
+
+#[derive(Clone, Copy, PartialEq)]
+enum AnsiState {
+    Normal,
+    Escape,
+    Csi
+}
+
+// Text console rendering into a linear framebuffer with a bitmap font
+pub struct FramebufferConsole {
+    fb: Option<Framebuffer>,
+    font: Option<Font>,
+    cols: usize,
+    rows: usize,
+    cursor_x: usize,
+    cursor_y: usize,
+    fg: usize,
+    bg: usize,
+    bold: bool,
+    state: AnsiState,
+    params: [usize; MAX_ANSI_PARAMS],
+    num_params: usize
+}
+
+impl FramebufferConsole {
+    pub const fn new() -> Self {
+        FramebufferConsole {
+            fb: None,
+            font: None,
+            cols: 0,
+            rows: 0,
+            cursor_x: 0,
+            cursor_y: 0,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            bold: false,
+            state: AnsiState::Normal,
+            params: [0; MAX_ANSI_PARAMS],
+            num_params: 0
+        }
+    }
+
+    // Attach console to framebuffer and clear it, returns false if not even one character fits
+    pub fn attach(&mut self, fb: Framebuffer, font: Font) -> bool {
+        let cols = fb.width()  / font.width();
+        let rows = fb.height() / font.height();
+        if cols == 0 || rows == 0 {
+            return false
+        }
+
+        self.fb       = Some(fb);
+        self.font     = Some(font);
+        self.cols     = cols;
+        self.rows     = rows;
+        self.cursor_x = 0;
+        self.cursor_y = 0;
+        fb.clear(PALETTE[DEFAULT_BG]);
+        true
+    }
+
+    fn fg_color(&self) -> Color {
+        match self.bold && self.fg < 8 {
+            true => PALETTE[self.fg + 8],
+            false => PALETTE[self.fg]
+        }
+    }
+
+    // Draw character cell at cursor position
+    fn draw_char(&self, c: char) {
+        let (fb, font) = match (self.fb, self.font) {
+            (Some(fb), Some(font)) => (fb, font),
+            _ => return
+        };
+
+        let glyph = font.glyph(c);
+        let fg    = self.fg_color();
+        let bg    = PALETTE[self.bg];
+        let x0    = self.cursor_x * font.width();
+        let y0    = self.cursor_y * font.height();
+        for y in 0..font.height() {
+            for x in 0..font.width() {
+                fb.put_pixel(x0 + x, y0 + y, match font.is_set(glyph, x, y) {
+                    true => fg,
+                    false => bg
+                });
+            }
+        }
+    }
+
+    // Clear cells from column to end of provided row
+    fn clear_row_from(&self, row: usize, col: usize) {
+        if let (Some(fb), Some(font)) = (self.fb, self.font) {
+            fb.fill_rect(col * font.width(), row * font.height(),
+                         (self.cols - col) * font.width(), font.height(), PALETTE[self.bg]);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.cursor_x = 0;
+        if self.cursor_y + 1 < self.rows {
+            self.cursor_y += 1;
+            return
+        }
+
+        // Scroll by one text row
+        if let (Some(fb), Some(font)) = (self.fb, self.font) {
+            fb.scroll_up(font.height(), PALETTE[self.bg]);
+        }
+    }
+
+    fn put_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.cursor_x = 0,
+            '\t' => {
+                let next = (self.cursor_x / TAB_WIDTH + 1) * TAB_WIDTH;
+                match next >= self.cols {
+                    true => self.newline(),
+                    false => self.cursor_x = next
+                }
+            },
+            '\x08' => self.cursor_x = self.cursor_x.saturating_sub(1),
+            _ => {
+                if self.cursor_x >= self.cols {
+                    self.newline();
+                }
+                self.draw_char(c);
+                self.cursor_x += 1;
+            }
+        }
+    }
+
+    // Apply "Select Graphic Rendition" parameters
+    fn apply_sgr(&mut self) {
+        if self.num_params == 0 {
+            self.num_params = 1;
+            self.params[0]  = 0;
+        }
+
+        for i in 0..self.num_params {
+            match self.params[i] {
+                0 => {
+                    self.fg   = DEFAULT_FG;
+                    self.bg   = DEFAULT_BG;
+                    self.bold = false;
+                },
+                1 => self.bold = true,
+                22 => self.bold = false,
+                p @ 30..=37 => self.fg = p - 30,
+                39 => self.fg = DEFAULT_FG,
+                p @ 40..=47 => self.bg = p - 40,
+                49 => self.bg = DEFAULT_BG,
+                p @ 90..=97 => self.fg = p - 90 + 8,
+                p @ 100..=107 => self.bg = p - 100 + 8,
+                _ => { }
+            }
+        }
+    }
+
+    // Execute final byte of a CSI sequence
+    fn execute_csi(&mut self, c: char) {
+        let first = match self.num_params {
+            0 => 0,
+            _ => self.params[0]
+        };
+
+        match c {
+            'm' => self.apply_sgr(),
+            // Cursor position, parameters are 1-based
+            'H' | 'f' => {
+                let row = first.saturating_sub(1);
+                let col = match self.num_params > 1 {
+                    true => self.params[1].saturating_sub(1),
+                    false => 0
+                };
+                self.cursor_y = core::cmp::min(row, self.rows - 1);
+                self.cursor_x = core::cmp::min(col, self.cols - 1);
+            },
+            // Erase display (only full clear is supported)
+            'J' if first == 2 => {
+                if let Some(fb) = self.fb {
+                    fb.clear(PALETTE[self.bg]);
+                }
+                self.cursor_x = 0;
+                self.cursor_y = 0;
+            },
+            // Erase to end of line
+            'K' if self.cursor_x < self.cols => {
+                self.clear_row_from(self.cursor_y, self.cursor_x);
+            },
+            _ => { }
+        }
+    }
+
+    // Feed character through ANSI escape state machine
+    fn feed(&mut self, c: char) {
+        match self.state {
+            AnsiState::Normal => match c {
+                '\x1B' => self.state = AnsiState::Escape,
+                _ => self.put_char(c)
+            },
+            AnsiState::Escape => match c {
+                '[' => {
+                    self.state      = AnsiState::Csi;
+                    self.params     = [0; MAX_ANSI_PARAMS];
+                    self.num_params = 0;
+                },
+                _ => self.state = AnsiState::Normal
+            },
+            AnsiState::Csi => match c {
+                '0'..='9' => {
+                    if self.num_params == 0 {
+                        self.num_params = 1;
+                    }
+                    let p = &mut self.params[self.num_params - 1];
+                    *p = p.saturating_mul(10).saturating_add(c as usize - '0' as usize);
+                },
+                ';' if self.num_params < MAX_ANSI_PARAMS => {
+                    // Empty leading parameter still counts
+                    if self.num_params == 0 {
+                        self.num_params = 1;
+                    }
+                    self.num_params += 1;
+                },
+                '\x40'..='\x7E' => {
+                    self.execute_csi(c);
+                    self.state = AnsiState::Normal;
+                },
+                _ => { }
+            }
+        }
+    }
+}
+
+// The framebuffer is mapped in the kernel half every CPU shares
+unsafe impl Send for FramebufferConsole { }
+
+impl ConsoleSink for FramebufferConsole {
+    fn name(&self) -> &'static str {
+        "fbcon"
+    }
+
+    fn write_str(&mut self, s: &str) {
+        if self.fb.is_none() {
+            return
+        }
+
+        for c in s.chars() {
+            self.feed(c);
+        }
+    }
+}
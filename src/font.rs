@@ -0,0 +1,99 @@
+// Built-in console font: public domain VGA-derived 8x8 glyphs with every row doubled to 8x16, stored as PSF1
+static DEFAULT_FONT_DATA: &[u8] = include_bytes!("fonts/font8x16.psf");
+
+const PSF1_MAGIC:     [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512:  u8      = 0x01;
+const PSF1_HEADER_SIZE: usize = 4;
+
+const PSF2_MAGIC:     [u8; 4] = [0x72, 0xB5, 0x4A, 0x86];
+const PSF2_HEADER_SIZE: usize = 32;
+
+// Bitmap font parsed from a PSF1 or PSF2 blob
+#[derive(Clone, Copy)]
+pub struct Font {
+    data: &'static [u8],
+    glyph_offset: usize,
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    bytes_per_row: usize,
+    width: usize,
+    height: usize
+}
+
+fn read_u32(data: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize
+}
+
+impl Font {
+    // Parse PSF1/PSF2 font, returns None if header is invalid or glyph data is truncated
+    pub fn parse(data: &'static [u8]) -> Option<Self> {
+        let font = if data.len() >= PSF1_HEADER_SIZE && data[0..2] == PSF1_MAGIC {
+            let height = data[3] as usize;
+            Font {
+                data,
+                glyph_offset: PSF1_HEADER_SIZE,
+                glyph_count: match data[2] & PSF1_MODE_512 {
+                    0 => 256,
+                    _ => 512
+                },
+                bytes_per_glyph: height,
+                bytes_per_row: 1,
+                width: 8,
+                height
+            }
+        } else if data.len() >= PSF2_HEADER_SIZE && data[0..4] == PSF2_MAGIC {
+            let width = read_u32(data, 28);
+            Font {
+                data,
+                glyph_offset: read_u32(data, 8),
+                glyph_count: read_u32(data, 16),
+                bytes_per_glyph: read_u32(data, 20),
+                bytes_per_row: width.div_ceil(8),
+                width,
+                height: read_u32(data, 24)
+            }
+        } else {
+            return None
+        };
+
+        if font.width == 0 || font.height == 0 || font.glyph_count == 0 {
+            return None
+        }
+
+        if font.glyph_offset + font.glyph_count * font.bytes_per_glyph > data.len() {
+            return None
+        }
+        Some(font)
+    }
+
+    // Get built-in font
+    pub fn default() -> Self {
+        match Font::parse(DEFAULT_FONT_DATA) {
+            Some(f) => f,
+            None => panic!("Built-in console font is invalid")
+        }
+    }
+
+    pub const fn width(&self) -> usize {
+        self.width
+    }
+
+    pub const fn height(&self) -> usize {
+        self.height
+    }
+
+    // Get glyph bitmap for character, falling back to '?' for characters the font doesn't cover
+    pub fn glyph(&self, c: char) -> &'static [u8] {
+        let index = match (c as usize) < self.glyph_count {
+            true => c as usize,
+            false => '?' as usize
+        };
+        let start = self.glyph_offset + index * self.bytes_per_glyph;
+        &self.data[start..start + self.bytes_per_glyph]
+    }
+
+    // Check if pixel (x, y) of glyph is set, leftmost pixel is the MSB of each row
+    pub fn is_set(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        glyph[y * self.bytes_per_row + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
@@ -0,0 +1,181 @@
+use crate::asm_wrappers::{clts, cpuid, fxrstor, fxsave, read_cr0, read_cr4, write_cr0, write_cr4, xrstor, xsave, xsetbv, Cr0, Cr4, XCR0};
+use crate::cpu::{self, Feature};
+use crate::percpu;
+
+// XCR0 state components
+const XSTATE_X87: u64 = 1 << 0;
+const XSTATE_SSE: u64 = 1 << 1;
+const XSTATE_AVX: u64 = 1 << 2;
+
+// Legacy region, XSAVE header and AVX upper halves, which is everything we ever enable
+pub const FPU_STATE_SIZE: usize = 1024;
+
+// Power-on control words: every x87 and SSE exception masked
+const DEFAULT_FCW:   u16 = 0x037F;
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+// Saved FPU, SSE and AVX registers of one context
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+pub struct FpuState {
+    area: [u8; FPU_STATE_SIZE]
+}
+
+impl FpuState {
+    // Clean state, with an all zero XSAVE header restoring every component to its initial value
+    pub const fn new() -> Self {
+        let mut area = [0u8; FPU_STATE_SIZE];
+        let fcw   = DEFAULT_FCW.to_le_bytes();
+        let mxcsr = DEFAULT_MXCSR.to_le_bytes();
+        area[0] = fcw[0];
+        area[1] = fcw[1];
+        area[24] = mxcsr[0];
+        area[25] = mxcsr[1];
+        area[26] = mxcsr[2];
+        area[27] = mxcsr[3];
+        FpuState { area }
+    }
+}
+
+static mut XSAVE_MASK: u64 = 0;
+static mut STATE_SIZE: usize = 512;
+
+// Context whose state is in the registers, and context that runs now. They differ after a switch until
+// the new context first touches the FPU, which is when #NM swaps them
+percpu! {
+    static OWNER:   Option<*mut FpuState> = None;
+    static CURRENT: Option<*mut FpuState> = None;
+    static IN_KERNEL_FPU: bool = false;
+}
+
+// Enable x87, SSE and where available AVX, and make the first use of them trap. Returns false without SSE2
+pub unsafe fn init() -> bool {
+    if !cpu::has(Feature::Fxsr) || !cpu::has(Feature::Sse2) {
+        return false
+    }
+
+    write_cr0(read_cr0().without(Cr0::EM) | Cr0::MP | Cr0::NE | Cr0::TS);
+    let mut cr4 = read_cr4() | Cr4::OSFXSR | Cr4::OSXMMEXCPT;
+
+    if cpu::has(Feature::Xsave) {
+        cr4 = cr4 | Cr4::OSXSAVE;
+        write_cr4(cr4);
+
+        let mut mask = XSTATE_X87 | XSTATE_SSE;
+        if cpu::has(Feature::Avx) {
+            mask |= XSTATE_AVX;
+        }
+        xsetbv(XCR0, mask);
+
+        // EBX reports the size needed for the components enabled in XCR0
+        if cpuid(0xD, 0).ebx as usize > FPU_STATE_SIZE {
+            mask = XSTATE_X87 | XSTATE_SSE;
+            xsetbv(XCR0, mask);
+        }
+        XSAVE_MASK = mask;
+        STATE_SIZE = cpuid(0xD, 0).ebx as usize;
+    } else {
+        write_cr4(cr4);
+    }
+    true
+}
+
+pub fn uses_xsave() -> bool {
+    unsafe { XSAVE_MASK != 0 }
+}
+
+pub fn avx_enabled() -> bool {
+    unsafe { XSAVE_MASK & XSTATE_AVX != 0 }
+}
+
+// Bytes of state saved per context
+pub fn state_size() -> usize {
+    unsafe { STATE_SIZE }
+}
+
+unsafe fn save(state: *mut FpuState) {
+    match XSAVE_MASK {
+        0 => fxsave((*state).area.as_mut_ptr()),
+        mask => xsave((*state).area.as_mut_ptr(), mask)
+    }
+}
+
+unsafe fn restore(state: *const FpuState) {
+    match XSAVE_MASK {
+        0 => fxrstor((*state).area.as_ptr()),
+        mask => xrstor((*state).area.as_ptr(), mask)
+    }
+}
+
+// Make context current on context switch, its registers are only loaded once it uses the FPU
+pub unsafe fn switch_to(state: Option<*mut FpuState>) {
+    CURRENT.set(state);
+    write_cr0(read_cr0() | Cr0::TS);
+}
+
+// Forget context that is going away on this CPU, so its state is never saved again
+pub unsafe fn release(state: *mut FpuState) {
+    for v in [&OWNER, &CURRENT] {
+        v.with(|s| if *s == Some(state) {
+            *s = None;
+        });
+    }
+}
+
+// Handle #NM by swapping in the current context's state, returns false if nothing may use the FPU now
+pub unsafe fn handle_device_not_available() -> bool {
+    let current = match CURRENT.get() {
+        None => return false,
+        Some(c) => c
+    };
+
+    clts();
+    let owner = OWNER.get();
+    if owner != Some(current) {
+        if let Some(o) = owner {
+            save(o);
+        }
+        restore(current);
+        OWNER.set(Some(current));
+    }
+    true
+}
+
+// Lets kernel code use SIMD until dropped. Functions doing so opt in with #[target_feature]. Keeps the
+// thread on this CPU meanwhile, since a switch would set TS and hand the registers to someone else
+pub struct KernelFpu {
+    _private: ()
+}
+
+impl Drop for KernelFpu {
+    fn drop(&mut self) {
+        unsafe {
+            // Registers now hold kernel values, so the next user of the FPU has to reload its own
+            write_cr0(read_cr0() | Cr0::TS);
+            IN_KERNEL_FPU.set(false);
+        }
+        percpu::preempt_enable();
+    }
+}
+
+// Save the state of whoever owns the registers and hand them to the kernel, must not be nested
+pub fn kernel_fpu_begin() -> KernelFpu {
+    percpu::preempt_disable();
+    unsafe {
+        if IN_KERNEL_FPU.get() {
+            panic!("kernel_fpu_begin nested");
+        }
+        IN_KERNEL_FPU.set(true);
+
+        clts();
+        if let Some(owner) = OWNER.with(|o| o.take()) {
+            save(owner);
+        }
+    }
+    KernelFpu { _private: () }
+}
+
+// Same as dropping the guard
+pub fn kernel_fpu_end(guard: KernelFpu) {
+    drop(guard);
+}
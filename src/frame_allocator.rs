@@ -0,0 +1,218 @@
+use core::ptr::{addr_of_mut, write_bytes};
+
+use crate::constants::*;
+use crate::memory::{self, MemoryKind};
+use crate::sync::SpinLock;
+
+const NUM_PHYS_ADDR_MAP_ENTRIES: usize = MAX_FRAMES / 64;
+
+pub static FRAME_ALLOCATOR: SpinLock<FrameAllocator> = SpinLock::new(FrameAllocator::new());
+
+// References to allocated frames beyond the first, kept out of the allocator so it lands in .bss instead of the image.
+// Only touched through methods of the allocator, so FRAME_ALLOCATOR's lock covers it
+static mut FRAME_SHARES: [u8; MAX_FRAMES] = [0; MAX_FRAMES];
+
+// Bitmap allocator for physical page frames, a set bit marks a frame as in use
+pub struct FrameAllocator {
+    phys_addr_map: [u64; NUM_PHYS_ADDR_MAP_ENTRIES],
+    total_frames: usize,
+    free_frames: usize,
+    next_free_hint: usize
+}
+
+impl FrameAllocator {
+    // Return new allocator with every frame marked as in use
+    pub const fn new() -> Self {
+        FrameAllocator {
+            phys_addr_map: [!0; NUM_PHYS_ADDR_MAP_ENTRIES],
+            total_frames: 0,
+            free_frames: 0,
+            next_free_hint: 0
+        }
+    }
+
+    // Release usable regions of memory map below provided limit, frame 0 is never handed out
+    pub fn init(&mut self, mem_limit: Option<usize>) {
+        let limit = match mem_limit {
+            None => PMEM_MAX,
+            Some(l) => core::cmp::min(l, PMEM_MAX)
+        };
+
+        for r in memory::regions() {
+            if r.kind != MemoryKind::Usable {
+                continue;
+            }
+
+            let start = core::cmp::max((r.base + PAGE_SIZE - 1) & !0xFFF, PAGE_SIZE);
+            let end   = core::cmp::min(r.end() & !0xFFF, limit);
+            let mut addr = start;
+            while addr < end {
+                self.set_free(addr / PAGE_SIZE);
+                self.total_frames += 1;
+                self.free_frames  += 1;
+                addr += PAGE_SIZE;
+            }
+        }
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        self.phys_addr_map[frame / 64] &= !(1 << (frame % 64));
+    }
+
+    fn set_used(&mut self, frame: usize) {
+        self.phys_addr_map[frame / 64] |= 1 << (frame % 64);
+    }
+
+    fn is_frame_used(&self, frame: usize) -> bool {
+        self.phys_addr_map[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    // Get frame number of allocated frame at physical address
+    fn allocated_frame(&self, ptr: Option<*const ()>) -> Option<usize> {
+        let p = ptr?;
+        if p as usize & 0xFFF != 0x000 {
+            return None
+        }
+
+        let frame = p as usize / PAGE_SIZE;
+        match frame != 0 && frame < MAX_FRAMES && self.is_frame_used(frame) {
+            false => None,
+            true => Some(frame)
+        }
+    }
+
+    // Check if frame containing physical address is in use
+    pub fn is_allocated(&self, ptr: Option<*const ()>) -> bool {
+        let frame = match ptr {
+            None => return true,
+            Some(p) => p as usize / PAGE_SIZE
+        };
+
+        match frame < MAX_FRAMES {
+            false => true,
+            true => self.is_frame_used(frame)
+        }
+    }
+
+    // Find free frame without allocating it
+    pub fn find_free_frame(&self) -> Option<*const ()> {
+        self.find_free_frames(1)
+    }
+
+    // Find run of free physically-contiguous frames without allocating them
+    pub fn find_free_frames(&self, num_frames: usize) -> Option<*const ()> {
+        if num_frames == 0 || self.free_frames < num_frames {
+            return None
+        }
+
+        let mut run_start: usize = 0;
+        let mut run_len:   usize = 0;
+        let mut i:       usize = self.next_free_hint % MAX_FRAMES;
+        let mut scanned: usize = 0;
+        // Scan from hint to end, then wrap around once
+        while scanned < MAX_FRAMES {
+            if i == MAX_FRAMES {
+                i = 0;
+                run_len = 0;
+            }
+
+            // Skip fully used words quickly
+            if i.is_multiple_of(64) && self.phys_addr_map[i / 64] == !0 {
+                i       += 64;
+                scanned += 64;
+                run_len  = 0;
+                continue;
+            }
+
+            match self.is_frame_used(i) {
+                true => run_len = 0,
+                false => {
+                    if run_len == 0 {
+                        run_start = i;
+                    }
+                    run_len += 1;
+                    if run_len == num_frames {
+                        return Some((run_start * PAGE_SIZE) as *const ())
+                    }
+                }
+            }
+            i       += 1;
+            scanned += 1;
+        }
+        None
+    }
+
+    // Allocate single frame
+    pub fn allocate_frame(&mut self) -> Option<*const ()> {
+        self.allocate_frames(1)
+    }
+
+    // Allocate physically-contiguous frames
+    pub fn allocate_frames(&mut self, num_frames: usize) -> Option<*const ()> {
+        let p = self.find_free_frames(num_frames)?;
+        let first = p as usize / PAGE_SIZE;
+        for frame in first..first + num_frames {
+            self.set_used(frame);
+        }
+        unsafe {
+            write_bytes(addr_of_mut!(FRAME_SHARES[first]), 0, num_frames);
+        }
+        self.free_frames   -= num_frames;
+        self.next_free_hint = first + num_frames;
+        Some(p)
+    }
+
+    // Drop reference to frame, returning it to allocator once the last one is gone, returns None if it wasn't allocated
+    pub fn free_frame(&mut self, ptr: Option<*const ()>) -> Option<*const ()> {
+        let frame = self.allocated_frame(ptr)?;
+        unsafe {
+            if FRAME_SHARES[frame] != 0 {
+                FRAME_SHARES[frame] -= 1;
+                return ptr
+            }
+        }
+
+        self.set_free(frame);
+        self.free_frames += 1;
+        if frame < self.next_free_hint {
+            self.next_free_hint = frame;
+        }
+        ptr
+    }
+
+    // Take additional reference to allocated frame, fails if it isn't allocated or has too many
+    pub fn ref_frame(&mut self, ptr: Option<*const ()>) -> bool {
+        let frame = match self.allocated_frame(ptr) {
+            None => return false,
+            Some(f) => f
+        };
+
+        unsafe {
+            match FRAME_SHARES[frame].checked_add(1) {
+                None => false,
+                Some(n) => {
+                    FRAME_SHARES[frame] = n;
+                    true
+                }
+            }
+        }
+    }
+
+    // Get number of references to frame, zero if it isn't allocated
+    pub fn ref_count(&self, ptr: Option<*const ()>) -> usize {
+        match self.allocated_frame(ptr) {
+            None => 0,
+            Some(frame) => unsafe { FRAME_SHARES[frame] as usize + 1 }
+        }
+    }
+
+    // Number of frames handed to allocator at boot
+    pub const fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    // Number of frames currently free
+    pub const fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+}
@@ -0,0 +1,242 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use limine::{LimineFramebuffer, LimineFramebufferResponse};
+
+use crate::constants::PAGE_SIZE;
+use crate::memory;
+use crate::pager::Pager;
+
+pub const MAX_FRAMEBUFFERS: usize = 4;
+
+// Limine memory model value for linear RGB framebuffers, the only one defined by the protocol
+const MEMORY_MODEL_RGB: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+}
+
+// Layout of a single pixel as described by the bootloader's channel masks
+#[derive(Clone, Copy)]
+pub struct PixelFormat {
+    pub bpp: u16,
+    pub red_size: u8,
+    pub red_shift: u8,
+    pub green_size: u8,
+    pub green_shift: u8,
+    pub blue_size: u8,
+    pub blue_shift: u8
+}
+
+impl PixelFormat {
+    // Scale 8-bit channel down to mask size and move it to its position
+    const fn channel(value: u8, size: u8, shift: u8) -> u32 {
+        match size {
+            0 => 0,
+            1..8 => ((value as u32) >> (8 - size)) << shift,
+            _ => (value as u32) << shift
+        }
+    }
+
+    // Pack color into raw pixel value
+    pub const fn pack(&self, c: Color) -> u32 {
+        PixelFormat::channel(c.r, self.red_size, self.red_shift)
+            | PixelFormat::channel(c.g, self.green_size, self.green_shift)
+            | PixelFormat::channel(c.b, self.blue_size, self.blue_shift)
+    }
+
+    pub const fn bytes_per_pixel(&self) -> usize {
+        (self.bpp as usize).div_ceil(8)
+    }
+}
+
+// Linear framebuffer
+#[derive(Clone, Copy)]
+pub struct Framebuffer {
+    address: *mut u8,
+    width: usize,
+    height: usize,
+    pitch: usize,
+    format: PixelFormat
+}
+
+impl Framebuffer {
+    // Build framebuffer from Limine description, returns None for unsupported memory models or depths
+    pub fn from_limine(fb: &LimineFramebuffer) -> Option<Self> {
+        if fb.memory_model != MEMORY_MODEL_RGB {
+            return None
+        }
+
+        match fb.bpp {
+            16 | 24 | 32 => { },
+            _ => return None
+        }
+
+        let address = fb.address.as_mut_ptr()?;
+
+        Some(Framebuffer {
+            address,
+            width:  fb.width  as usize,
+            height: fb.height as usize,
+            pitch:  fb.pitch  as usize,
+            format: PixelFormat {
+                bpp:         fb.bpp,
+                red_size:    fb.red_mask_size,
+                red_shift:   fb.red_mask_shift,
+                green_size:  fb.green_mask_size,
+                green_shift: fb.green_mask_shift,
+                blue_size:   fb.blue_mask_size,
+                blue_shift:  fb.blue_mask_shift
+            }
+        })
+    }
+
+    pub const fn address(&self) -> *mut u8 {
+        self.address
+    }
+
+    pub const fn width(&self) -> usize {
+        self.width
+    }
+
+    pub const fn height(&self) -> usize {
+        self.height
+    }
+
+    // Size of framebuffer memory in bytes
+    pub const fn size(&self) -> usize {
+        self.pitch * self.height
+    }
+
+    // Write raw pixel value at byte offset
+    unsafe fn write_raw(&self, offset: usize, value: u32) {
+        let p = self.address.add(offset);
+        match self.format.bytes_per_pixel() {
+            4 => write_volatile(p as *mut u32, value),
+            3 => {
+                write_volatile(p,        value        as u8);
+                write_volatile(p.add(1), (value >> 8)  as u8);
+                write_volatile(p.add(2), (value >> 16) as u8);
+            },
+            _ => write_volatile(p as *mut u16, value as u16)
+        }
+    }
+
+    // Set pixel, ignoring out-of-bounds coordinates
+    pub fn put_pixel(&self, x: usize, y: usize, c: Color) {
+        if x >= self.width || y >= self.height {
+            return
+        }
+
+        unsafe {
+            self.write_raw(y * self.pitch + x * self.format.bytes_per_pixel(), self.format.pack(c));
+        }
+    }
+
+    // Fill rectangle clipped to framebuffer bounds
+    pub fn fill_rect(&self, x: usize, y: usize, w: usize, h: usize, c: Color) {
+        let value = self.format.pack(c);
+        let bpp   = self.format.bytes_per_pixel();
+        let x_end = core::cmp::min(x + w, self.width);
+        let y_end = core::cmp::min(y + h, self.height);
+
+        for row in y..y_end {
+            for col in x..x_end {
+                unsafe {
+                    self.write_raw(row * self.pitch + col * bpp, value);
+                }
+            }
+        }
+    }
+
+    // Clear whole framebuffer
+    pub fn clear(&self, c: Color) {
+        self.fill_rect(0, 0, self.width, self.height, c);
+    }
+
+    // Move contents up by provided number of pixel rows and fill vacated rows with color
+    pub fn scroll_up(&self, rows: usize, c: Color) {
+        if rows >= self.height {
+            self.clear(c);
+            return
+        }
+
+        // Copy whole 32-bit words, pitch is always a multiple of 4 on supported depths
+        let words = (self.height - rows) * self.pitch / 4;
+        let dst   = self.address as *mut u32;
+        unsafe {
+            let src = self.address.add(rows * self.pitch) as *const u32;
+            for i in 0..words {
+                write_volatile(dst.add(i), read_volatile(src.add(i)));
+            }
+        }
+        self.fill_rect(0, self.height - rows, self.width, rows, c);
+    }
+}
+
+const NO_FRAMEBUFFER: Option<Framebuffer> = None;
+
+static mut FRAMEBUFFERS: [Option<Framebuffer>; MAX_FRAMEBUFFERS] = [NO_FRAMEBUFFER; MAX_FRAMEBUFFERS];
+
+// Record every supported framebuffer in Limine response, returns how many were found
+pub fn init(response: &LimineFramebufferResponse) -> usize {
+    let fbs = match response.framebuffers() {
+        None => return 0,
+        Some(f) => f
+    };
+
+    let mut count: usize = 0;
+    for fb in fbs.iter() {
+        if count == MAX_FRAMEBUFFERS {
+            break;
+        }
+
+        if let Some(f) = Framebuffer::from_limine(fb) {
+            unsafe {
+                FRAMEBUFFERS[count] = Some(f);
+            }
+            count += 1;
+        }
+    }
+    count
+}
+
+// Get framebuffer by index
+pub fn get(index: usize) -> Option<Framebuffer> {
+    match index < MAX_FRAMEBUFFERS {
+        false => None,
+        true => unsafe { FRAMEBUFFERS[index] }
+    }
+}
+
+// Map every framebuffer into provided page table at the same virtual address the bootloader used
+pub unsafe fn map_all(pager: &mut Pager) -> bool {
+    for i in 0..MAX_FRAMEBUFFERS {
+        let fb = match get(i) {
+            None => continue,
+            Some(f) => f
+        };
+
+        // Bootloader hands out framebuffers as direct map addresses
+        let vaddr = fb.address() as usize & !0xFFF;
+        let paddr = match memory::virt_to_phys(vaddr as *const ()) {
+            None => return false,
+            Some(p) => p as usize
+        };
+
+        let num_pages = (fb.address() as usize + fb.size() - vaddr).div_ceil(PAGE_SIZE);
+        match pager.allocate_physically_contiguous_pages(Some(paddr as *const ()), Some(vaddr as *const ()), num_pages) {
+            None => return false,
+            Some(_p) => { }
+        }
+    }
+    true
+}
@@ -0,0 +1,109 @@
+use crate::asm_wrappers::{lgdt, load_segments, ltr};
+use crate::constants::MAX_CPUS;
+use crate::stack::{self, StackOwner};
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+// sysret finds the user segments right after the kernel data segment, data first. User selectors have RPL 3
+pub const USER_DATA_SELECTOR:   u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR:   u16 = 0x20 | 3;
+pub const TSS_SELECTOR:         u16 = 0x28;
+
+// Interrupt stack table slot used for double faults, which mostly come from a broken stack
+pub const IST_DOUBLE_FAULT: u8 = 1;
+
+// Long mode code and data segments, present, ring 0
+const KERNEL_CODE: u64 = 0x00AF_9A00_0000_FFFF;
+const KERNEL_DATA: u64 = 0x00CF_9200_0000_FFFF;
+// Same for ring 3
+const USER_CODE:   u64 = 0x00AF_FA00_0000_FFFF;
+const USER_DATA:   u64 = 0x00CF_F200_0000_FFFF;
+
+const GDT_ENTRIES: usize = 7;
+
+// Available 64-bit TSS, present, ring 0
+const TSS_TYPE: u64 = 0x89;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Tss {
+    reserved0: u32,
+    // Stack pointers loaded when entering rings 0-2 from a less privileged ring
+    rsp: [u64; 3],
+    reserved1: u64,
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16
+}
+
+impl Tss {
+    const fn new() -> Self {
+        Tss {
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            // No I/O permission bitmap
+            iomap_base: core::mem::size_of::<Tss>() as u16
+        }
+    }
+}
+
+// GDT and TSS of one CPU
+#[derive(Clone, Copy)]
+struct CpuTables {
+    gdt: [u64; GDT_ENTRIES],
+    tss: Tss
+}
+
+const NO_TABLES: CpuTables = CpuTables { gdt: [0; GDT_ENTRIES], tss: Tss::new() };
+
+static mut TABLES: [CpuTables; MAX_CPUS] = [NO_TABLES; MAX_CPUS];
+
+// Build the two GDT entries describing a TSS
+fn tss_descriptor(tss: &Tss) -> (u64, u64) {
+    let base  = tss as *const Tss as u64;
+    let limit = (core::mem::size_of::<Tss>() - 1) as u64;
+    let low = (limit & 0xFFFF)
+        | ((base & 0xFF_FFFF) << 16)
+        | (TSS_TYPE << 40)
+        | (((limit >> 16) & 0xF) << 48)
+        | (((base >> 24) & 0xFF) << 56);
+    (low, base >> 32)
+}
+
+// Load GDT and TSS of CPU and reload segment registers, returns false if its exception stack can't be allocated
+pub unsafe fn init(cpu: usize) -> bool {
+    if cpu >= MAX_CPUS {
+        return false
+    }
+
+    let ist = match stack::allocate(StackOwner::Exception(cpu)) {
+        None => return false,
+        Some(s) => s
+    };
+
+    let t = &mut TABLES[cpu];
+    t.tss = Tss::new();
+    t.tss.ist[IST_DOUBLE_FAULT as usize - 1] = ist.top() as u64;
+
+    let (tss_low, tss_high) = tss_descriptor(&t.tss);
+    t.gdt = [0, KERNEL_CODE, KERNEL_DATA, USER_DATA, USER_CODE, tss_low, tss_high];
+
+    lgdt(t.gdt.as_ptr() as usize, (core::mem::size_of::<[u64; GDT_ENTRIES]>() - 1) as u16);
+    load_segments(KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR);
+    ltr(TSS_SELECTOR);
+    true
+}
+
+// Set stack CPU switches to when an interrupt arrives in user mode
+pub fn set_kernel_stack(cpu: usize, top: usize) {
+    if cpu < MAX_CPUS {
+        unsafe {
+            TABLES[cpu].tss.rsp[0] = top as u64;
+        }
+    }
+}
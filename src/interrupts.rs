@@ -0,0 +1,256 @@
+use crate::address_space;
+use crate::apic;
+use crate::asm_wrappers::{lidt, rcr2};
+use crate::console;
+use crate::constants::KERNEL_HALF_BASE;
+use crate::fpu;
+use crate::gdt;
+use crate::pager::Pager;
+use crate::percpu::{self, InterruptEntry};
+use crate::sched;
+use crate::softirq;
+use crate::stack;
+use crate::thread;
+use crate::timer;
+use crate::tlb;
+use crate::PAGE_TABLE;
+
+pub const VECTOR_INVALID_OPCODE:        u8 = 6;
+pub const VECTOR_DEVICE_NOT_AVAILABLE: u8 = 7;
+pub const VECTOR_DOUBLE_FAULT:         u8 = 8;
+pub const VECTOR_GENERAL_PROTECTION:   u8 = 13;
+pub const VECTOR_PAGE_FAULT:           u8 = 14;
+
+// Page fault error code bits
+const PF_PRESENT: u64 = 1 << 0;
+const PF_WRITE:   u64 = 1 << 1;
+const PF_USER:    u64 = 1 << 2;
+
+// 64-bit interrupt gate, present, only reachable from ring 0 through software interrupts
+const GATE_INTERRUPT: u8 = 0x8E;
+
+// State pushed by the CPU when entering a handler
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptStackFrame {
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64
+}
+
+pub type Handler = extern "x86-interrupt" fn(InterruptStackFrame);
+pub type HandlerWithErrorCode = extern "x86-interrupt" fn(InterruptStackFrame, u64);
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attr: u8,
+    offset_mid: u16,
+    offset_high: u32,
+    reserved: u32
+}
+
+impl IdtEntry {
+    const fn missing() -> Self {
+        IdtEntry {
+            offset_low: 0,
+            selector: 0,
+            ist: 0,
+            type_attr: 0,
+            offset_mid: 0,
+            offset_high: 0,
+            reserved: 0
+        }
+    }
+
+    fn new(handler: usize, selector: u16) -> Self {
+        IdtEntry {
+            offset_low: handler as u16,
+            selector,
+            ist: 0,
+            type_attr: GATE_INTERRUPT,
+            offset_mid: (handler >> 16) as u16,
+            offset_high: (handler >> 32) as u32,
+            reserved: 0
+        }
+    }
+}
+
+static mut IDT: [IdtEntry; 256] = [IdtEntry::missing(); 256];
+
+fn set_gate(vector: u8, handler: usize) {
+    unsafe {
+        IDT[vector as usize] = IdtEntry::new(handler, gdt::KERNEL_CODE_SELECTOR);
+    }
+}
+
+// Make vector switch to interrupt stack table entry of the TSS, 0 keeps the interrupted stack
+pub fn set_ist(vector: u8, ist: u8) {
+    unsafe {
+        IDT[vector as usize].ist = ist & 0x7;
+    }
+}
+
+// Install handler for vector without error code
+pub fn set_handler(vector: u8, handler: Handler) {
+    set_gate(vector, handler as usize);
+}
+
+// Install handler for exception pushing an error code
+pub fn set_handler_with_error_code(vector: u8, handler: HandlerWithErrorCode) {
+    set_gate(vector, handler as usize);
+}
+
+// Load IDT on this CPU
+pub unsafe fn load() {
+    lidt(core::ptr::addr_of!(IDT) as usize, (core::mem::size_of::<[IdtEntry; 256]>() - 1) as u16);
+}
+
+// Install exception handlers and load IDT, the GDT must already be loaded
+pub unsafe fn init() {
+    set_handler(VECTOR_INVALID_OPCODE, invalid_opcode);
+    set_handler(VECTOR_DEVICE_NOT_AVAILABLE, device_not_available);
+    set_handler_with_error_code(VECTOR_DOUBLE_FAULT, double_fault);
+    set_ist(VECTOR_DOUBLE_FAULT, gdt::IST_DOUBLE_FAULT);
+    set_handler_with_error_code(VECTOR_GENERAL_PROTECTION, general_protection);
+    set_handler_with_error_code(VECTOR_PAGE_FAULT, page_fault);
+    set_handler(apic::VECTOR_TIMER, timer);
+    set_handler(apic::VECTOR_RESCHEDULE, reschedule);
+    set_handler(apic::VECTOR_TLB_SHOOTDOWN, tlb_shootdown);
+    set_handler(apic::VECTOR_SPURIOUS, spurious);
+    load();
+}
+
+// End of every device interrupt and IPI handler, after its EOI. Softirqs raised by the handler run first,
+// then whatever switch they or the handler asked for
+fn irq_exit() {
+    softirq::run_pending();
+    sched::preempt_from_interrupt();
+}
+
+// Periodic tick of every CPU, which is where running threads get preempted
+extern "x86-interrupt" fn timer(frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::new(frame.cs);
+    timer::handle_tick();
+    apic::eoi();
+    irq_exit();
+}
+
+// Wakes up a halted idle thread, which looks at the run queue once the handler returns
+extern "x86-interrupt" fn reschedule(frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::new(frame.cs);
+    apic::eoi();
+    irq_exit();
+}
+
+extern "x86-interrupt" fn tlb_shootdown(frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::new(frame.cs);
+    tlb::handle_shootdown();
+    apic::eoi();
+    irq_exit();
+}
+
+// Raised when an interrupt goes away before being accepted, needs no EOI
+extern "x86-interrupt" fn spurious(_frame: InterruptStackFrame) { }
+
+fn from_user(frame: &InterruptStackFrame) -> bool {
+    frame.cs & 3 != 0
+}
+
+// End thread whose user code raised an exception. The handler never returns, so its thread leaves
+// interrupt context here, and keeps the kernel GS base it swapped in
+fn kill_user_thread(frame: &InterruptStackFrame, args: core::fmt::Arguments) -> ! {
+    console::write_fmt(format_args!("thread {} killed: {}, rip {:#x}\n", thread::current().unwrap_or(0), args, frame.rip));
+    percpu::replace_irq_depth(0);
+    thread::exit()
+}
+
+extern "x86-interrupt" fn invalid_opcode(frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::new(frame.cs);
+    match from_user(&frame) {
+        true => kill_user_thread(&frame, format_args!("invalid opcode")),
+        false => panic!("invalid opcode, rip {:#x}", frame.rip)
+    }
+}
+
+extern "x86-interrupt" fn general_protection(frame: InterruptStackFrame, error_code: u64) {
+    let _entry = InterruptEntry::new(frame.cs);
+    match from_user(&frame) {
+        true => kill_user_thread(&frame, format_args!("general protection fault {:#x}", error_code)),
+        false => panic!("general protection fault {:#x}, rip {:#x}", error_code, frame.rip)
+    }
+}
+
+// First FPU instruction after a context switch, or one outside kernel_fpu_begin in kernel code
+extern "x86-interrupt" fn device_not_available(frame: InterruptStackFrame) {
+    let _entry = InterruptEntry::new(frame.cs);
+    if !unsafe { fpu::handle_device_not_available() } {
+        panic!("FPU used without a context or kernel_fpu_begin, rip {:#x}", frame.rip);
+    }
+}
+
+// Overflowing a kernel stack faults again while pushing the page fault frame, which ends up here
+extern "x86-interrupt" fn double_fault(frame: InterruptStackFrame, _error_code: u64) {
+    let _entry = InterruptEntry::new(frame.cs);
+    let vaddr = unsafe { rcr2() };
+    match stack::guard_owner(vaddr) {
+        Some(owner) => panic!("kernel stack overflow in {}", owner),
+        None => panic!("double fault, rip {:#x}", frame.rip)
+    }
+}
+
+// Populate area or break copy-on-write sharing at faulting address, returns false if the fault is a real one
+unsafe fn resolve_fault(pager: &mut Pager, vaddr: usize, error_code: u64) -> bool {
+    let write = error_code & PF_WRITE != 0;
+    let user  = error_code & PF_USER != 0;
+    match error_code & PF_PRESENT {
+        // Areas are populated on first access
+        0 => pager.handle_area_fault(vaddr as *const (), write, user),
+        // Writes to present pages may hit copy-on-write mappings
+        _ => write && pager.handle_cow_fault(vaddr as *const ())
+    }
+}
+
+extern "x86-interrupt" fn page_fault(frame: InterruptStackFrame, error_code: u64) {
+    let _entry = InterruptEntry::new(frame.cs);
+    let vaddr = unsafe { rcr2() };
+
+    let resolved = unsafe {
+        // Kernel half areas live in the kernel page table, whose tables every address space shares
+        match address_space::current().and_then(address_space::get) {
+            Some(mut s) if vaddr < KERNEL_HALF_BASE => resolve_fault(s.pager(), vaddr, error_code),
+            _ => resolve_fault(&mut PAGE_TABLE.lock(), vaddr, error_code)
+        }
+    };
+    if resolved {
+        return
+    }
+
+    if from_user(&frame) {
+        kill_user_thread(&frame, format_args!("page fault at {:#x}", vaddr));
+    }
+
+    if let Some(owner) = stack::guard_owner(vaddr) {
+        panic!("kernel stack overflow in {}", owner);
+    }
+
+    panic!("page fault at {:#x} ({} {} in {} mode), rip {:#x}", vaddr,
+           match error_code & PF_WRITE {
+               0 => "read",
+               _ => "write"
+           },
+           match error_code & PF_PRESENT {
+               0 => "of unmapped page",
+               _ => "violating protection"
+           },
+           match error_code & PF_USER {
+               0 => "kernel",
+               _ => "user"
+           },
+           frame.rip);
+}
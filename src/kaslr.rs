@@ -0,0 +1,56 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::constants::*;
+use crate::memory;
+use crate::random;
+use crate::stack;
+
+// Regions slide in steps of 1 GiB so the direct map keeps its 1 GiB pages
+pub const SLIDE_ALIGN: usize = 1 << 30;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DIRECT_MAP_OFFSET: AtomicUsize = AtomicUsize::new(0);
+static HEAP_BASE: AtomicUsize = AtomicUsize::new(KERNEL_HEAP_BASE);
+static STACK_BASE: AtomicUsize = AtomicUsize::new(KERNEL_STACK_BASE);
+
+// Pick random aligned base in [lo, hi) leaving room for len bytes
+fn pick(lo: usize, hi: usize, len: usize) -> usize {
+    let len = (len + SLIDE_ALIGN - 1) & !(SLIDE_ALIGN - 1);
+    match hi - lo >= len {
+        false => lo,
+        true => lo + random::below(((hi - lo - len) / SLIDE_ALIGN + 1) as u64) as usize * SLIDE_ALIGN
+    }
+}
+
+// Choose bases of randomized kernel regions, or keep defaults and the bootloader's direct map if disabled
+pub fn init(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    DIRECT_MAP_OFFSET.store(memory::direct_map_offset(), Ordering::Relaxed);
+    if !enabled {
+        return
+    }
+
+    let ram_end = memory::regions().filter(|r| r.kind.is_ram()).map(|r| r.end()).max().unwrap_or(0);
+    DIRECT_MAP_OFFSET.store(pick(DIRECT_MAP_WINDOW_BASE, KERNEL_HEAP_BASE, ram_end), Ordering::Relaxed);
+    HEAP_BASE.store(pick(KERNEL_HEAP_BASE, KERNEL_STACK_BASE, VMEM_MAX), Ordering::Relaxed);
+    STACK_BASE.store(pick(KERNEL_STACK_BASE, KERNEL_STACK_END, stack::REGION_SIZE), Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// Offset the kernel page table maps physical memory at
+pub fn direct_map_offset() -> usize {
+    DIRECT_MAP_OFFSET.load(Ordering::Relaxed)
+}
+
+// Base of region searched for kernel heap pages
+pub fn heap_base() -> usize {
+    HEAP_BASE.load(Ordering::Relaxed)
+}
+
+// Base of kernel stack region
+pub fn stack_base() -> usize {
+    STACK_BASE.load(Ordering::Relaxed)
+}
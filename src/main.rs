@@ -4,34 +4,86 @@
 #![feature(exclusive_range_pattern)]
 #![feature(const_option)]
 #![feature(const_mut_refs)]
+#![feature(abi_x86_interrupt)]
+#![feature(asm_const)]
 
-use core::{panic::PanicInfo, sync::atomic::{AtomicPtr}};
+use core::{panic::PanicInfo, ptr::{addr_of, addr_of_mut}, sync::atomic::{AtomicPtr}};
 
 use limine::*;
 
 use constants::PAGE_SIZE;
 use pager::Pager;
+use frame_allocator::FRAME_ALLOCATOR;
+use stack::StackOwner;
+use sync::{Once, SpinLock};
 
 mod constants;
 mod pager;
 mod asm_wrappers;
-
-const COM1: u16 = 0x03F8;
+mod console;
+mod serial;
+mod debugcon;
+mod terminal;
+mod font;
+mod framebuffer;
+mod fbcon;
+mod cmdline;
+mod selftest;
+mod memory;
+mod frame_allocator;
+mod tlb;
+mod address_space;
+mod interrupts;
+mod vma;
+mod ptdump;
+mod shell;
+mod stack;
+mod gdt;
+mod cpu;
+mod random;
+mod kaslr;
+mod usercopy;
+mod fpu;
+mod apic;
+mod smp;
+mod percpu;
+mod sync;
+mod thread;
+mod sched;
+mod timer;
+mod wait;
+mod softirq;
+mod workqueue;
+mod syscall;
+mod user;
+mod elf;
+mod module;
 
 static INIT_STACK: [u8; 4096] = [0; 4096];
 
 extern "C" {
-    #[linkage = "external"] static __stack_start:  *const ();
-    #[linkage = "external"] static __stack_end:    *const ();
     #[linkage = "external"] static __kernel_start: *const ();
     #[linkage = "external"] static __kernel_end:   *const ();
+    #[linkage = "external"] static __text_start:   *const ();
+    #[linkage = "external"] static __rodata_start: *const ();
+    #[linkage = "external"] static __data_start:   *const ();
+}
+
+// Get address of linker-provided symbol (the symbol itself holds no meaningful value)
+fn symbol_addr(s: &*const ()) -> usize {
+    s as *const *const () as usize
 }
 
-static mut KERNEL_SIZE: Option<usize> = None;
-static mut KERNEL_BEGIN_VIRT: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
-static mut KERNEL_BEGIN_PHYS: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
-static mut PAGE_TABLE: Pager = Pager::new();
-static mut LIMINE_TERMINAL_RESPONSE: Option<&LimineTerminalResponse> = None;
+// Where the bootloader put the kernel image
+#[derive(Clone, Copy)]
+struct KernelImage {
+    virt_base: usize,
+    phys_base: usize,
+    size: usize
+}
+
+static KERNEL_IMAGE: Once<KernelImage> = Once::new();
+pub static PAGE_TABLE: SpinLock<Pager> = SpinLock::new(Pager::new());
 
 static mut LIMINE_TERMINAL_REQUEST:         LimineTerminalRequest       = LimineTerminalRequest::new(0);
 static mut LIMINE_RSDP_REQUEST:             LimineRsdpRequest           = LimineRsdpRequest::new(0);
@@ -40,120 +92,363 @@ static mut LIMINE_EFI_SYSTEM_TABLE_REQUEST: LimineEfiSystemTableRequest = Limine
 static mut LIMINE_BOOT_TIME_REQUEST:        LimineBootTimeRequest       = LimineBootTimeRequest::new(0);
 static mut LIMINE_KERNEL_ADDRESS_REQUEST:   LimineKernelAddressRequest  = LimineKernelAddressRequest::new(0);
 static mut LIMINE_STACK_SIZE_REQUEST:       LimineStackSizeRequest      = LimineStackSizeRequest::new(0).stack_size(16 * 1024 * 1024);
+static mut LIMINE_FRAMEBUFFER_REQUEST:      LimineFramebufferRequest    = LimineFramebufferRequest::new(0);
+static mut LIMINE_HHDM_REQUEST:             LimineHhdmRequest           = LimineHhdmRequest::new(0);
+static mut LIMINE_KERNEL_FILE_REQUEST:      LimineKernelFileRequest     = LimineKernelFileRequest::new(0);
+static mut LIMINE_MMAP_REQUEST:             LimineMmapRequest           = LimineMmapRequest::new(0);
+static mut LIMINE_MODULE_REQUEST:           LimineModuleRequest         = LimineModuleRequest::new(0);
+// Ask for x2APIC mode, which needs no MMIO mapping
+static mut LIMINE_SMP_REQUEST:              LimineSmpRequest            = LimineSmpRequest::new(0).flags(1);
 
 #[link_section = ".limine_reqs"]
 #[no_mangle]
 #[used]
-static LIMINE_REQUESTS_ARRAY: [AtomicPtr<()>; 8] = [
-    unsafe { AtomicPtr::new(&mut LIMINE_TERMINAL_REQUEST         as *mut LimineTerminalRequest       as *mut ()) },
-    unsafe { AtomicPtr::new(&mut LIMINE_RSDP_REQUEST             as *mut LimineRsdpRequest           as *mut ()) },
-    unsafe { AtomicPtr::new(&mut LIMINE_SMBIOS_REQUEST           as *mut LimineSmbiosRequest         as *mut ()) },
-    unsafe { AtomicPtr::new(&mut LIMINE_EFI_SYSTEM_TABLE_REQUEST as *mut LimineEfiSystemTableRequest as *mut ()) },
-    unsafe { AtomicPtr::new(&mut LIMINE_BOOT_TIME_REQUEST        as *mut LimineBootTimeRequest       as *mut ()) },
-    unsafe { AtomicPtr::new(&mut LIMINE_KERNEL_ADDRESS_REQUEST   as *mut LimineKernelAddressRequest  as *mut ()) },
-    unsafe { AtomicPtr::new(&mut LIMINE_STACK_SIZE_REQUEST       as *mut LimineStackSizeRequest      as *mut ()) },
-             AtomicPtr::new(core::ptr::null_mut()                                                    as *mut ())    
-                
+static LIMINE_REQUESTS_ARRAY: [AtomicPtr<()>; 14] = [
+    AtomicPtr::new(addr_of_mut!(LIMINE_TERMINAL_REQUEST)           as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_RSDP_REQUEST)               as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_SMBIOS_REQUEST)             as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_EFI_SYSTEM_TABLE_REQUEST)   as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_BOOT_TIME_REQUEST)          as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_KERNEL_ADDRESS_REQUEST)     as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_STACK_SIZE_REQUEST)         as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_FRAMEBUFFER_REQUEST)        as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_HHDM_REQUEST)               as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_KERNEL_FILE_REQUEST)        as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_MMAP_REQUEST)               as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_MODULE_REQUEST)             as *mut ()),
+    AtomicPtr::new(addr_of_mut!(LIMINE_SMP_REQUEST)                as *mut ()),
+    AtomicPtr::new(core::ptr::null_mut()                           as *mut ())
 ];
     
-unsafe fn write_com1(c: u8) {
-    asm_wrappers::outb(COM1, c);
+fn printstr(s: &str) {
+    console::write_str(s);
 }
 
-fn printstr_serial(s: &str) {
-    for &c in s.as_bytes().iter() {
-        unsafe {
-            write_com1(c);
-        }
-    }
+fn log(s: &str) {
+    log_at(console::LogLevel::Info, s);
 }
 
-fn printstr_terminal(s: &str) {
-    unsafe {
-        match LIMINE_TERMINAL_RESPONSE {
-            None => { },
-            Some(r) => match r.terminal_count {
-                0 => { },
-                _ => {
-                    match r.write() {
-                        None => {  },
-                        Some(w) => match r.terminals() {
-                            None => {  },
-                            Some(a) => match a.first() {
-                                None => {  },
-                                Some(t) => w(t, s)
-                            }
-                        }
-                    }
-                }
-            }
-        };
+fn log_fmt(args: core::fmt::Arguments) {
+    if console::is_enabled(console::LogLevel::Info) {
+        console::write_fmt(args);
+        printstr("\n");
     }
 }
 
-fn printstr(s: &str) {
-    printstr_terminal(s);
-    printstr_serial(s);
+fn log_at(level: console::LogLevel, s: &str) {
+    if console::is_enabled(level) {
+        printstr(s);
+        printstr("\n");
+    }
 }
 
-fn log(s: &str) {
-    printstr(s);
-    printstr("\n");
+fn log_fmt_at(level: console::LogLevel, args: core::fmt::Arguments) {
+    if console::is_enabled(level) {
+        console::write_fmt(args);
+        printstr("\n");
+    }
 }
 
 fn done() -> ! {
-    loop{}
+    loop {
+        unsafe {
+            asm_wrappers::hlt();
+        }
+    }
 }
 
 #[no_mangle]
 extern "C" fn entry() {
     init();
-    done()
+
+    // Leave the bootloader's stack, which has nothing below it to catch an overflow
+    match stack::allocate(StackOwner::Cpu(0)) {
+        None => panic("Failed to allocate boot CPU stack."),
+        Some(s) => unsafe { asm_wrappers::switch_stack(s.top(), kernel_main) }
+    }
 }
 
-fn init() {
-    unsafe { 
-        LIMINE_TERMINAL_RESPONSE = match LIMINE_TERMINAL_REQUEST.get_response().get() {
-            Some(p) => Some(p),
-            None => panic("Failed to acquire limine terminal request response."),
-        };
+// Map kernel image section by section so code isn't writable and data isn't executable
+unsafe fn map_kernel(image: &KernelImage) -> bool {
+    let mut pt    = PAGE_TABLE.lock();
+    let virt_base = image.virt_base;
+    let phys_base = image.phys_base;
+    let nx        = pt.nx_flag();
+    let rw        = pager::PTE_PRESENT | pager::PTE_WRITABLE | pager::PTE_GLOBAL;
+
+    // Sections are page aligned and contiguous, limine requests come first and are written by the bootloader
+    let sections: [(usize, usize, u64); 4] = [
+        (symbol_addr(&__kernel_start), symbol_addr(&__text_start),   rw | nx),
+        (symbol_addr(&__text_start),   symbol_addr(&__rodata_start), pager::PTE_PRESENT | pager::PTE_GLOBAL),
+        (symbol_addr(&__rodata_start), symbol_addr(&__data_start),   pager::PTE_PRESENT | pager::PTE_GLOBAL | nx),
+        (symbol_addr(&__data_start),   symbol_addr(&__kernel_end),   rw | nx)
+    ];
 
-        if __stack_end as usize - __stack_start as usize <= 4096 {
-            panic("Stack is too small!");
-        } else {
-            log("Stack size is valid.");
+    for (start, end, flags) in sections {
+        if end > start && pt.map_range((start - virt_base + phys_base) as *const (),
+                                       start as *const (), end - start, flags).is_none() {
+            return false
         }
+    }
 
-        PAGE_TABLE.init();
-        log("Page table successfully initialized.");
+    // Direct map alias of kernel code mustn't allow writing it either, this splits the huge pages covering it
+    let text_start = symbol_addr(&__text_start);
+    let text_alias = (kaslr::direct_map_offset() + text_start - virt_base + phys_base) as *const ();
+    pt.protect_range(text_alias, symbol_addr(&__rodata_start) - text_start,
+                     pager::PTE_PRESENT | pager::PTE_GLOBAL | nx).is_some()
+}
 
-        KERNEL_BEGIN_VIRT = match LIMINE_KERNEL_ADDRESS_REQUEST.get_response().get() {
-            None => panic("Failed to acquire limine kernel base address response."),
-            Some(r) => match r.virtual_base {
-                0 => panic("Limine kernel base address response virtual address is null."),
-                _ => AtomicPtr::<()>::new(r.virtual_base as *mut ())
+// Keep bootloader memory at the bootloader's direct map, which is where its stack and responses are found
+unsafe fn map_bootloader_memory() -> bool {
+    let offset = memory::boot_direct_map_offset();
+    if offset == kaslr::direct_map_offset() {
+        return true
+    }
+
+    let mut pt = PAGE_TABLE.lock();
+    memory::regions()
+        .filter(|r| r.kind == memory::MemoryKind::BootloaderReclaimable)
+        .all(|r| pt.map_direct(r.base & !0xFFF, (r.end() + PAGE_SIZE - 1) & !0xFFF, offset))
+}
+
+// Register sinks that need nothing from the bootloader so output works from the very start
+fn init_early_console() {
+    unsafe {
+        if (*addr_of_mut!(serial::COM1)).init() {
+            console::register(&mut *addr_of_mut!(serial::COM1));
+        }
+
+        if (*addr_of!(debugcon::DEBUGCON)).is_present() {
+            console::register(&mut *addr_of_mut!(debugcon::DEBUGCON));
+        }
+    }
+}
+
+// Register console sink unless it was disabled on the command line
+fn register_console(sink: &'static mut dyn console::ConsoleSink) -> bool {
+    match cmdline::options().console_enabled(sink.name()) {
+        false => false,
+        true => console::register(sink)
+    }
+}
+
+// Fetch command line from kernel file and apply console settings
+fn init_cmdline() {
+    let s = unsafe {
+        match (*addr_of!(LIMINE_KERNEL_FILE_REQUEST)).get_response().get() {
+            None => None,
+            Some(r) => match r.kernel_file.get() {
+                None => None,
+                Some(f) => f.cmdline.to_string()
+            }
+        }
+    };
+
+    match s {
+        None => log("No kernel command line provided, using defaults."),
+        Some(s) => cmdline::init(s)
+    };
+
+    let options = cmdline::options();
+    console::set_log_level(options.log_level);
+
+    // Early sinks were registered before the command line was known
+    for name in ["serial", "debugcon"] {
+        if !options.console_enabled(name) {
+            console::unregister(name);
+        }
+    }
+}
+
+// Replace Limine terminal with text console on first framebuffer
+fn init_framebuffer_console() {
+    let fb = match framebuffer::get(0) {
+        None => return,
+        Some(f) => f
+    };
+
+    unsafe {
+        if (*addr_of_mut!(fbcon::FBCON)).attach(fb, font::Font::default()) && register_console(&mut *addr_of_mut!(fbcon::FBCON)) {
+            log("Framebuffer console initialized.");
+        }
+    }
+}
+
+fn init() {
+    // Everything asking for the current CPU reads it from here
+    unsafe {
+        percpu::init(0);
+        percpu::load(0);
+    }
+
+    init_early_console();
+    log("Early console initialized.");
+    init_cmdline();
+    log("Kernel command line parsed.");
+
+    unsafe { 
+        match (*addr_of!(LIMINE_TERMINAL_REQUEST)).get_response().get() {
+            None => log("No limine terminal available, continuing without it."),
+            Some(r) => match (*addr_of_mut!(terminal::LIMINE_TERMINAL)).attach(r) && register_console(&mut *addr_of_mut!(terminal::LIMINE_TERMINAL)) {
+                true => log("Limine terminal attached to console."),
+                false => log("Limine terminal unusable, continuing without it.")
             }
         };
-        log("Acquired kernel base virtual address.");
 
-        KERNEL_BEGIN_PHYS = match LIMINE_KERNEL_ADDRESS_REQUEST.get_response().get() {
+        let image = match (*addr_of!(LIMINE_KERNEL_ADDRESS_REQUEST)).get_response().get() {
             None => panic("Failed to acquire limine kernel base address response."),
-            Some(r) => match r.physical_base {
-                0 => panic("Limine kernel base address response physical address is null."),
-                _ => AtomicPtr::<()>::new(r.physical_base as *mut ())
+            Some(r) => match (r.virtual_base, r.physical_base) {
+                (0, _) => panic("Limine kernel base address response virtual address is null."),
+                (_, 0) => panic("Limine kernel base address response physical address is null."),
+                (virt, phys) => KernelImage {
+                    virt_base: virt as usize,
+                    phys_base: phys as usize,
+                    size: symbol_addr(&__kernel_end) - symbol_addr(&__kernel_start)
+                }
             }
         };
-        log("Acquired kernel base physical address.");
+        KERNEL_IMAGE.set(image);
+        log("Acquired kernel base virtual and physical addresses.");
+
+        let hhdm_offset = match (*addr_of!(LIMINE_HHDM_REQUEST)).get_response().get() {
+            None => panic("Failed to acquire limine HHDM response."),
+            Some(r) => r.offset as usize
+        };
+        let memmap = match (*addr_of!(LIMINE_MMAP_REQUEST)).get_response().get() {
+            None => panic("Failed to acquire limine memory map response."),
+            Some(r) => match r.mmap() {
+                None => panic("Limine memory map response has no entries."),
+                Some(m) => m
+            }
+        };
+        memory::init(hhdm_offset, memmap, image.virt_base, image.phys_base, image.size);
+        log("Memory map and direct map offset recorded.");
+
+        // Module paths are only reachable through the bootloader's page tables
+        match (*addr_of!(LIMINE_MODULE_REQUEST)).get_response().get() {
+            None => log("No boot modules."),
+            Some(r) => log_fmt(format_args!("{} boot modules recorded.", module::init(r)))
+        }
+
+        cpu::init();
+        if console::is_enabled(console::LogLevel::Info) {
+            cpu::print_summary();
+        }
+
+        tlb::init();
+        log_fmt(format_args!("TLB initialized, PCID {}.", match tlb::pcid_enabled() {
+            true => "enabled",
+            false => "unavailable"
+        }));
+
+        FRAME_ALLOCATOR.lock().init(cmdline::options().mem_limit);
+        log("Frame allocator successfully initialized.");
+
+        random::init();
+        kaslr::init(cmdline::options().kaslr);
+        log_fmt(format_args!("Entropy from {}, address space randomization {}.", random::source(), match kaslr::enabled() {
+            true => "enabled",
+            false => "disabled"
+        }));
+        // Offsets would defeat the randomization if they ended up in ordinary logs
+        log_fmt_at(console::LogLevel::Debug, format_args!("Kernel at {:#x}, direct map at {:#x}, heap at {:#x}, stacks at {:#x}.",
+                                                           image.virt_base, kaslr::direct_map_offset(),
+                                                           kaslr::heap_base(), kaslr::stack_base()));
+
+        if !PAGE_TABLE.lock().init(kaslr::direct_map_offset()) {
+            panic("Failed to build direct map in new page table.");
+        }
+        log("Page table successfully initialized.");
 
-        KERNEL_SIZE          = Some(__kernel_end as usize - __kernel_start as usize);
-        let num_pages: usize = KERNEL_SIZE.unwrap() / constants::PAGE_SIZE; 
-        PAGE_TABLE.allocate_physically_contiguous_pages(Some(*KERNEL_BEGIN_PHYS.get_mut()), 
-                                                        Some(*KERNEL_BEGIN_VIRT.get_mut()), 
-                                                        num_pages);
+        if !map_kernel(&image) {
+            panic("Failed to map kernel to new page table.");
+        }
         log("Kernel successfully mapped to new page table.");
-        PAGE_TABLE.activate();
+
+        if !map_bootloader_memory() {
+            panic("Failed to map bootloader memory to new page table.");
+        }
+
+        let stats = PAGE_TABLE.lock().page_stats();
+        log_fmt(format_args!("Page table uses {} 4 KiB, {} 2 MiB and {} 1 GiB pages.",
+                             stats.pages_4k, stats.pages_2m, stats.pages_1g));
+
+        let framebuffers_mapped = match (*addr_of!(LIMINE_FRAMEBUFFER_REQUEST)).get_response().get() {
+            None => false,
+            Some(r) => framebuffer::init(r) != 0 && framebuffer::map_all(&mut PAGE_TABLE.lock())
+        };
+
+        // Limine terminal may only be used while the bootloader's page tables are loaded
+        console::unregister("limine-terminal");
+        PAGE_TABLE.lock().activate();
+        memory::set_direct_map_offset(kaslr::direct_map_offset());
+        // Global translations left by the bootloader survive CR3 loads
+        tlb::flush_all_global();
         log("New page table successfully loaded.");
+
+        let protections = cpu::enable_protections();
+        log_fmt(format_args!("User page protections: SMEP {}, SMAP {}, UMIP {}.",
+                             protections.contains(asm_wrappers::Cr4::SMEP),
+                             protections.contains(asm_wrappers::Cr4::SMAP),
+                             protections.contains(asm_wrappers::Cr4::UMIP)));
+
+        if !gdt::init(0) {
+            panic("Failed to allocate exception stack for boot CPU.");
+        }
+        interrupts::init();
+        syscall::init_cpu();
+        log("GDT, TSS and IDT loaded.");
+
+        match fpu::init() {
+            false => log("No SSE2, FPU stays disabled."),
+            true => log_fmt(format_args!("FPU enabled with {}{}, {} bytes of state per context.", match fpu::uses_xsave() {
+                true => "XSAVE",
+                false => "FXSAVE"
+            }, match fpu::avx_enabled() {
+                true => " and AVX",
+                false => ""
+            }, fpu::state_size()))
+        }
+
+        match framebuffers_mapped {
+            true => init_framebuffer_console(),
+            false => log("No usable framebuffer, continuing without framebuffer console.")
+        };
+
+        let online = smp::init((*addr_of!(LIMINE_SMP_REQUEST)).get_response().as_mut_ptr().map(|r| &mut *r), !cmdline::options().nosmp);
+        log_fmt(format_args!("{} of {} CPUs online, {} APIC.", online, smp::cpu_count(), match apic::is_x2apic() {
+            true => "x2APIC",
+            false => "xAPIC"
+        }));
+        match timer::tsc_per_ms() {
+            0 => log_fmt_at(console::LogLevel::Warn, format_args!("Timer calibration failed, threads won't be preempted.")),
+            tsc => log_fmt(format_args!("Timer ticking at {} Hz, TSC runs at {} MHz.", timer::TICK_HZ, tsc / 1000))
+        }
+    }
+}
+
+// Continue boot on a kernel stack with guard pages
+extern "C" fn kernel_main() -> ! {
+    // From here on the boot CPU runs threads, with this one as the first
+    if !thread::init() {
+        panic("Failed to create boot thread.");
+    }
+    sched::start();
+    unsafe {
+        asm_wrappers::sti();
     }
+    if !workqueue::init() {
+        log_fmt_at(console::LogLevel::Warn, format_args!("Failed to start work queue threads."));
+    }
+
+    if cmdline::options().selftest {
+        selftest::run_all();
+    }
+
+    if cmdline::options().debug_shell {
+        shell::run();
+    }
+    done()
 }
 
 fn panic(s: &str) -> ! {
@@ -164,7 +459,7 @@ fn panic(s: &str) -> ! {
 }
 
 #[panic_handler]
-fn panic_handler(_info: &PanicInfo) -> ! {
-    printstr("Panicking!");
+fn panic_handler(info: &PanicInfo) -> ! {
+    console::write_fmt_panic(format_args!("Panicking! {}\n", info));
     done()
 }
@@ -0,0 +1,134 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use limine::{LimineMemmapEntry, LimineMemoryMapEntryType};
+
+use crate::constants::*;
+use crate::sync::Once;
+
+pub const MAX_MEMORY_REGIONS: usize = 128;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    Usable,
+    Reserved,
+    AcpiReclaimable,
+    AcpiNvs,
+    BadMemory,
+    BootloaderReclaimable,
+    KernelAndModules,
+    Framebuffer
+}
+
+impl MemoryKind {
+    // Check if region is RAM that belongs in the direct map
+    pub const fn is_ram(&self) -> bool {
+        matches!(self, MemoryKind::Usable | MemoryKind::AcpiReclaimable | MemoryKind::AcpiNvs
+                       | MemoryKind::BootloaderReclaimable | MemoryKind::KernelAndModules)
+    }
+}
+
+// Physical memory region copied out of the bootloader memory map
+#[derive(Clone, Copy)]
+pub struct MemoryRegion {
+    pub base: usize,
+    pub len: usize,
+    pub kind: MemoryKind
+}
+
+impl MemoryRegion {
+    pub const fn end(&self) -> usize {
+        self.base + self.len
+    }
+}
+
+const NO_REGION: Option<MemoryRegion> = None;
+
+// Where the kernel image was loaded, for translating its addresses
+#[derive(Clone, Copy)]
+struct KernelLocation {
+    virt_base: usize,
+    phys_base: usize,
+    size: usize
+}
+
+static REGIONS: Once<[Option<MemoryRegion>; MAX_MEMORY_REGIONS]> = Once::new();
+static KERNEL: Once<KernelLocation> = Once::new();
+static DIRECT_MAP_OFFSET: AtomicUsize = AtomicUsize::new(0);
+static BOOT_DIRECT_MAP_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+// Record direct map offset, kernel image location and a copy of the memory map
+pub fn init(hhdm_offset: usize, memmap: &[LimineMemmapEntry], kernel_virt: usize, kernel_phys: usize, kernel_size: usize) {
+    DIRECT_MAP_OFFSET.store(hhdm_offset, Ordering::Relaxed);
+    BOOT_DIRECT_MAP_OFFSET.store(hhdm_offset, Ordering::Relaxed);
+    KERNEL.set(KernelLocation { virt_base: kernel_virt, phys_base: kernel_phys, size: kernel_size });
+
+    let mut regions = [NO_REGION; MAX_MEMORY_REGIONS];
+    for (i, e) in memmap.iter().take(MAX_MEMORY_REGIONS).enumerate() {
+        regions[i] = Some(MemoryRegion {
+            base: e.base as usize,
+            len:  e.len  as usize,
+            kind: match e.typ {
+                LimineMemoryMapEntryType::Usable                => MemoryKind::Usable,
+                LimineMemoryMapEntryType::Reserved              => MemoryKind::Reserved,
+                LimineMemoryMapEntryType::AcpiReclaimable       => MemoryKind::AcpiReclaimable,
+                LimineMemoryMapEntryType::AcpiNvs               => MemoryKind::AcpiNvs,
+                LimineMemoryMapEntryType::BadMemory             => MemoryKind::BadMemory,
+                LimineMemoryMapEntryType::BootloaderReclaimable => MemoryKind::BootloaderReclaimable,
+                LimineMemoryMapEntryType::KernelAndModules      => MemoryKind::KernelAndModules,
+                LimineMemoryMapEntryType::Framebuffer           => MemoryKind::Framebuffer
+            }
+        });
+    }
+    REGIONS.set(regions);
+}
+
+// Iterate recorded memory regions
+pub fn regions() -> impl Iterator<Item = &'static MemoryRegion> {
+    REGIONS.get().into_iter().flat_map(|r| r.iter().filter_map(|r| r.as_ref()))
+}
+
+// Check if physical address lies within RAM
+pub fn is_ram(paddr: usize) -> bool {
+    regions().any(|r| r.kind.is_ram() && paddr >= r.base && paddr < r.end())
+}
+
+// Get kind of memory map region containing physical address
+pub fn region_kind(paddr: usize) -> Option<MemoryKind> {
+    regions().find(|r| paddr >= r.base && paddr < r.end()).map(|r| r.kind)
+}
+
+// Base of higher-half direct map of physical memory
+pub fn direct_map_offset() -> usize {
+    DIRECT_MAP_OFFSET.load(Ordering::Relaxed)
+}
+
+// Direct map set up by the bootloader, through which its own memory and responses are reached
+pub fn boot_direct_map_offset() -> usize {
+    BOOT_DIRECT_MAP_OFFSET.load(Ordering::Relaxed)
+}
+
+// Switch to direct map of newly loaded page table
+pub fn set_direct_map_offset(offset: usize) {
+    DIRECT_MAP_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+// Get direct map virtual address of physical address
+pub fn phys_to_virt(paddr: *const ()) -> *const () {
+    (paddr as usize + direct_map_offset()) as *const ()
+}
+
+// Get physical address of direct map or kernel image virtual address
+pub fn virt_to_phys(vaddr: *const ()) -> Option<*const ()> {
+    let v = vaddr as usize;
+    if let Some(k) = KERNEL.get() {
+        if v >= k.virt_base && v < k.virt_base + k.size {
+            return Some((v - k.virt_base + k.phys_base) as *const ())
+        }
+    }
+
+    let offset = direct_map_offset();
+    match v >= offset && v < offset + PMEM_MAX {
+        true => Some((v - offset) as *const ()),
+        false => None
+    }
+}
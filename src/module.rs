@@ -0,0 +1,107 @@
+use limine::LimineModuleResponse;
+
+use crate::console;
+use crate::memory;
+use crate::sync::Once;
+
+pub const MAX_MODULES: usize = 16;
+// Longer paths are cut, which only matters for looking modules up by them
+const MAX_PATH: usize = 64;
+
+// File the bootloader loaded next to the kernel, such as a user program. Its memory is part of the kernel
+// and modules region, which stays in the direct map
+#[derive(Clone, Copy)]
+pub struct BootModule {
+    path: [u8; MAX_PATH],
+    path_len: usize,
+    phys_base: usize,
+    len: usize
+}
+
+impl BootModule {
+    // Path within the boot volume, with a leading slash
+    pub fn path(&self) -> &str {
+        core::str::from_utf8(&self.path[..self.path_len]).unwrap_or("")
+    }
+
+    // Last component of path
+    pub fn name(&self) -> &str {
+        let path = self.path();
+        match path.rfind('/') {
+            None => path,
+            Some(i) => &path[i + 1..]
+        }
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    // Get contents through the direct map in use
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(memory::phys_to_virt(self.phys_base as *const ()) as *const u8, self.len) }
+    }
+}
+
+const NO_MODULE: Option<BootModule> = None;
+
+static MODULES: Once<[Option<BootModule>; MAX_MODULES]> = Once::new();
+
+// Record modules of bootloader response, needs the bootloader's direct map still loaded to read paths.
+// Returns how many were recorded
+pub fn init(response: &LimineModuleResponse) -> usize {
+    let files = match response.modules.as_ptr() {
+        None => return 0,
+        Some(f) => f
+    };
+
+    let mut modules = [NO_MODULE; MAX_MODULES];
+    let mut count = 0;
+    for i in 0..core::cmp::min(response.module_count as usize, MAX_MODULES) {
+        let file = match unsafe { (*files.add(i)).get() } {
+            None => continue,
+            Some(f) => f
+        };
+        let base = match file.base.as_ptr() {
+            None => continue,
+            Some(b) => b as usize
+        };
+
+        // Addresses are in the bootloader's direct map, which the kernel may move
+        let mut module = BootModule {
+            path: [0; MAX_PATH],
+            path_len: 0,
+            phys_base: base - memory::boot_direct_map_offset(),
+            len: file.length as usize
+        };
+        let path = file.path.to_string().unwrap_or("");
+        let mut path_len = core::cmp::min(path.len(), MAX_PATH);
+        while !path.is_char_boundary(path_len) {
+            path_len -= 1;
+        }
+        module.path[..path_len].copy_from_slice(&path.as_bytes()[..path_len]);
+        module.path_len = path_len;
+
+        modules[count] = Some(module);
+        count += 1;
+    }
+    MODULES.set(modules);
+    count
+}
+
+// Iterate recorded modules
+pub fn modules() -> impl Iterator<Item = &'static BootModule> {
+    MODULES.get().into_iter().flat_map(|m| m.iter().filter_map(|m| m.as_ref()))
+}
+
+// Find module by its path or by the last component of it
+pub fn find(name: &str) -> Option<&'static BootModule> {
+    modules().find(|m| m.path() == name || m.name() == name)
+}
+
+pub fn print_modules() {
+    console::write_fmt(format_args!("size       path\n"));
+    for m in modules() {
+        console::write_fmt(format_args!("{:<10} {}\n", m.len(), m.path()));
+    }
+}
@@ -1,222 +1,695 @@
-use core::mem::size_of;
-
 use crate::constants::*;
 use crate::asm_wrappers::lcr3;
+use crate::cpu::{self, Feature};
+use crate::frame_allocator::FRAME_ALLOCATOR;
+use crate::kaslr;
+use crate::memory::{self, phys_to_virt};
+use crate::tlb;
+use crate::vma::{self, Backing, Vma, VmaTable};
+
+// Page table entry flags
+pub const PTE_PRESENT:       u64 = 1 << 0;
+pub const PTE_WRITABLE:      u64 = 1 << 1;
+pub const PTE_USER:          u64 = 1 << 2;
+pub const PTE_WRITE_THROUGH: u64 = 1 << 3;
+pub const PTE_CACHE_DISABLE: u64 = 1 << 4;
+pub const PTE_ACCESSED:      u64 = 1 << 5;
+pub const PTE_DIRTY:         u64 = 1 << 6;
+pub const PTE_HUGE:          u64 = 1 << 7;
+pub const PTE_GLOBAL:        u64 = 1 << 8;
+// Available to software: frame was allocated by the pager and goes back to the frame allocator on unmap
+pub const PTE_OWNED:         u64 = 1 << 9;
+// Available to software: page is writable but its frame is shared, so the first write copies it
+pub const PTE_COW:           u64 = 1 << 10;
+pub const PTE_NO_EXECUTE:    u64 = 1 << 63;
+
+pub const PTE_ADDR_MASK:     u64 = 0x000F_FFFF_FFFF_F000;
+pub const PTE_DEFAULT_FLAGS: u64 = PTE_PRESENT | PTE_WRITABLE;
+
+// Flags given to intermediate tables, leaf entries restrict access further
+const TABLE_FLAGS: u64 = PTE_PRESENT | PTE_WRITABLE;
+
+// Get table index of virtual address at provided level (4 = PML4T .. 1 = PT)
+const fn table_index(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (12 + 9 * (level - 1))) & 0x1FF
+}
 
-const NUM_PML4TES: usize = match VMEM_MAX / (512 * 1024 * 1024 * 1024) {
-    0 => 1,
-    1..511 => VMEM_MAX / (512 * 1024 * 1024 * 1024),
-    _ => 512
-};
-
-const NUM_PDPTES: usize = match VMEM_MAX / (1 * 1024 * 1024 * 1024) {
-    0 => 1,
-    1..511 => VMEM_MAX / (1 * 1024 * 1024 * 1024),
-    _ => 512
-};
-
-const NUM_PDTES: usize = match VMEM_MAX / (2 * 1024 * 1024) {
-    0 => 1,
-    1..511 => VMEM_MAX / (2 * 1024 * 1024),
-    _ => 512
-};
-
-const NUM_PHYS_ADDR_MAP_ENTRIES: usize = MAX_PAGES / 64;
-
-#[repr(align(4096), C)]
-struct aligned_array<T, const N: usize> {
-    pub arr: [T; N]
+// Get direct map pointer to table stored in physical frame
+fn table_at(paddr: u64) -> *mut [u64; 512] {
+    phys_to_virt((paddr & PTE_ADDR_MASK) as *const ()) as *mut [u64; 512]
 }
 
-#[repr(align(4096), C)]
-pub struct Pager {
-    //ptes: aligned_array<aligned_array<aligned_array<aligned_array<u64, 512>, NUM_PDTES>, NUM_PDPTES, NUM_PML4TES>,
-    ptes: [[[[u64; 512]; NUM_PDTES  ]; NUM_PDPTES ]; NUM_PML4TES],
-    pdtes: [[[u64; 512]; NUM_PDPTES ]; NUM_PML4TES],
-    pdptes: [[u64; 512]; NUM_PML4TES],
-    pml4tes: [u64; 512],
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G
+}
 
-    allocated_pages: [Option<*const ()>; MAX_PAGES],
-    phys_addr_map:   [u64; NUM_PHYS_ADDR_MAP_ENTRIES],
+impl PageSize {
+    pub const fn bytes(&self) -> usize {
+        match self {
+            PageSize::Size4K => 4 * 1024,
+            PageSize::Size2M => 2 * 1024 * 1024,
+            PageSize::Size1G => 1024 * 1024 * 1024
+        }
+    }
 
-    last_mapped_phys_addr: Option<*const ()>,
-    last_mapped_virt_addr: Option<*const ()>
+    // Level of the table whose entries map pages of this size
+    const fn level(&self) -> usize {
+        match self {
+            PageSize::Size4K => 1,
+            PageSize::Size2M => 2,
+            PageSize::Size1G => 3
+        }
+    }
+
+    // Size of the pages a huge page is split into
+    const fn smaller(&self) -> PageSize {
+        match self {
+            PageSize::Size1G => PageSize::Size2M,
+            _ => PageSize::Size4K
+        }
+    }
 }
 
-fn memset<T>(t: &mut T, c: u8) {
-    let ptr = t as *mut T as *mut u8;
-    for i in 0..size_of::<T>() {
-        unsafe {
-            *((ptr as usize + i) as *mut u8) = c;
+// Number of mapped pages of each size
+#[derive(Clone, Copy)]
+pub struct PageStats {
+    pub pages_4k: usize,
+    pub pages_2m: usize,
+    pub pages_1g: usize
+}
+
+impl PageStats {
+    const fn new() -> Self {
+        PageStats {
+            pages_4k: 0,
+            pages_2m: 0,
+            pages_1g: 0
         }
     }
+
+    fn count(&mut self, size: PageSize) -> &mut usize {
+        match size {
+            PageSize::Size4K => &mut self.pages_4k,
+            PageSize::Size2M => &mut self.pages_2m,
+            PageSize::Size1G => &mut self.pages_1g
+        }
+    }
+}
+
+// Physical address of page mapped by leaf entry
+pub const fn entry_frame(e: u64, size: PageSize) -> u64 {
+    e & PTE_ADDR_MASK & !(size.bytes() as u64 - 1)
+}
+
+// Allocate zeroed frame for a page table
+unsafe fn allocate_table() -> Option<u64> {
+    let p = FRAME_ALLOCATOR.lock().allocate_frame()?;
+    core::ptr::write_bytes(phys_to_virt(p) as *mut u8, 0, PAGE_SIZE);
+    Some(p as u64)
 }
 
+pub struct Pager {
+    pml4t_phys: Option<*const ()>,
+
+    last_mapped_phys_addr: Option<*const ()>,
+    last_mapped_virt_addr: Option<*const ()>,
+
+    supports_1g_pages: bool,
+    supports_nx: bool,
+    stats: PageStats,
+
+    // Process-context identifier tagging this address space's TLB entries
+    pcid: u16,
+    // CPUs that loaded this page table and may still cache its translations
+    active_cpus: u64,
+
+    vmas: VmaTable
+}
+
+// Pointers held are plain addresses of tables and pages, which belong to the pager and not to a CPU
+unsafe impl Send for Pager { }
+
 impl Pager {
-    // Return new Pager with fields all zeroed
+    // Return new Pager without any tables, init() must be called before use
     pub const fn new() -> Self {
         Pager {
-            ptes: [[[[0; 512]; NUM_PDTES  ]; NUM_PDPTES ]; NUM_PML4TES],
-            pdtes: [[[0; 512]; NUM_PDPTES ]; NUM_PML4TES],
-            pdptes: [[0; 512]; NUM_PML4TES],
-            pml4tes: [0; 512],
-
-            allocated_pages: [None; MAX_PAGES],
-            phys_addr_map:   [0;    NUM_PHYS_ADDR_MAP_ENTRIES],
+            pml4t_phys: None,
 
             last_mapped_phys_addr: None,
-            last_mapped_virt_addr: None
+            last_mapped_virt_addr: None,
+
+            supports_1g_pages: false,
+            supports_nx: false,
+            stats: PageStats::new(),
+
+            pcid: 0,
+            active_cpus: 0,
+
+            vmas: VmaTable::new()
         }
     }
 
-    // Identity-map first 16MB, leave rest to be allocated on demand
-    pub fn init(&mut self) {
-        // Identity-map first PML4T and PDPT entries
-        self.pml4tes[0]   = (&self.pdptes[0][0]   as *const u64 as u64 & !0xFFF) | 0x03;
-        self.pdptes[0][0] = (&self.pdtes[0][0][0] as *const u64 as u64 & !0xFFF) | 0x03;
+    // Allocate PML4 and map all RAM at provided direct map offset, leave rest to be allocated on demand
+    pub unsafe fn init(&mut self, direct_map_offset: usize) -> bool {
+        self.supports_1g_pages = cpu::has(Feature::Page1Gb);
+        self.supports_nx       = cpu::has(Feature::Nx);
+
+        self.pml4t_phys = match allocate_table() {
+            None => return false,
+            Some(p) => Some(p as *const ())
+        };
+
+        // Regions are sorted by base but may overlap and needn't be page aligned, so merge them first
+        let mut start: usize = 0;
+        let mut end:   usize = 0;
+        for r in memory::regions().filter(|r| r.kind.is_ram()) {
+            let r_start = r.base & !0xFFF;
+            let r_end   = (r.end() + PAGE_SIZE - 1) & !0xFFF;
+            if r_start <= end && end != 0 {
+                end = core::cmp::max(end, r_end);
+                continue;
+            }
 
-        // Identity-map first 8 PDT entires (each 2MB)
-        for i in 0..8 {
-            self.pdtes[0][0][i] = (&self.ptes[0][0][i][0] as *const u64 as u64 & !0xFFF) | 0x03;
-            // ...and identity map each respective PT
-            for j in 0..512 {
-                self.ptes[0][0][i][j] = ((((i * PT_SIZE) + (j * PAGE_SIZE)) & !0xFFF) | 0x03) as u64;
+            if end != 0 && !self.map_direct(start, end, direct_map_offset) {
+                return false
             }
+            start = r_start;
+            end   = r_end;
+        }
+        if end != 0 && !self.map_direct(start, end, direct_map_offset) {
+            return false
         }
+        self.preallocate_kernel_half()
     }
 
-    // Check if virtual address lies within allocated virtual memory
-    pub fn is_virtually_allocated(&self, ptr: Option<*const ()>) -> bool {
-        if ptr.is_none() {
-            return true
+    // Give every kernel half PML4 entry a PDPT so copies of them in other address spaces never go stale
+    unsafe fn preallocate_kernel_half(&mut self) -> bool {
+        let pml4t = table_at(self.pml4t_phys.unwrap() as u64);
+        for i in 256..512 {
+            if (*pml4t)[i] & PTE_PRESENT == 0 {
+                (*pml4t)[i] = match allocate_table() {
+                    None => return false,
+                    Some(t) => t | TABLE_FLAGS
+                };
+            }
         }
+        true
+    }
 
-        let pml4t_index: usize = (ptr.unwrap() as usize >> 39) & 0x1FF;
-        let pdpt_index:  usize = (ptr.unwrap() as usize >> 30) & 0x1FF;
-        let pdt_index:   usize = (ptr.unwrap() as usize >> 21) & 0x1FF;
-        let pt_index:    usize = (ptr.unwrap() as usize >> 12) & 0x1FF;
+    // Create page table for new address space with empty user half and kernel half shared with provided one
+    pub unsafe fn new_user(kernel: &Pager) -> Option<Pager> {
+        let src = table_at(kernel.pml4t_phys? as u64);
+        let pml4t = allocate_table()?;
+        let dst = table_at(pml4t);
+        for i in 256..512 {
+            (*dst)[i] = (*src)[i];
+        }
 
-        // Bail if entries aren't marked present (can't be allocated)
-        if self.pml4tes[pml4t_index] & 0x01 == 0x00000000 {
-            return false
+        let mut pager = Pager::new();
+        pager.pml4t_phys        = Some(pml4t as *const ());
+        pager.supports_1g_pages = kernel.supports_1g_pages;
+        pager.supports_nx       = kernel.supports_nx;
+        Some(pager)
+    }
+
+    // Call function for every present leaf entry under PML4 entries first..last, stops when it returns false
+    pub unsafe fn walk_leaves(&self, first: usize, last: usize, f: &mut dyn FnMut(usize, *mut u64, PageSize) -> bool) -> bool {
+        match self.pml4t_phys {
+            None => true,
+            Some(p) => Pager::walk_table(p as u64, 4, 0, first, last, f)
         }
+    }
 
-        if self.pdptes[pml4t_index][pdpt_index] & 0x01 == 0x00000000 {
-            return false
+    unsafe fn walk_table(table: u64, level: usize, base: usize, first: usize, last: usize,
+                         f: &mut dyn FnMut(usize, *mut u64, PageSize) -> bool) -> bool {
+        for i in first..last {
+            let e = &mut (*table_at(table))[i];
+            if *e & PTE_PRESENT == 0 {
+                continue;
+            }
+
+            let mut vaddr = base | (i << (12 + 9 * (level - 1)));
+            // Sign-extend addresses in the upper half
+            if level == 4 && i >= 256 {
+                vaddr |= 0xFFFF_0000_0000_0000;
+            }
+
+            let cont = match (level, *e & PTE_HUGE != 0) {
+                (1, _)     => f(vaddr, e, PageSize::Size4K),
+                (2, true)  => f(vaddr, e, PageSize::Size2M),
+                (3, true)  => f(vaddr, e, PageSize::Size1G),
+                _ => Pager::walk_table(*e, level - 1, vaddr, 0, 512, f)
+            };
+            if !cont {
+                return false
+            }
         }
+        true
+    }
 
-        if self.pdtes[pml4t_index][pdpt_index][pdt_index] & 0x01 == 0x00000000 {
-            return false
+    // Copy user half into new address space sharing the kernel half, owned pages get copied into new frames
+    pub unsafe fn clone_user_half(&self, kernel: &Pager) -> Option<Pager> {
+        let mut child = Pager::new_user(kernel)?;
+        let ok = self.walk_leaves(0, 256, &mut |vaddr, e, size| {
+            let flags = *e & !PTE_ADDR_MASK & !PTE_HUGE & !PTE_ACCESSED & !PTE_DIRTY;
+            let frame = entry_frame(*e, size);
+
+            // Device and other borrowed mappings are shared as they are
+            if *e & PTE_OWNED == 0 {
+                return child.map_page_sized(frame as *const (), vaddr as *const (), flags, size).is_some()
+            }
+
+            // Owned memory is copied page by page, so huge pages become 4 KiB ones in the copy
+            let flags = match flags & PTE_COW {
+                0 => flags,
+                _ => (flags & !PTE_COW) | PTE_WRITABLE
+            };
+            for i in 0..size.bytes() / PAGE_SIZE {
+                let copy = match FRAME_ALLOCATOR.lock().allocate_frame() {
+                    None => return false,
+                    Some(p) => p
+                };
+                core::ptr::copy_nonoverlapping(phys_to_virt((frame as usize + i * PAGE_SIZE) as *const ()) as *const u8,
+                                               phys_to_virt(copy) as *mut u8, PAGE_SIZE);
+                if child.map_page(copy, (vaddr + i * PAGE_SIZE) as *const (), flags).is_none() {
+                    FRAME_ALLOCATOR.lock().free_frame(Some(copy));
+                    return false
+                }
+            }
+            true
+        });
+
+        match ok {
+            true => {
+                child.vmas = self.vmas;
+                Some(child)
+            },
+            false => {
+                child.destroy();
+                None
+            }
         }
-    
-        // Check if entry is present
-        self.ptes[pml4t_index][pdpt_index][pdt_index][pt_index] & 0x01 != 0x00000000
     }
 
-    // Check if physical address lies within allocated physical memory
-    pub fn is_physically_allocated(&self, ptr: Option<*const ()>) -> bool {
-        if ptr.is_none() {
-            return true
+    // Share user half with new address space copy-on-write, writable owned pages become read-only in both
+    pub unsafe fn fork_user_half(&mut self, kernel: &Pager) -> Option<Pager> {
+        let mut child = Pager::new_user(kernel)?;
+        let ok = self.walk_leaves(0, 256, &mut |vaddr, e, size| {
+            let frame = entry_frame(*e, size);
+            if *e & PTE_OWNED != 0 {
+                for i in 0..size.bytes() / PAGE_SIZE {
+                    if !FRAME_ALLOCATOR.lock().ref_frame(Some((frame as usize + i * PAGE_SIZE) as *const ())) {
+                        // Drop references taken so far, the page stays private to this address space
+                        for j in 0..i {
+                            FRAME_ALLOCATOR.lock().free_frame(Some((frame as usize + j * PAGE_SIZE) as *const ()));
+                        }
+                        return false
+                    }
+                }
+
+                if *e & PTE_WRITABLE != 0 {
+                    *e = (*e & !PTE_WRITABLE) | PTE_COW;
+                }
+            }
+
+            let flags = *e & !PTE_ADDR_MASK & !PTE_HUGE & !PTE_ACCESSED & !PTE_DIRTY;
+            match child.map_page_sized(frame as *const (), vaddr as *const (), flags, size) {
+                Some(_) => true,
+                None => {
+                    // The child never got this page, so destroying it won't drop the references taken for it
+                    if *e & PTE_OWNED != 0 {
+                        for i in 0..size.bytes() / PAGE_SIZE {
+                            FRAME_ALLOCATOR.lock().free_frame(Some((frame as usize + i * PAGE_SIZE) as *const ()));
+                        }
+                    }
+                    false
+                }
+            }
+        });
+
+        // Writable translations of now shared frames must go before anyone writes through them
+        tlb::shootdown_pcid(self.active_cpus, self.pcid);
+
+        match ok {
+            true => {
+                child.vmas = self.vmas;
+                Some(child)
+            },
+            false => {
+                // Pages already shared drop back to one reference and become writable again on the next write
+                child.destroy();
+                None
+            }
         }
+    }
 
-        if ptr == self.last_mapped_phys_addr {
-            return true
+    // Resolve write fault on copy-on-write page, returns false if the fault wasn't caused by one
+    pub unsafe fn handle_cow_fault(&mut self, vaddr: *const ()) -> bool {
+        let page = (vaddr as usize & !0xFFF) as *const ();
+        let e = loop {
+            match self.leaf(page) {
+                None => return false,
+                Some((e, _)) if *e & PTE_COW == 0 => return false,
+                Some((e, PageSize::Size4K)) => break e,
+                // Only the written 4 KiB page gets copied
+                Some(_) => if !self.split_huge_page(page) {
+                    return false
+                }
+            }
+        };
+
+        let frame = (*e & PTE_ADDR_MASK) as *const ();
+        let flags = (*e & !PTE_ADDR_MASK & !PTE_COW) | PTE_WRITABLE;
+        let refs = FRAME_ALLOCATOR.lock().ref_count(Some(frame));
+        match refs {
+            // Every other sharer is gone, so the frame can simply be written
+            1 => *e = frame as u64 | flags,
+            _ => {
+                let copy = match FRAME_ALLOCATOR.lock().allocate_frame() {
+                    None => return false,
+                    Some(p) => p
+                };
+                core::ptr::copy_nonoverlapping(phys_to_virt(frame) as *const u8, phys_to_virt(copy) as *mut u8, PAGE_SIZE);
+                *e = copy as u64 | flags;
+                FRAME_ALLOCATOR.lock().free_frame(Some(frame));
+            }
         }
+        self.invalidate(page, 1);
+        true
+    }
+
+    // Free user half tables and owned frames along with the PML4, kernel half is left alone
+    pub unsafe fn destroy(&mut self) {
+        let pml4t = match self.pml4t_phys {
+            None => return,
+            Some(p) => p as u64
+        };
+
+        // Translations must be gone everywhere before any frame is reused
+        tlb::shootdown_pcid(self.active_cpus, self.pcid);
+        Pager::free_table(pml4t, 4, 0, 256);
 
-        // Addresses less than 16MB are guaranteed to be indentity-mapped
-        if ptr.unwrap() < ((16 * 1024 * 1024) as *const ()) {
-            return true 
+        self.pml4t_phys  = None;
+        self.stats       = PageStats::new();
+        self.active_cpus = 0;
+        self.vmas        = VmaTable::new();
+    }
+
+    unsafe fn free_table(table: u64, level: usize, first: usize, last: usize) {
+        for i in first..last {
+            let e = (*table_at(table))[i];
+            if e & PTE_PRESENT == 0 {
+                continue;
+            }
+
+            match (level, e & PTE_HUGE != 0) {
+                (2..=4, false) => Pager::free_table(e & PTE_ADDR_MASK, level - 1, 0, 512),
+                _ => if e & PTE_OWNED != 0 {
+                    let size = match level {
+                        3 => PageSize::Size1G,
+                        2 => PageSize::Size2M,
+                        _ => PageSize::Size4K
+                    };
+                    for j in 0..size.bytes() / PAGE_SIZE {
+                        FRAME_ALLOCATOR.lock().free_frame(Some((entry_frame(e, size) as usize + j * PAGE_SIZE) as *const ()));
+                    }
+                }
+            }
         }
+        FRAME_ALLOCATOR.lock().free_frame(Some((table & PTE_ADDR_MASK) as *const ()));
+    }
 
-        let temp   = (ptr.unwrap() as usize & !0xFFF) / PAGE_SIZE;
-        let index  = temp / 64;
-        let offset = temp % 64;
+    // Map physical range at direct map offset as non-executable data
+    pub unsafe fn map_direct(&mut self, start: usize, end: usize, offset: usize) -> bool {
+        let flags = PTE_DEFAULT_FLAGS | PTE_GLOBAL | self.nx_flag();
+        self.map_range(start as *const (), (start + offset) as *const (), end - start, flags).is_some()
+    }
 
-        self.phys_addr_map[index] & (1 << offset) != 0x00000000
+    // Get no-execute flag, or nothing if the CPU doesn't support it
+    pub const fn nx_flag(&self) -> u64 {
+        match self.supports_nx {
+            true => PTE_NO_EXECUTE,
+            false => 0
+        }
     }
 
-    // Get last mapped physical address
-    pub const fn last_mapped_phys_addr(&self) -> Option<*const ()> {
-        self.last_mapped_phys_addr
+    // Check if CPU supports 1 GiB pages
+    pub const fn supports_1g_pages(&self) -> bool {
+        self.supports_1g_pages
     }
 
-    // Get last mapped virtual address
-    pub const fn last_mapped_virt_addr(&self) -> Option<*const ()> {
-        self.last_mapped_phys_addr
+    // Get number of mapped pages of each size
+    pub const fn page_stats(&self) -> PageStats {
+        self.stats
     }
 
-    // Get allocated pages
-    pub const fn allocated_pages(&self) -> &[Option<*const ()>; MAX_PAGES] {
-        &self.allocated_pages
+    // Walk tables down to entry of virtual address at provided level, creating missing tables if requested
+    unsafe fn entry_at(&self, vaddr: *const (), level: usize, flags: u64, create: bool) -> Option<*mut u64> {
+        let mut table = table_at(self.pml4t_phys? as u64);
+        for l in ((level + 1)..=4).rev() {
+            let e = &mut (*table)[table_index(vaddr as usize, l)];
+            if *e & PTE_PRESENT == 0 {
+                if !create {
+                    return None
+                }
+                *e = allocate_table()? | TABLE_FLAGS;
+            } else if *e & PTE_HUGE != 0 {
+                // Address is already covered by a larger page
+                return None
+            }
+
+            // User pages need the user bit on every level above them
+            *e |= flags & PTE_USER;
+            table = table_at(*e);
+        }
+        Some(&mut (*table)[table_index(vaddr as usize, level)] as *mut u64)
     }
 
-    // Get PML4 Table
-    pub const fn pml4t(&self) -> &[u64; 512] {
-        &self.pml4tes
+    // Find leaf entry mapping virtual address along with the size of the page it maps
+    unsafe fn leaf(&self, vaddr: *const ()) -> Option<(*mut u64, PageSize)> {
+        let mut table = table_at(self.pml4t_phys? as u64);
+        for l in (1..=4).rev() {
+            let e = &mut (*table)[table_index(vaddr as usize, l)];
+            if *e & PTE_PRESENT == 0 {
+                return None
+            }
+
+            match l {
+                1 => return Some((e as *mut u64, PageSize::Size4K)),
+                2 if *e & PTE_HUGE != 0 => return Some((e as *mut u64, PageSize::Size2M)),
+                3 if *e & PTE_HUGE != 0 => return Some((e as *mut u64, PageSize::Size1G)),
+                _ => { }
+            }
+            table = table_at(*e);
+        }
+        None
     }
 
-    // Activate page table
-    pub unsafe fn activate(&self) {
-        lcr3(&self.pml4tes as *const u64 as usize);
+    // Read leaf entry of virtual address, zero if it isn't mapped
+    fn read_entry(&self, vaddr: *const ()) -> (u64, PageSize) {
+        unsafe {
+            match self.leaf(vaddr) {
+                None => (0, PageSize::Size4K),
+                Some((e, size)) => (*e, size)
+            }
+        }
     }
 
-    // Map provided physical address to provided virtual address (neither can be None)
-    pub unsafe fn map_phys_addr_to_virt_addr(&mut self, paddr: Option<*const ()>, vaddr: Option<*const ()>) -> Option<*const ()> {
-        if vaddr.is_none() {
+    // Map 4 KiB page with provided flags, fails if virtual address is already mapped
+    pub unsafe fn map_page(&mut self, paddr: *const (), vaddr: *const (), flags: u64) -> Option<*const ()> {
+        self.map_page_sized(paddr, vaddr, flags, PageSize::Size4K)
+    }
+
+    // Map page of provided size, both addresses must be aligned to it
+    pub unsafe fn map_page_sized(&mut self, paddr: *const (), vaddr: *const (), flags: u64, size: PageSize) -> Option<*const ()> {
+        let mask = size.bytes() - 1;
+        // Can't map unaligned addresses
+        if (vaddr as usize & mask) != 0 || (paddr as usize & mask) != 0 {
             return None
         }
 
-        if paddr.is_none() {
+        if size == PageSize::Size1G && !self.supports_1g_pages {
             return None
         }
 
-        // Can't map unaligned address
-        if (vaddr.unwrap() as usize & 0xFFF) != 0x000 {
+        let e = self.entry_at(vaddr, size.level(), flags, true)?;
+        if *e & PTE_PRESENT != 0 {
             return None
         }
 
-        // Can't map unaligned address
-        if (paddr.unwrap() as usize & 0xFFF) != 0x000 {
+        *e = (paddr as u64 & PTE_ADDR_MASK) | flags | PTE_PRESENT | match size {
+            PageSize::Size4K => 0,
+            _ => PTE_HUGE
+        };
+        *self.stats.count(size) += 1;
+        Some(vaddr)
+    }
+
+    // Map physical range using the largest page size both addresses are aligned to at each step
+    pub unsafe fn map_range(&mut self, paddr: *const (), vaddr: *const (), len: usize, flags: u64) -> Option<*const ()> {
+        if (vaddr as usize & 0xFFF) != 0 || (paddr as usize & 0xFFF) != 0 {
             return None
         }
 
-        // Can't map address if address is already allocated
-        if self.is_virtually_allocated(vaddr) {
-            return None
+        let mut offset: usize = 0;
+        while offset < len {
+            let p = paddr as usize + offset;
+            let v = vaddr as usize + offset;
+            let size = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K].into_iter()
+                .find(|s| (*s != PageSize::Size1G || self.supports_1g_pages)
+                    && p.is_multiple_of(s.bytes())
+                    && v.is_multiple_of(s.bytes())
+                    && len - offset >= s.bytes())
+                .unwrap_or(PageSize::Size4K);
+
+            self.map_page_sized(p as *const (), v as *const (), flags, size)?;
+            offset += size.bytes();
+        }
+        Some(vaddr)
+    }
+
+    // Split huge page containing virtual address into pages of the next smaller size
+    pub unsafe fn split_huge_page(&mut self, vaddr: *const ()) -> bool {
+        let (e, size) = match self.leaf(vaddr) {
+            None => return false,
+            Some(l) => l
+        };
+
+        if size == PageSize::Size4K {
+            return true
+        }
+
+        let table = match allocate_table() {
+            None => return false,
+            Some(t) => t
+        };
+
+        // Children inherit everything but the address, 4 KiB entries use bit 7 for PAT instead of size
+        let small = size.smaller();
+        let base  = entry_frame(*e, size);
+        let flags = match small {
+            PageSize::Size4K => *e & !PTE_ADDR_MASK & !PTE_HUGE,
+            _ => *e & !PTE_ADDR_MASK
+        };
+        for i in 0..512 {
+            (*table_at(table))[i] = (base + (i * small.bytes()) as u64) | flags;
         }
- 
-        let pml4t_index: usize = (vaddr.unwrap() as usize >> 39) & 0x1FF;
-        let pdpt_index:  usize = (vaddr.unwrap() as usize >> 30) & 0x1FF;
-        let pdt_index:   usize = (vaddr.unwrap() as usize >> 21) & 0x1FF;
-        let pt_index:    usize = (vaddr.unwrap() as usize >> 12) & 0x1FF;
 
-        // Mark entries as present if not already marked
-        if (self.pml4tes[pml4t_index] & 0x03) != 0x03 {
-            self.pml4tes[pml4t_index] = (&self.pdptes[pml4t_index][pdpt_index] as *const _) as u64 | 0x03;
+        *e = table | TABLE_FLAGS | (flags & PTE_USER);
+        *self.stats.count(size)  -= 1;
+        *self.stats.count(small) += 512;
+
+        // Stale huge page translations must not linger next to the new small ones
+        self.invalidate((vaddr as usize & !(size.bytes() - 1)) as *const (), size.bytes() / PAGE_SIZE);
+        true
+    }
+
+    // Change flags of pages mapped in range, splitting huge pages that only partly overlap it
+    pub unsafe fn protect_range(&mut self, vaddr: *const (), len: usize, flags: u64) -> Option<*const ()> {
+        if (vaddr as usize & 0xFFF) != 0 {
+            return None
         }
 
-        if (self.pdptes[pml4t_index][pdpt_index] & 0x03) != 0x03 {
-            self.pdptes[pml4t_index][pdpt_index] = 
-                (&self.pdtes[pml4t_index][pdpt_index][pdt_index] as *const _) as u64 | 0x03;
+        let end   = vaddr as usize + len;
+        let mut v = vaddr as usize;
+        while v < end {
+            let (e, size) = match self.leaf(v as *const ()) {
+                // Pages that aren't populated yet get their flags when they are mapped
+                None => {
+                    v += PAGE_SIZE;
+                    continue;
+                },
+                Some(l) => l
+            };
+            let page_start = v & !(size.bytes() - 1);
+            if page_start < vaddr as usize || page_start + size.bytes() > end {
+                if !self.split_huge_page(v as *const ()) {
+                    return None
+                }
+                continue;
+            }
+
+            // Keep address, page size and ownership, replace everything else
+            let cow = *e & PTE_COW != 0;
+            *e = (*e & (PTE_ADDR_MASK | PTE_HUGE | PTE_OWNED)) | (flags & !PTE_ADDR_MASK & !PTE_HUGE & !PTE_COW) | PTE_PRESENT;
+            // Shared frames stay read-only, write access is granted by copying them on the first write
+            if cow && *e & PTE_WRITABLE != 0 {
+                *e = (*e & !PTE_WRITABLE) | PTE_COW;
+            }
+            v = page_start + size.bytes();
         }
 
-        if (self.pdtes[pml4t_index][pdpt_index][pdt_index] & 0x03) != 0x03 {
-            self.pdtes[pml4t_index][pdpt_index][pdt_index] = 
-                (&self.ptes[pml4t_index][pdpt_index][pdt_index][pt_index] as *const _) as u64 | 0x03;
+        // Flush whole range at once rather than page by page
+        self.invalidate(vaddr, (end - vaddr as usize).div_ceil(PAGE_SIZE));
+        Some(vaddr)
+    }
+
+    // Check if virtual address lies within allocated virtual memory
+    pub fn is_virtually_allocated(&self, ptr: Option<*const ()>) -> bool {
+        match ptr {
+            None => true,
+            Some(p) => self.read_entry(p).0 & PTE_PRESENT != 0
         }
+    }
+
+    // Check if physical address lies within allocated physical memory
+    pub fn is_physically_allocated(&self, ptr: Option<*const ()>) -> bool {
+        FRAME_ALLOCATOR.lock().is_allocated(ptr)
+    }
+
+    // Get last mapped physical address
+    pub const fn last_mapped_phys_addr(&self) -> Option<*const ()> {
+        self.last_mapped_phys_addr
+    }
+
+    // Get last mapped virtual address
+    pub const fn last_mapped_virt_addr(&self) -> Option<*const ()> {
+        self.last_mapped_virt_addr
+    }
+
+    // Get physical address of PML4 Table
+    pub const fn pml4t_phys(&self) -> Option<*const ()> {
+        self.pml4t_phys
+    }
+
+    // Get PML4 Table
+    pub fn pml4t(&self) -> Option<&[u64; 512]> {
+        unsafe { Some(&*table_at(self.pml4t_phys? as u64)) }
+    }
+
+    // Get PCID of page table
+    pub const fn pcid(&self) -> u16 {
+        self.pcid
+    }
+
+    // Set PCID used when page table is activated
+    pub fn set_pcid(&mut self, pcid: u16) {
+        self.pcid = pcid & 0xFFF;
+    }
 
-        // Perform the actual allocation
-        if (self.ptes[pml4t_index][pdpt_index][pdt_index][pt_index] & 0x03) != 0x03 {
-            self.ptes[pml4t_index][pdpt_index][pdt_index][pt_index] = paddr.unwrap() as u64 | 0x03;
+    // Activate page table
+    pub unsafe fn activate(&mut self) {
+        if let Some(p) = self.pml4t_phys {
+            let pcid = match tlb::pcid_enabled() {
+                true => self.pcid as usize,
+                false => 0
+            };
+            lcr3(p as usize | pcid);
+            self.active_cpus |= tlb::current_cpu_mask();
         }
- 
-        // and mark page as allocated in physical address map
-        let temp   = (paddr.unwrap() as usize & !0xFFF) / PAGE_SIZE;
-        let index  = temp / 64;
-        let offset = temp % 64; 
+    }
 
-        self.phys_addr_map[index] |= 1 << offset;
-        return vaddr
+    // Invalidate translations of range on every CPU that may cache them
+    pub fn invalidate(&self, vaddr: *const (), num_pages: usize) {
+        tlb::shootdown(self.active_cpus, self.pcid, vaddr, num_pages);
+    }
+
+    // Map provided physical address to provided virtual address (neither can be None)
+    pub unsafe fn map_phys_addr_to_virt_addr(&mut self, paddr: Option<*const ()>, vaddr: Option<*const ()>) -> Option<*const ()> {
+        self.map_page(paddr?, vaddr?, PTE_DEFAULT_FLAGS)
     }
 
     // Allocate page either at provided virtual address, or at a random virtual address if none provided
@@ -239,24 +712,32 @@ impl Pager {
             }
         };
 
-        // Find free physical page to map virtual page to
-        let paddr = match self.find_free_physical_page() {
+        // Allocate physical frame to map virtual page to
+        let paddr = match FRAME_ALLOCATOR.lock().allocate_frame() {
             // Bail if none can be found
             None => return None,
             Some(p) => p
         };
 
         // Map physical address to virtual address
-        return match self.map_phys_addr_to_virt_addr(Some(paddr), Some(vaddr)) {
+        return match self.map_page(paddr, vaddr, PTE_DEFAULT_FLAGS | PTE_OWNED | self.nx_flag()) {
             Some(_p) => {
+                // Record page as anonymous memory, undoing the mapping if the area table is full
+                if !self.track(vaddr, PAGE_SIZE, PTE_DEFAULT_FLAGS | self.nx_flag(), Backing::Anonymous) {
+                    self.deallocate_page(Some(vaddr));
+                    return None
+                }
                 // If successful, update last_mapped_phys_addr and last_mapped_virt_addr accordingly
                 self.last_mapped_phys_addr = Some(paddr);
                 self.last_mapped_virt_addr = Some(vaddr);
                 // And return virtual address
-                Some(paddr)
+                Some(vaddr)
             },
-            // Else bail
-            None => None
+            // Else give frame back and bail
+            None => {
+                FRAME_ALLOCATOR.lock().free_frame(Some(paddr));
+                None
+            }
         }
     }
 
@@ -281,7 +762,7 @@ impl Pager {
                 Some(p) => p
             },
             // If one is provided, ensure it's properly aligned
-            Some(p) => match (p as usize & !0xFFF) != 0 {
+            Some(p) => match (p as usize & 0xFFF) != 0 {
                 // If not, bail
                 true => return None,
                 false => p
@@ -290,19 +771,11 @@ impl Pager {
 
         // Map each page
         for i in 0..num_pages {
-            // Find random free physical page to map virtual page to
-            let paddr = match self.find_free_physical_page() {
-                // Bail if none can be found
-                None => return None,
-                Some(p) => p
-            };
-
-            // Perform the actual mapping
-            match self.map_phys_addr_to_virt_addr(Some(paddr), Some((p as usize + i * PAGE_SIZE) as *const ())) {
-                Some(_p) => { },
-                // Bail if mapping fails
-                None => return None
-            };
+            // Allocate and map page, undoing what was mapped so far if it fails
+            if self.allocate_page(Some((p as usize + i * PAGE_SIZE) as *const ())).is_none() {
+                self.deallocate_pages(Some(p), i);
+                return None
+            }
         }
         // Return virtual address
         return Some(p)
@@ -310,23 +783,29 @@ impl Pager {
 
     // Allocate virtually- and physically-contiguous pages either at provided addresses, or random address if none provided respectively
     pub unsafe fn allocate_physically_contiguous_pages(&mut self, paddr: Option<*const ()>, vaddr: Option<*const ()>, num_pages: usize) -> Option<*const ()> {
-        let p = match paddr {
-            // If no physical address is provided, find one
-            None => match self.find_free_contiguous_physical_pages(num_pages) {
+        // Can't map zero pages
+        if num_pages == 0 {
+            return None
+        }
+
+        // Frames only belong to this mapping if they were allocated here
+        let (p, flags) = match paddr {
+            // If no physical address is provided, allocate one
+            None => match FRAME_ALLOCATOR.lock().allocate_frames(num_pages) {
                 // Bail if none can be found
                 None => return None,
-                Some(p) => p
+                Some(p) => (p, PTE_DEFAULT_FLAGS | PTE_OWNED | self.nx_flag())
             },
             // Else ensure address is correctly aligned
             Some(p) => match (p as usize & 0xFFF) != 0x000 {
                 // Bail if not
-                false => return None,
-                true => p
+                true => return None,
+                false => (p, PTE_DEFAULT_FLAGS | self.nx_flag())
             }
         };
 
         let v = match vaddr {
-            // If no physical address is provided, find one
+            // If no virtual address is provided, find one
             None => match self.find_free_contiguous_virtual_pages(num_pages) {
                 // Bail if none can be found
                 None => return None,
@@ -335,39 +814,44 @@ impl Pager {
             // Else ensure address is correctly aligned
             Some(p) => match (p as usize & 0xFFF) != 0x000 {
                 // Bail if not
-                false => return None,
-                true => p
+                true => return None,
+                false => p
             }
         };
 
-        match num_pages {
-            // Can't map zero pages
-            0 => return None,
-            // Map single page
-            1 => return match self.map_phys_addr_to_virt_addr(Some(p), Some(v)) {
-                Some(_p) => Some(v),
-                None => None
-            },
-            // Map pages
-            _ => {
+        let backing = match flags & PTE_OWNED {
+            0 => Backing::Device { paddr: p as usize },
+            _ => Backing::Anonymous
+        };
+        if !self.track(v, num_pages * PAGE_SIZE, flags, backing) {
+            if flags & PTE_OWNED != 0 {
                 for i in 0..num_pages {
-                    // Calculate pointers
-                    let temp_paddr = (paddr.unwrap() as usize + i * PAGE_SIZE) as *const ();
-                    let temp_vaddr = (vaddr.unwrap() as usize + i * PAGE_SIZE) as *const ();
-                    
-                    // Perform actual mapping
-                    match self.map_phys_addr_to_virt_addr(Some(temp_paddr), Some(temp_vaddr)) {
-                        Some(_p) => { },
-                        // Bail if mapping fails
-                        None => return None
+                    FRAME_ALLOCATOR.lock().free_frame(Some((p as usize + i * PAGE_SIZE) as *const ()));
+                }
+            }
+            return None
+        }
+
+        // Perform actual mapping, using huge pages where addresses allow
+        match self.map_range(p, v, num_pages * PAGE_SIZE, flags) {
+            Some(v) => Some(v),
+            None => {
+                // Frames of pages that never got mapped are out of reach of deallocate_pages
+                if flags & PTE_OWNED != 0 {
+                    for i in 0..num_pages {
+                        if !self.is_virtually_allocated(Some((v as usize + i * PAGE_SIZE) as *const ())) {
+                            FRAME_ALLOCATOR.lock().free_frame(Some((p as usize + i * PAGE_SIZE) as *const ()));
+                        }
                     }
                 }
+                // Unmaps the rest, frees their frames and drops the area
+                self.deallocate_pages(Some(v), num_pages);
                 None
             }
         }
     }
 
-    // Unmap physical address
+    // Return physical frame to frame allocator
     unsafe fn unmap_phys_addr(&mut self, ptr: Option<*const ()>) -> Option<*const ()> {
         // Can't unmap nonexistent pointer
         if ptr.is_none() {
@@ -379,17 +863,7 @@ impl Pager {
             return None
         }
 
-        // Can't unmap pointer that's not allocated
-        if !self.is_physically_allocated(ptr) {
-            return None
-        }
-
-        let temp = ptr.unwrap() as usize / PAGE_SIZE;
-        let index = temp / 64;
-        let offset = temp % 64;
-
-        self.phys_addr_map[index] &= !(1 << offset);
-        return ptr
+        FRAME_ALLOCATOR.lock().free_frame(ptr)
     }
 
     // Unmap virtual address
@@ -404,18 +878,21 @@ impl Pager {
             return None
         }
 
-        // Can't unmap pointer not already allocated
-        if !self.is_virtually_allocated(ptr) {
-            return None
-        }
- 
-        let pml4t_index: usize = (ptr.unwrap() as usize >> 39) & 0x1FF;
-        let pdpt_index:  usize = (ptr.unwrap() as usize >> 30) & 0x1FF;
-        let pdt_index:   usize = (ptr.unwrap() as usize >> 21) & 0x1FF;
-        let pt_index:    usize = (ptr.unwrap() as usize >> 12) & 0x1FF;
+        // Can't unmap pointer not already allocated, and only 4 KiB pages can be unmapped on their own
+        let e = loop {
+            match self.leaf(ptr.unwrap()) {
+                None => return None,
+                Some((e, PageSize::Size4K)) => break e,
+                Some(_) => if !self.split_huge_page(ptr.unwrap()) {
+                    return None
+                }
+            }
+        };
 
-        // Perform actual unmapping
-        self.ptes[pml4t_index][pdpt_index][pdt_index][pt_index] = 0x00000000;
+        // Perform actual unmapping, translation must be gone everywhere before the frame is reused
+        *e = 0x00000000;
+        self.stats.pages_4k -= 1;
+        self.invalidate(ptr.unwrap(), 1);
         return ptr
     }
 
@@ -432,23 +909,24 @@ impl Pager {
         }
 
         // Can't deallocate page not already allocated
-        if !self.is_virtually_allocated(ptr) {
+        let paddr = match self.as_phys_addr(ptr) {
+            None => return None,
+            Some(p) => p
+        };
+        let e = self.read_entry(ptr.unwrap()).0;
+
+        // Perform unmapping of virtual address
+        if self.unmap_virt_addr(ptr).is_none() {
             return None
         }
 
-        // Perform unmapping of virtual address and keep result
-        let b: bool = self.unmap_virt_addr(ptr) == None;
+        self.untrack(ptr.unwrap(), PAGE_SIZE);
 
-        // Perform unmapping of physical address, and return None if fail
-        if self.unmap_phys_addr(self.as_phys_addr(ptr)).is_none() {
+        // Return frame to allocator if this mapping owned it
+        if e & PTE_OWNED != 0 && self.unmap_phys_addr(Some(paddr)).is_none() {
             return None
         }
-
-        // Return none if virtual unmap failed, ptr if neither failed
-        return match b {
-            true => None,
-            false => ptr
-        }
+        ptr
     }
 
     // Deallocate virtually-contiguous pages starting at proved virtual address
@@ -464,10 +942,42 @@ impl Pager {
         }
 
         let mut b: bool = false;
-        // deallocate pages, note if any failed but still try the rest
+        // Mark pages not present but keep their addresses, note if any failed but still try the rest
         for i in 0..num_pages {
-            if self.deallocate_page(Some((ptr.unwrap() as usize + i * PAGE_SIZE) as *const ())).is_none() {
-                b = true;
+            let p = (ptr.unwrap() as usize + i * PAGE_SIZE) as *const ();
+            let e = loop {
+                match self.leaf(p) {
+                    None => break None,
+                    Some((e, PageSize::Size4K)) => break Some(e),
+                    Some(_) => if !self.split_huge_page(p) {
+                        break None
+                    }
+                }
+            };
+
+            match e {
+                None => b = true,
+                Some(e) => {
+                    *e &= !PTE_PRESENT;
+                    self.stats.pages_4k -= 1;
+                }
+            }
+        }
+
+        // Single invalidation for the whole range before any frame is reused
+        self.invalidate(ptr.unwrap(), num_pages);
+        self.untrack(ptr.unwrap(), num_pages * PAGE_SIZE);
+
+        // Free owned frames and clear entries
+        for i in 0..num_pages {
+            let p = (ptr.unwrap() as usize + i * PAGE_SIZE) as *const ();
+            if let Some(e) = self.entry_at(p, PageSize::Size4K.level(), 0, false) {
+                if *e & PTE_PRESENT == 0 && *e != 0 {
+                    if *e & PTE_OWNED != 0 && self.unmap_phys_addr(Some((*e & PTE_ADDR_MASK) as *const ())).is_none() {
+                        b = true;
+                    }
+                    *e = 0x00000000;
+                }
             }
         }
 
@@ -478,124 +988,233 @@ impl Pager {
         };
     }
 
-    // Find free virtual page
+    // Find free virtual page in kernel heap region
     pub fn find_free_virtual_page(&self) -> Option<*const ()> {
-        let mut addr: usize = match self.last_mapped_virt_addr.is_none() {
-            // If no virtual address has been mapped yet, use 16MB
-            true => 16 * 1024 * 1024,
-            false => self.last_mapped_virt_addr.unwrap() as usize
-        };
+        self.find_free_contiguous_virtual_pages(1)
+    }
 
-        let mut b: bool = false;
-        // Find free page
-        loop {
-            if addr > VMEM_MAX - PAGE_SIZE {
-                break;
-            }
+    // Find free physical page
+    pub fn find_free_physical_page(&self) -> Option<*const ()> {
+        FRAME_ALLOCATOR.lock().find_free_frame()
+    }
 
-            if !self.is_virtually_allocated(Some(addr as *const ())) {
-                b = true;
-                break;
-            }
-            addr += PAGE_SIZE;
-        }
-        // Return None if none can be found
-        if b == false {
+    // Find range of free contiguous virtual pages in kernel heap region
+    pub fn find_free_contiguous_virtual_pages(&self, num_pages: usize) -> Option<*const ()> {
+        let (lo, hi) = Pager::search_region(false);
+        self.vmas.find_gap(num_pages * PAGE_SIZE, lo, hi).map(|p| p as *const ())
+    }
+
+    // Find range of free contiguous physical pages
+    pub fn find_free_contiguous_physical_pages(&self, num_pages: usize) -> Option<*const ()> {
+        FRAME_ALLOCATOR.lock().find_free_frames(num_pages)
+    }
+
+    // Get physical address from provided virtual address
+    pub fn as_phys_addr(&self, ptr: Option<*const ()>) -> Option<*const ()> {
+        let p = ptr?;
+        let (e, size) = self.read_entry(p);
+        if e & PTE_PRESENT == 0 {
             return None
         }
-        // Else return pointer
-        Some(addr as *const ())
+        Some((entry_frame(e, size) | (p as usize & (size.bytes() - 1)) as u64) as *const ())
     }
 
-    // Find free physical page -- Same functioning as above
-    pub fn find_free_physical_page(&self) -> Option<*const ()> {
-        let mut addr: usize = match self.last_mapped_virt_addr.is_none() {
-            true => 16 * 1024 * 1024,
-            false => self.last_mapped_phys_addr.unwrap() as usize
-        };
-
-        let mut b: bool = false;
-        loop {
-            if addr > VMEM_MAX - PAGE_SIZE {
-                break;
-            }
+    // Get areas of address space
+    pub const fn vmas(&self) -> &VmaTable {
+        &self.vmas
+    }
 
-            if self.is_physically_allocated(Some(addr as *const ())) {
-                b = true;
-                break;
-            }
-            addr += PAGE_SIZE;
+    // Get range searched for free virtual memory, user memory avoids the first pages to keep null dereferences faulting
+    fn search_region(user: bool) -> (usize, usize) {
+        match user {
+            true => (USER_MMAP_BASE, USER_HALF_END),
+            false => (kaslr::heap_base(), kaslr::heap_base() + VMEM_MAX)
         }
-        if b == false {
-            return None
+    }
+
+    // Check if virtual address lies in a region whose allocations are tracked as areas
+    fn is_tracked(vaddr: usize) -> bool {
+        let (user_lo, user_hi) = Pager::search_region(true);
+        let (heap_lo, heap_hi) = Pager::search_region(false);
+        (vaddr >= user_lo && vaddr < user_hi) || (vaddr >= heap_lo && vaddr < heap_hi)
+    }
+
+    // Record range mapped outside of mmap() as area, ranges already inside areas are left as they are
+    unsafe fn track(&mut self, vaddr: *const (), len: usize, flags: u64, backing: Backing) -> bool {
+        let start = vaddr as usize;
+        if !Pager::is_tracked(start) || self.vmas.covers(start, start + len) {
+            return true
         }
-        Some(addr as *const ())
+        self.vmas.insert(Vma { start, end: start + len, prot: Pager::prot_of(flags), backing })
     }
 
-    // Find range of free contiguous virtual pages
-    pub fn find_free_contiguous_virtual_pages(&self, num_pages: usize) -> Option<*const ()> {
-        let mut p: *const () = (16 * 1024 * 1024) as *const ();
-        loop {
-            if p as usize + num_pages * PAGE_SIZE > VMEM_MAX {
-                break;
-            }
-            let mut b: bool = false;
-            for i in 0..num_pages {
-                if self.is_virtually_allocated(Some((p as usize + i * PAGE_SIZE) as *const ())) {
-                    b = true;
-                    break;
-                }
-            }
-            if b == false {
-                return Some(p)
-            }
-            p = (p as usize + PAGE_SIZE) as *const ();
+    fn untrack(&mut self, vaddr: *const (), len: usize) {
+        if Pager::is_tracked(vaddr as usize) {
+            self.vmas.remove(vaddr as usize, vaddr as usize + len);
         }
-        None
     }
 
-    // Find range of free contiguous physical pages
-    pub fn find_free_contiguous_physical_pages(&self, num_pages: usize) -> Option<*const ()> {
-        let mut mask: u64 = 0;
-        for i in 0..num_pages {
-            mask |= 1 << i;
+    // Translate page table entry flags into area permissions
+    const fn prot_of(flags: u64) -> u64 {
+        let mut prot = vma::VMA_READ;
+        if flags & PTE_WRITABLE != 0 {
+            prot |= vma::VMA_WRITE;
         }
+        if flags & PTE_USER != 0 {
+            prot |= vma::VMA_USER;
+        }
+        if flags & PTE_NO_EXECUTE == 0 {
+            prot |= vma::VMA_EXEC;
+        }
+        prot
+    }
 
-        for i in 0..NUM_PHYS_ADDR_MAP_ENTRIES {
-            for j in 0..64 {
-                if (self.phys_addr_map[i] >> j) & mask == mask {
-                    return Some(((i * 64 * PAGE_SIZE) + (j * PAGE_SIZE)) as *const ())
-                }
-            }
+    // Get page table entry flags for pages of area
+    pub const fn vma_flags(&self, vma: &Vma) -> u64 {
+        let mut flags = PTE_PRESENT;
+        if vma.prot & vma::VMA_WRITE != 0 {
+            flags |= PTE_WRITABLE;
+        }
+        if vma.prot & vma::VMA_USER != 0 {
+            flags |= PTE_USER;
+        }
+        if vma.prot & vma::VMA_EXEC == 0 {
+            flags |= self.nx_flag();
+        }
+        match vma.backing {
+            Backing::Device { .. } => flags | PTE_CACHE_DISABLE | PTE_WRITE_THROUGH,
+            _ => flags
         }
-        return None
     }
 
-    // Get physical address from provided virtual address
-    pub fn as_phys_addr(&self, ptr: Option<*const ()>) -> Option<*const ()> {
-        if ptr.is_none() {
+    // Create area of provided length at provided address, or in the first gap that fits if none provided
+    pub unsafe fn mmap(&mut self, vaddr: Option<*const ()>, len: usize, prot: u64, backing: Backing) -> Option<*const ()> {
+        let len = (len + PAGE_SIZE - 1) & !0xFFF;
+        if len == 0 {
             return None
         }
 
-        let pml4t_index: usize = (ptr.unwrap() as usize >> 39) & 0x1FF;
-        let pdpt_index:  usize = (ptr.unwrap() as usize >> 30) & 0x1FF;
-        let pdt_index:   usize = (ptr.unwrap() as usize >> 21) & 0x1FF;
-        let pt_index:    usize = (ptr.unwrap() as usize >> 12) & 0x1FF;
-        let offset:      usize =  ptr.unwrap() as usize & 0xFFF;
+        let start = match vaddr {
+            None => {
+                let (lo, hi) = Pager::search_region(prot & vma::VMA_USER != 0);
+                self.vmas.find_gap(len, lo, hi)?
+            },
+            Some(p) => match p as usize & 0xFFF {
+                0 => p as usize,
+                _ => return None
+            }
+        };
 
-        if self.pml4tes[pml4t_index] & 0x01 == 0x00000000 {
+        let area = Vma { start, end: start + len, prot, backing };
+        if !self.vmas.insert(area) {
             return None
         }
 
-        if self.pdptes[pml4t_index][pdpt_index] & 0x01 == 0x00000000 {
+        // Physical backings are known up front, everything else is populated on first access
+        let paddr = match backing {
+            Backing::Device { paddr } | Backing::Shared { paddr } => paddr,
+            _ => return Some(start as *const ())
+        };
+        match self.map_range(paddr as *const (), start as *const (), len, self.vma_flags(&area)) {
+            Some(p) => Some(p),
+            None => {
+                self.munmap(start as *const (), len);
+                None
+            }
+        }
+    }
+
+    // Remove areas in range and unmap their pages, freeing frames that were populated on demand
+    pub unsafe fn munmap(&mut self, vaddr: *const (), len: usize) -> Option<*const ()> {
+        let len = (len + PAGE_SIZE - 1) & !0xFFF;
+        if vaddr as usize & 0xFFF != 0 || !self.vmas.remove(vaddr as usize, vaddr as usize + len) {
             return None
         }
 
-        if self.pdtes[pml4t_index][pdpt_index][pdt_index] & 0x01 == 0x00000000 {
+        // Holes are fine here, lazily populated areas are rarely mapped in full
+        self.deallocate_pages(Some(vaddr), len / PAGE_SIZE);
+        Some(vaddr)
+    }
+
+    // Change protection of areas in range and of pages already populated in them
+    pub unsafe fn mprotect(&mut self, vaddr: *const (), len: usize, prot: u64) -> Option<*const ()> {
+        let start = vaddr as usize;
+        let end   = start + ((len + PAGE_SIZE - 1) & !0xFFF);
+        if start & 0xFFF != 0 || !self.vmas.covers(start, end) || !self.vmas.protect(start, end, prot) {
             return None
         }
-    
-        Some(((self.ptes[pml4t_index][pdpt_index][pdt_index][pt_index] & !0xFFF) | offset as u64) as *const ())
+
+        let areas = self.vmas;
+        for a in areas.iter().filter(|a| a.end > start && a.start < end) {
+            let from = core::cmp::max(a.start, start);
+            let to   = core::cmp::min(a.end, end);
+            self.protect_range(from as *const (), to - from, self.vma_flags(a))?;
+        }
+        Some(vaddr)
+    }
+
+    // Make sure every page of user range is mapped for user access, and writable if requested, resolving pages
+    // left to be populated or copied on first access. Returns false where a user access would fault for good
+    pub unsafe fn prepare_user_range(&mut self, vaddr: usize, len: usize, write: bool) -> bool {
+        let end = match vaddr.checked_add(len) {
+            None => return false,
+            Some(e) => e
+        };
+        if end > USER_HALF_END {
+            return false
+        }
+
+        let mut page = vaddr & !0xFFF;
+        while page < end {
+            let p = page as *const ();
+            if self.leaf(p).is_none() && !self.handle_area_fault(p, write, true) {
+                return false
+            }
+
+            let (e, _) = self.read_entry(p);
+            if write && e & PTE_WRITABLE == 0 && (e & PTE_COW == 0 || !self.handle_cow_fault(p)) {
+                return false
+            }
+
+            let (e, _) = self.read_entry(p);
+            if e & PTE_USER == 0 || (write && e & PTE_WRITABLE == 0) {
+                return false
+            }
+            page += PAGE_SIZE;
+        }
+        true
     }
 
-}
\ No newline at end of file
+    // Populate page of area on first access, returns false if the area forbids the access or can't be populated
+    pub unsafe fn handle_area_fault(&mut self, vaddr: *const (), write: bool, user: bool) -> bool {
+        let area = match self.vmas.find(vaddr as usize) {
+            None => return false,
+            Some(a) => *a
+        };
+
+        if (write && area.prot & vma::VMA_WRITE == 0) || (user && area.prot & vma::VMA_USER == 0) {
+            return false
+        }
+
+        match area.backing {
+            Backing::Anonymous => {
+                let frame = match FRAME_ALLOCATOR.lock().allocate_frame() {
+                    None => return false,
+                    Some(p) => p
+                };
+                core::ptr::write_bytes(phys_to_virt(frame) as *mut u8, 0, PAGE_SIZE);
+
+                let page = (vaddr as usize & !0xFFF) as *const ();
+                match self.map_page(frame, page, self.vma_flags(&area) | PTE_OWNED) {
+                    Some(_p) => true,
+                    None => {
+                        FRAME_ALLOCATOR.lock().free_frame(Some(frame));
+                        false
+                    }
+                }
+            },
+            // File contents need a file layer, physical backings were mapped with the area
+            _ => false
+        }
+    }
+}
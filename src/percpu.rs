@@ -0,0 +1,220 @@
+use core::cell::UnsafeCell;
+use core::ptr::addr_of;
+
+use crate::asm_wrappers::{read_gs_u64, swapgs, wrmsr, MSR_GS_BASE, MSR_KERNEL_GS_BASE};
+use crate::constants::{MAX_CPUS, PAGE_SIZE};
+use crate::sync;
+use crate::PAGE_TABLE;
+
+extern "C" {
+    #[linkage = "external"] static __percpu_start: *const ();
+    #[linkage = "external"] static __percpu_end:   *const ();
+}
+
+// Area of the bootstrap processor, which needs its variables before the page allocator is up
+const BOOT_AREA_SIZE: usize = PAGE_SIZE;
+
+#[repr(C, align(64))]
+struct BootArea([u8; BOOT_AREA_SIZE]);
+
+static mut BOOT_AREA: BootArea = BootArea([0; BOOT_AREA_SIZE]);
+
+// Base of every CPU's area, zero until set up
+static mut AREAS: [usize; MAX_CPUS] = [0; MAX_CPUS];
+
+// Per-CPU variable. The static only holds the initial value, every CPU reads and writes its own copy
+pub struct PerCpu<T> {
+    template: UnsafeCell<T>
+}
+
+// Each CPU only ever touches its own copy
+unsafe impl<T> Sync for PerCpu<T> { }
+
+// Declare per-CPU variables, accessed through get, set and with
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$meta:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;)*) => {
+        $(
+            $(#[$meta])*
+            #[link_section = ".percpu"]
+            $vis static $name: $crate::percpu::PerCpu<$t> = $crate::percpu::PerCpu::new($init);
+        )*
+    };
+}
+
+percpu! {
+    // Base of this CPU's area, so variables can be found with a single GS relative load
+    static AREA_BASE: usize = 0;
+    static CPU_ID: usize = 0;
+    // Interrupt handlers currently running on this CPU
+    static IRQ_DEPTH: usize = 0;
+    // Sections that must not be preempted, nonzero keeps the scheduler off this CPU
+    static PREEMPT_COUNT: usize = 0;
+}
+
+// Offsets of the syscall entry stub's fields in every area, which it reads before it has a register to spare
+pub const SYSCALL_KERNEL_RSP: usize = 0;
+pub const SYSCALL_USER_RSP:   usize = 8;
+
+#[repr(C)]
+struct SyscallArea {
+    // Top of the running thread's kernel stack
+    kernel_rsp: usize,
+    // User stack pointer while the stub moves to the kernel stack
+    user_rsp: usize
+}
+
+// Placed first in the template by the linker script, so the fields are at the offsets above
+#[link_section = ".percpu.syscall"]
+static SYSCALL_AREA: PerCpu<SyscallArea> = PerCpu::new(SyscallArea { kernel_rsp: 0, user_rsp: 0 });
+
+fn template_start() -> usize {
+    unsafe { crate::symbol_addr(&__percpu_start) }
+}
+
+fn template_size() -> usize {
+    unsafe { crate::symbol_addr(&__percpu_end) - template_start() }
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(value: T) -> Self {
+        PerCpu { template: UnsafeCell::new(value) }
+    }
+
+    fn offset(&self) -> usize {
+        self.template.get() as usize - template_start()
+    }
+
+    // Pointer to this CPU's copy, only valid while the caller stays on this CPU
+    pub fn as_ptr(&self) -> *mut T {
+        unsafe { (read_gs_u64(AREA_BASE.offset()) as usize + self.offset()) as *mut T }
+    }
+
+    // Pointer to copy of another CPU, None if that CPU has no area yet
+    pub fn on(&self, cpu: usize) -> Option<*mut T> {
+        unsafe {
+            match (*addr_of!(AREAS)).get(cpu) {
+                Some(&base) if base != 0 => Some((base + self.offset()) as *mut T),
+                _ => None
+            }
+        }
+    }
+
+    // Access this CPU's copy with interrupts disabled, so no handler can access it at the same time
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let enabled = sync::save_and_disable_interrupts();
+        let r = f(unsafe { &mut *self.as_ptr() });
+        sync::restore_interrupts(enabled);
+        r
+    }
+}
+
+impl<T: Copy> PerCpu<T> {
+    pub fn get(&self) -> T {
+        self.with(|v| *v)
+    }
+
+    pub fn set(&self, value: T) {
+        self.with(|v| *v = value);
+    }
+}
+
+// Build area of CPU from the template, the bootstrap processor gets the static one. Returns false if it can't be allocated
+pub unsafe fn init(cpu: usize) -> bool {
+    if cpu >= MAX_CPUS || SYSCALL_AREA.offset() != SYSCALL_KERNEL_RSP {
+        return false
+    }
+
+    let size = template_size();
+    let base = match cpu {
+        0 => match size <= BOOT_AREA_SIZE {
+            true => core::ptr::addr_of_mut!(BOOT_AREA) as usize,
+            false => return false
+        },
+        _ => match PAGE_TABLE.lock().allocate_pages(None, size.div_ceil(PAGE_SIZE)) {
+            None => return false,
+            Some(p) => p as usize
+        }
+    };
+
+    core::ptr::copy_nonoverlapping(template_start() as *const u8, base as *mut u8, size);
+    AREAS[cpu] = base;
+    *AREA_BASE.on(cpu).unwrap() = base;
+    *CPU_ID.on(cpu).unwrap() = cpu;
+    true
+}
+
+// Point GS base of this CPU at the area of CPU, user mode starts out with a zero GS base
+pub unsafe fn load(cpu: usize) {
+    wrmsr(MSR_GS_BASE, AREAS[cpu] as u64);
+    wrmsr(MSR_KERNEL_GS_BASE, 0);
+}
+
+// Set kernel stack the syscall entry stub switches to on this CPU
+pub fn set_syscall_stack(top: usize) {
+    SYSCALL_AREA.with(|a| a.kernel_rsp = top);
+}
+
+pub fn cpu_id() -> usize {
+    unsafe { read_gs_u64(CPU_ID.offset()) as usize }
+}
+
+pub fn irq_depth() -> usize {
+    IRQ_DEPTH.get()
+}
+
+// Set depth of interrupt handlers, returning the old one. For switching threads inside a handler
+pub fn replace_irq_depth(depth: usize) -> usize {
+    IRQ_DEPTH.with(|d| core::mem::replace(d, depth))
+}
+
+pub fn in_interrupt() -> bool {
+    irq_depth() != 0
+}
+
+pub fn preempt_disable() {
+    PREEMPT_COUNT.with(|c| *c += 1);
+}
+
+pub fn preempt_enable() {
+    PREEMPT_COUNT.with(|c| *c -= 1);
+}
+
+pub fn preempt_count() -> usize {
+    PREEMPT_COUNT.get()
+}
+
+pub fn preemptible() -> bool {
+    PREEMPT_COUNT.get() == 0 && !in_interrupt()
+}
+
+// Kept alive for the duration of an interrupt handler. Entries from user mode swap in the kernel GS base
+// first and swap it back when dropped, right before the handler returns
+pub struct InterruptEntry {
+    from_user: bool
+}
+
+impl InterruptEntry {
+    // Takes CS of the interrupted code
+    pub fn new(cs: u64) -> Self {
+        let from_user = cs & 3 != 0;
+        if from_user {
+            unsafe {
+                swapgs();
+            }
+        }
+        IRQ_DEPTH.with(|d| *d += 1);
+        InterruptEntry { from_user }
+    }
+}
+
+impl Drop for InterruptEntry {
+    fn drop(&mut self) {
+        IRQ_DEPTH.with(|d| *d -= 1);
+        if self.from_user {
+            unsafe {
+                swapgs();
+            }
+        }
+    }
+}
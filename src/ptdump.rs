@@ -0,0 +1,186 @@
+use crate::address_space;
+use crate::console;
+use crate::memory::{self, MemoryKind};
+use crate::pager::*;
+use crate::vma::Backing;
+use crate::PAGE_TABLE;
+
+// Violations printed per check, the rest are only counted
+const MAX_REPORTS: usize = 16;
+
+// Entry bits that don't describe the mapping itself
+const IGNORED_FLAGS: u64 = PTE_ADDR_MASK | PTE_ACCESSED | PTE_DIRTY | PTE_HUGE;
+
+// Run of pages mapped contiguously both virtually and physically with identical flags and page size
+#[derive(Clone, Copy)]
+pub struct Run {
+    pub virt: usize,
+    pub phys: usize,
+    pub len: usize,
+    pub flags: u64,
+    pub size: PageSize
+}
+
+impl Run {
+    // Check if page directly follows run and can be added to it
+    fn extends(&self, virt: usize, phys: usize, flags: u64, size: PageSize) -> bool {
+        self.virt + self.len == virt
+            && self.phys + self.len == phys
+            && self.flags == flags
+            && self.size == size
+    }
+}
+
+// Call function for every run of present mappings in address space, merging adjacent pages
+pub unsafe fn for_each_run(pager: &Pager, f: &mut dyn FnMut(&Run)) {
+    let mut run: Option<Run> = None;
+    pager.walk_leaves(0, 512, &mut |virt, e, size| {
+        let phys  = entry_frame(*e, size) as usize;
+        let flags = *e & !IGNORED_FLAGS;
+        match run.as_mut() {
+            Some(r) if r.extends(virt, phys, flags, size) => r.len += size.bytes(),
+            _ => {
+                if let Some(r) = run {
+                    f(&r);
+                }
+                run = Some(Run { virt, phys, len: size.bytes(), flags, size });
+            }
+        }
+        true
+    });
+
+    if let Some(r) = run {
+        f(&r);
+    }
+}
+
+// Format length using the largest unit it is a multiple of
+fn size_parts(len: usize) -> (usize, &'static str) {
+    match len {
+        l if l % (1 << 30) == 0 => (l >> 30, "GiB"),
+        l if l % (1 << 20) == 0 => (l >> 20, "MiB"),
+        l => (l >> 10, "KiB")
+    }
+}
+
+// Print one line per run as `virt-range -> phys-range, size, flags`
+// Flags read r, w or -, x or -, u(ser) or k(ernel), g(lobal), o(wned), c(opy-on-write), n(o cache) and page size
+pub unsafe fn dump(pager: &Pager) {
+    let mut runs: usize = 0;
+    for_each_run(pager, &mut |r| {
+        let (amount, unit) = size_parts(r.len);
+        let bit = |flag: u64, c: char| match r.flags & flag {
+            0 => '-',
+            _ => c
+        };
+        console::write_fmt(format_args!("{:#018x}-{:#018x} -> {:#014x}-{:#014x}, {:>4} {}, r{}{}{}{}{}{}{} {}\n",
+            r.virt, r.virt + r.len, r.phys, r.phys + r.len, amount, unit,
+            bit(PTE_WRITABLE, 'w'),
+            match r.flags & PTE_NO_EXECUTE {
+                0 => 'x',
+                _ => '-'
+            },
+            match r.flags & PTE_USER {
+                0 => 'k',
+                _ => 'u'
+            },
+            bit(PTE_GLOBAL, 'g'),
+            bit(PTE_OWNED, 'o'),
+            bit(PTE_COW, 'c'),
+            bit(PTE_CACHE_DISABLE, 'n'),
+            match r.size {
+                PageSize::Size4K => "4K",
+                PageSize::Size2M => "2M",
+                PageSize::Size1G => "1G"
+            }));
+        runs += 1;
+    });
+
+    let stats = pager.page_stats();
+    console::write_fmt(format_args!("{} runs, {} 4 KiB, {} 2 MiB and {} 1 GiB pages\n",
+                                    runs, stats.pages_4k, stats.pages_2m, stats.pages_1g));
+}
+
+// Print areas of address space
+pub fn dump_vmas(pager: &Pager) {
+    for a in pager.vmas().iter() {
+        let (amount, unit) = size_parts(a.len());
+        console::write_fmt(format_args!("{:#018x}-{:#018x}, {:>4} {}, prot {:#x}, {}\n",
+                                        a.start, a.end, amount, unit, a.prot, a.backing.name()));
+    }
+    console::write_fmt(format_args!("{} areas\n", pager.vmas().len()));
+}
+
+// Name of checked address space, None for the kernel page table
+struct SpaceName(Option<usize>);
+
+impl core::fmt::Display for SpaceName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.0 {
+            None => write!(f, "kernel"),
+            Some(id) => write!(f, "address space {}", id)
+        }
+    }
+}
+
+fn report(name: &SpaceName, count: &mut usize, args: core::fmt::Arguments) {
+    if *count < MAX_REPORTS {
+        console::write_fmt(format_args!("{}: {}\n", name, args));
+    }
+    *count += 1;
+}
+
+// Check if physical address may be mapped: RAM, framebuffers, or device memory the address space asked for
+fn is_mappable(pager: &Pager, virt: usize, phys: usize) -> bool {
+    memory::is_ram(phys)
+        || memory::region_kind(phys) == Some(MemoryKind::Framebuffer)
+        || matches!(pager.vmas().find(virt).map(|a| a.backing), Some(Backing::Device { .. }))
+}
+
+// Verify invariants of address space, or kernel page table if no id provided, returns number of violations
+pub unsafe fn check(id: Option<usize>, pager: &Pager) -> usize {
+    let name = SpaceName(id);
+    let mut violations: usize = 0;
+
+    // Every address space must see exactly the kernel's kernel half. The kernel page table is locked by our caller
+    if id.is_some() {
+        let kernel_table = PAGE_TABLE.lock();
+        if let (Some(own), Some(kernel)) = (pager.pml4t(), kernel_table.pml4t()) {
+            for i in 256..512 {
+                if own[i] != kernel[i] {
+                    report(&name, &mut violations, format_args!("kernel half PML4 entry {} differs from kernel page table", i));
+                }
+            }
+        }
+    }
+
+    let nx = pager.nx_flag() != 0;
+    pager.walk_leaves(0, 512, &mut |virt, e, size| {
+        let phys = entry_frame(*e, size) as usize;
+        if !is_mappable(pager, virt, phys) || !is_mappable(pager, virt + size.bytes() - 1, phys + size.bytes() - 1) {
+            report(&name, &mut violations, format_args!("{:#x} maps {:#x} outside RAM", virt, phys));
+        }
+
+        if nx && *e & PTE_WRITABLE != 0 && *e & PTE_NO_EXECUTE == 0 {
+            report(&name, &mut violations, format_args!("{:#x} is writable and executable", virt));
+        }
+        true
+    });
+
+    if violations > MAX_REPORTS {
+        console::write_fmt(format_args!("{}: {} more violations\n", name, violations - MAX_REPORTS));
+    }
+    violations
+}
+
+// Check kernel page table and every address space, returns total number of violations
+pub unsafe fn check_all() -> usize {
+    let mut violations = check(None, &PAGE_TABLE.lock());
+    for id in address_space::ids() {
+        if let Some(mut s) = address_space::get(id) {
+            violations += check(Some(id), s.pager());
+        }
+    }
+    console::write_fmt(format_args!("page table check: {} violations\n", violations));
+    violations
+}
@@ -0,0 +1,72 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::asm_wrappers::{rdrand, rdseed, rdtsc};
+use crate::cpu::{self, Feature};
+
+// RDRAND and RDSEED may briefly run dry, Intel recommends retrying this often
+const HARDWARE_RETRIES: usize = 10;
+
+// SplitMix64 increment
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+static HAS_RDRAND: AtomicBool = AtomicBool::new(false);
+static HAS_RDSEED: AtomicBool = AtomicBool::new(false);
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+// Detect hardware generators and seed the mixing state from the best source available
+pub fn init() {
+    let rdrand_supported = cpu::has(Feature::Rdrand);
+    let rdseed_supported = cpu::has(Feature::Rdseed);
+    HAS_RDRAND.store(rdrand_supported, Ordering::Relaxed);
+    HAS_RDSEED.store(rdseed_supported, Ordering::Relaxed);
+
+    let mut state = unsafe { rdtsc() };
+    if let Some(s) = hardware(rdseed_supported, rdseed).or_else(|| hardware(rdrand_supported, rdrand)) {
+        state ^= s;
+    }
+    STATE.store(state, Ordering::Relaxed);
+}
+
+// Name of strongest entropy source in use
+pub fn source() -> &'static str {
+    match (HAS_RDSEED.load(Ordering::Relaxed), HAS_RDRAND.load(Ordering::Relaxed)) {
+        (true, _) => "RDSEED",
+        (false, true) => "RDRAND",
+        (false, false) => "TSC"
+    }
+}
+
+fn hardware(supported: bool, f: unsafe fn() -> Option<u64>) -> Option<u64> {
+    if !supported {
+        return None
+    }
+    (0..HARDWARE_RETRIES).find_map(|_| unsafe { f() })
+}
+
+// SplitMix64 output function, spreads weak seeds such as timestamps over all bits
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+// Get random value, not suitable for cryptography without a hardware generator
+pub fn next_u64() -> u64 {
+    // Timestamp jitter keeps values apart even without hardware support. Every caller advances the state
+    // once, so CPUs asking at the same time get different values
+    let jitter = unsafe { rdtsc() };
+    let step = |s: u64| (s ^ jitter).wrapping_add(GOLDEN_GAMMA);
+    let state = match STATE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |s| Some(step(s))) {
+        Ok(previous) | Err(previous) => step(previous)
+    };
+    let v = mix(state);
+    match hardware(HAS_RDRAND.load(Ordering::Relaxed), rdrand) {
+        None => v,
+        Some(r) => v ^ r
+    }
+}
+
+// Get random value below bound, which must not be zero
+pub fn below(bound: u64) -> u64 {
+    next_u64() % bound
+}
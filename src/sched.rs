@@ -0,0 +1,311 @@
+use core::cmp::Reverse;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::apic;
+use crate::console;
+use crate::constants::MAX_CPUS;
+use crate::percpu;
+use crate::smp;
+use crate::sync::SpinLock;
+use crate::thread::{self, MAX_THREADS};
+use crate::timer;
+
+// Scheduling class, a ready thread of a class always runs before any of the classes after it
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    RealTime,
+    Normal,
+    // Only runs when nothing else wants the CPU
+    Idle
+}
+
+const NUM_CLASSES: usize = 3;
+
+impl Priority {
+    // Timer ticks a thread runs before others of its class get a turn
+    pub const fn slice(self) -> u32 {
+        match self {
+            Priority::RealTime => 10,
+            Priority::Normal => 5,
+            Priority::Idle => 2
+        }
+    }
+
+    const fn class(self) -> usize {
+        self as usize
+    }
+
+    const fn from_class(class: usize) -> Self {
+        match class {
+            0 => Priority::RealTime,
+            1 => Priority::Normal,
+            _ => Priority::Idle
+        }
+    }
+
+    // Parse name as shown by Display
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "realtime" => Some(Priority::RealTime),
+            "normal" => Some(Priority::Normal),
+            "idle" => Some(Priority::Idle),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Priority::RealTime => "realtime",
+            Priority::Normal => "normal",
+            Priority::Idle => "idle"
+        })
+    }
+}
+
+// Affinity mask allowing every CPU
+pub const ALL_CPUS: u64 = !0;
+
+// Ready threads of one class in the order they became ready
+struct ClassQueue {
+    ids: [u16; MAX_THREADS],
+    head: usize,
+    len: usize
+}
+
+impl ClassQueue {
+    const fn new() -> Self {
+        ClassQueue { ids: [0; MAX_THREADS], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, id: usize) {
+        self.ids[(self.head + self.len) % MAX_THREADS] = id as u16;
+        self.len += 1;
+    }
+
+    // Remove first thread accepted by f, keeping the others in order
+    fn take(&mut self, f: impl Fn(usize) -> bool) -> Option<usize> {
+        let i = (0..self.len).find(|&i| f(self.ids[(self.head + i) % MAX_THREADS] as usize))?;
+        let id = self.ids[(self.head + i) % MAX_THREADS] as usize;
+        for j in i..self.len - 1 {
+            self.ids[(self.head + j) % MAX_THREADS] = self.ids[(self.head + j + 1) % MAX_THREADS];
+        }
+        self.len -= 1;
+        Some(id)
+    }
+
+    fn any(&self, f: impl Fn(usize) -> bool) -> bool {
+        (0..self.len).any(|i| f(self.ids[(self.head + i) % MAX_THREADS] as usize))
+    }
+}
+
+struct RunQueue {
+    classes: [ClassQueue; NUM_CLASSES]
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        RunQueue { classes: [ClassQueue::new(), ClassQueue::new(), ClassQueue::new()] }
+    }
+
+    fn len(&self) -> usize {
+        self.classes.iter().map(|c| c.len).sum()
+    }
+
+    // Remove most important thread accepted by f, of priority min or better
+    fn take(&mut self, min: Priority, f: impl Fn(usize) -> bool) -> Option<usize> {
+        self.classes[..=min.class()].iter_mut().find_map(|c| c.take(&f))
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_QUEUE: SpinLock<RunQueue> = SpinLock::new(RunQueue::new());
+
+// Taken with interrupts disabled, before the thread table if both are needed
+static RUN_QUEUES: [SpinLock<RunQueue>; MAX_CPUS] = [EMPTY_QUEUE; MAX_CPUS];
+
+// CPUs halted in their idle thread, woken with a reschedule IPI when work shows up
+static HALTED: AtomicU64 = AtomicU64::new(0);
+
+// Set once every CPU has its per-CPU area, from then on threads are preempted
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+pub struct CpuStats {
+    pub switches: u64,
+    pub busy_ticks: u64,
+    pub idle_ticks: u64,
+    // Threads taken from the run queue of another CPU
+    pub steals: u64
+}
+
+impl CpuStats {
+    const fn new() -> Self {
+        CpuStats { switches: 0, busy_ticks: 0, idle_ticks: 0, steals: 0 }
+    }
+}
+
+percpu! {
+    static STATS: CpuStats = CpuStats::new();
+    static NEED_RESCHED: bool = false;
+    // Class of thread running on this CPU, None while it runs its idle thread
+    static RUNNING: Option<Priority> = None;
+}
+
+pub fn started() -> bool {
+    STARTED.load(Ordering::Acquire)
+}
+
+// Start preempting threads on every CPU
+pub fn start() {
+    STARTED.store(true, Ordering::Release);
+}
+
+fn load(cpu: usize) -> usize {
+    let running = unsafe { RUNNING.on(cpu).map(|r| (*r).is_some()).unwrap_or(false) };
+    RUN_QUEUES[cpu].lock_irqsave().len() + running as usize
+}
+
+// Pick CPU for thread becoming ready: the one it last ran on unless another allowed CPU is less loaded
+fn select_cpu(affinity: u64, last_cpu: usize) -> Option<usize> {
+    let allowed = affinity & smp::online_mask();
+    let mut best: Option<(usize, usize)> = None;
+    for cpu in (0..MAX_CPUS).filter(|c| allowed & (1 << c) != 0) {
+        let l = load(cpu);
+        match best {
+            Some((_, b)) if b < l || (b == l && cpu != last_cpu) => { },
+            _ => best = Some((cpu, l))
+        }
+    }
+    best.map(|(cpu, _)| cpu)
+}
+
+// Queue ready thread on a CPU its affinity allows and make that CPU look at it
+pub fn enqueue(id: usize, priority: Priority, affinity: u64, last_cpu: usize) {
+    let cpu = match select_cpu(affinity, last_cpu) {
+        None => smp::current_cpu(),
+        Some(c) => c
+    };
+    RUN_QUEUES[cpu].lock_irqsave().classes[priority.class()].push(id);
+
+    let running = unsafe { RUNNING.on(cpu).map(|r| *r).unwrap_or(None) };
+    let preempts = match running {
+        None => true,
+        Some(p) => priority < p
+    };
+    if cpu == smp::current_cpu() {
+        if preempts {
+            NEED_RESCHED.set(true);
+        }
+    } else if HALTED.load(Ordering::SeqCst) & (1 << cpu) != 0 || preempts {
+        if let Some(r) = NEED_RESCHED.on(cpu) {
+            unsafe {
+                core::ptr::write_volatile(r, true);
+            }
+        }
+        if let Some(apic_id) = smp::apic_id(cpu) {
+            apic::send_ipi(apic_id, apic::VECTOR_RESCHEDULE);
+        }
+    }
+}
+
+// Take thread from the most loaded CPU whose queue holds one allowed to run here
+fn steal(cpu: usize, min: Priority) -> Option<usize> {
+    let mut victims: [(usize, usize); MAX_CPUS] = [(0, 0); MAX_CPUS];
+    let mut n: usize = 0;
+    for other in (0..MAX_CPUS).filter(|&c| c != cpu && smp::online_mask() & (1 << c) != 0) {
+        let len = RUN_QUEUES[other].lock_irqsave().len();
+        if len != 0 {
+            victims[n] = (len, other);
+            n += 1;
+        }
+    }
+    victims[..n].sort_unstable_by_key(|&(len, _)| Reverse(len));
+
+    let id = victims[..n].iter().find_map(|&(_, other)| {
+        RUN_QUEUES[other].lock_irqsave().take(min, |id| thread::affinity(id) & (1 << cpu) != 0)
+    })?;
+    STATS.with(|s| s.steals += 1);
+    Some(id)
+}
+
+// Take next thread for this CPU of priority min or better, stealing one if none is queued here
+pub fn pick_next(min: Priority) -> Option<usize> {
+    let cpu = smp::current_cpu();
+    let own = RUN_QUEUES[cpu].lock_irqsave().take(min, |_| true);
+    own.or_else(|| steal(cpu, min))
+}
+
+// Check if this CPU's idle thread has anything to run, here or on a CPU to steal from
+pub fn has_work() -> bool {
+    let cpu = smp::current_cpu();
+    if RUN_QUEUES[cpu].lock_irqsave().len() != 0 {
+        return true
+    }
+
+    (0..MAX_CPUS).filter(|&c| c != cpu && smp::online_mask() & (1 << c) != 0).any(|c| {
+        RUN_QUEUES[c].lock_irqsave().classes.iter().any(|q| q.any(|id| thread::affinity(id) & (1 << cpu) != 0))
+    })
+}
+
+pub fn set_halted(halted: bool) {
+    let bit = 1 << smp::current_cpu();
+    match halted {
+        true => HALTED.fetch_or(bit, Ordering::SeqCst),
+        false => HALTED.fetch_and(!bit, Ordering::SeqCst)
+    };
+}
+
+// Record switch to thread of priority, None for the idle thread
+pub fn switched(priority: Option<Priority>) {
+    RUNNING.set(priority);
+    NEED_RESCHED.set(false);
+    STATS.with(|s| s.switches += 1);
+}
+
+// Account timer tick to running thread, asking for a reschedule once its slice is used up
+pub fn tick() {
+    let busy = RUNNING.get().is_some();
+    STATS.with(|s| match busy {
+        true => s.busy_ticks += 1,
+        false => s.idle_ticks += 1
+    });
+    if thread::tick() {
+        NEED_RESCHED.set(true);
+    }
+}
+
+// Switch threads at the end of an interrupt if the running one should give way. Only the outermost
+// handler does, and not while the interrupted code has preemption disabled
+pub fn preempt_from_interrupt() {
+    if !started() || !NEED_RESCHED.get() || percpu::irq_depth() != 1 || percpu::preempt_count() != 0 {
+        return
+    }
+
+    // The thread switched to isn't in this handler, its own depth comes back with it
+    let depth = percpu::replace_irq_depth(0);
+    NEED_RESCHED.set(false);
+    thread::reschedule_from_interrupt();
+    percpu::replace_irq_depth(depth);
+}
+
+pub fn print_stats() {
+    console::write_fmt(format_args!("cpu  switches  busy ms  idle ms  steals  queued\n"));
+    for cpu in (0..MAX_CPUS).filter(|&c| smp::online_mask() & (1 << c) != 0) {
+        let s = match STATS.on(cpu) {
+            None => continue,
+            Some(s) => unsafe { core::ptr::read_volatile(s) }
+        };
+        let queued = RUN_QUEUES[cpu].lock_irqsave().len();
+        console::write_fmt(format_args!("{:<4} {:<9} {:<8} {:<8} {:<7} {}\n", cpu, s.switches,
+                                        s.busy_ticks * 1000 / timer::TICK_HZ, s.idle_ticks * 1000 / timer::TICK_HZ,
+                                        s.steals, queued));
+    }
+    for class in 0..NUM_CLASSES {
+        let p = Priority::from_class(class);
+        let queued: usize = (0..MAX_CPUS).map(|c| RUN_QUEUES[c].lock_irqsave().classes[class].len).sum();
+        console::write_fmt(format_args!("{} threads queued: {}\n", p, queued));
+    }
+}
@@ -0,0 +1,814 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::address_space;
+use crate::asm_wrappers::{read_cr0, read_rflags, Cr0, RFLAGS_IF};
+use crate::cmdline::{self, Cmdline};
+use crate::constants::*;
+use crate::console::{self, LogLevel};
+use crate::elf::{self, ElfError};
+use crate::fpu;
+use crate::frame_allocator::FRAME_ALLOCATOR;
+use crate::kaslr;
+use crate::memory;
+use crate::percpu;
+use crate::ptdump;
+use crate::random;
+use crate::stack::{self, StackOwner};
+use crate::sync::{self, Once, RwSpinLock, SpinLock};
+use crate::sched::{Priority, ALL_CPUS};
+use crate::smp;
+use crate::softirq::Tasklet;
+use crate::thread;
+use crate::timer;
+use crate::user;
+use crate::usercopy::{self, UserCopyError};
+use crate::vma::{self, Backing, Vma, VmaTable};
+use crate::wait::{Mutex, Semaphore, WaitQueue};
+use crate::workqueue::{self, DelayedWork, Work};
+use crate::PAGE_TABLE;
+
+// Boot-time self-test, returns whether it passed
+struct SelfTest {
+    name: &'static str,
+    run: fn() -> bool
+}
+
+const TESTS: &[SelfTest] = &[
+    SelfTest { name: "cmdline::parse",        run: test_cmdline_parse },
+    SelfTest { name: "cmdline::parse_size",   run: test_cmdline_parse_size },
+    SelfTest { name: "cmdline::options",      run: test_cmdline_options },
+    SelfTest { name: "pager::allocate_page",  run: test_pager_allocate_page },
+    SelfTest { name: "address_space::clone",  run: test_address_space_clone },
+    SelfTest { name: "address_space::fork",   run: test_address_space_fork },
+    SelfTest { name: "vma::table",            run: test_vma_table },
+    SelfTest { name: "pager::mmap",           run: test_pager_mmap },
+    SelfTest { name: "ptdump::check",         run: test_ptdump_check },
+    SelfTest { name: "stack::allocate",       run: test_stack_allocate },
+    SelfTest { name: "random::next_u64",      run: test_random_next },
+    SelfTest { name: "kaslr::layout",         run: test_kaslr_layout },
+    SelfTest { name: "usercopy::round_trip",  run: test_usercopy_round_trip },
+    SelfTest { name: "fpu::kernel_fpu",       run: test_kernel_fpu },
+    SelfTest { name: "percpu::variable",      run: test_percpu_variable },
+    SelfTest { name: "sync::locks",           run: test_sync_locks },
+    SelfTest { name: "thread::spawn_join",    run: test_thread_spawn_join },
+    SelfTest { name: "sched::affinity",       run: test_sched_affinity },
+    SelfTest { name: "thread::set_affinity",  run: test_thread_set_affinity },
+    SelfTest { name: "thread::sleep",         run: test_thread_sleep },
+    SelfTest { name: "wait::queue",           run: test_wait_queue },
+    SelfTest { name: "wait::semaphore",       run: test_wait_semaphore },
+    SelfTest { name: "wait::mutex",           run: test_wait_mutex },
+    SelfTest { name: "softirq::tasklet",      run: test_softirq_tasklet },
+    SelfTest { name: "workqueue::queue",      run: test_workqueue_queue },
+    SelfTest { name: "user::demo",            run: test_user_demo },
+    SelfTest { name: "elf::validate",         run: test_elf_validate },
+    SelfTest { name: "elf::load",             run: test_elf_load }
+];
+
+fn test_cmdline_parse() -> bool {
+    let c = Cmdline::parse("  nosmp  loglevel=debug title=\"deimos kernel\" empty= ");
+    c.iter().count() == 4
+        && c.has_flag("nosmp")
+        && c.get("loglevel") == Some("debug")
+        && c.get("title") == Some("deimos kernel")
+        && c.get("empty") == Some("")
+        && c.get("missing").is_none()
+}
+
+fn test_cmdline_parse_size() -> bool {
+    cmdline::parse_size("4096")    == Some(4096)
+        && cmdline::parse_size("16K")  == Some(16 * 1024)
+        && cmdline::parse_size("512M") == Some(512 * 1024 * 1024)
+        && cmdline::parse_size("2G")   == Some(2 * 1024 * 1024 * 1024)
+        && cmdline::parse_size("0x1000") == Some(0x1000)
+        && cmdline::parse_size("lots").is_none()
+        && cmdline::parse_size("").is_none()
+}
+
+fn test_cmdline_options() -> bool {
+    let o = Cmdline::parse("loglevel=warn console=serial,fb mem=1G nosmp=0 selftest").kernel_options();
+    o.log_level == LogLevel::Warn
+        && o.consoles == cmdline::CONSOLE_SERIAL | cmdline::CONSOLE_FRAMEBUFFER
+        && o.mem_limit == Some(1024 * 1024 * 1024)
+        && !o.nosmp
+        && o.selftest
+}
+
+// Intermediate tables outlive the pages they map, so create the heap's first ones before counting free frames
+unsafe fn warm_up_heap_tables() {
+    let mut pt = PAGE_TABLE.lock();
+    if let Some(p) = pt.allocate_page(None) {
+        pt.deallocate_page(Some(p));
+    }
+}
+
+// Pagers take the frame allocator's lock themselves, so never hold it across pager calls
+fn free_frames() -> usize {
+    FRAME_ALLOCATOR.lock().free_frames()
+}
+
+fn ref_count(frame: *const ()) -> usize {
+    FRAME_ALLOCATOR.lock().ref_count(Some(frame))
+}
+
+fn test_pager_allocate_page() -> bool {
+    unsafe {
+        warm_up_heap_tables();
+        let free_before = free_frames();
+        let mut pt = PAGE_TABLE.lock();
+        let p = match pt.allocate_page(None) {
+            None => return false,
+            Some(p) => p
+        };
+
+        // Page must be writable and visible through the direct map at its physical address
+        *(p as *mut u64) = 0xDEADBEEF;
+        let paddr = pt.as_phys_addr(Some(p));
+        let seen  = match paddr {
+            None => 0,
+            Some(pa) => *(memory::phys_to_virt(pa) as *const u64)
+        };
+
+        let freed = pt.deallocate_page(Some(p)).is_some() && !pt.is_virtually_allocated(Some(p));
+        drop(pt);
+        freed
+            && seen == 0xDEADBEEF
+            && memory::virt_to_phys(memory::phys_to_virt(paddr.unwrap())) == paddr
+            && free_frames() == free_before
+    }
+}
+
+fn test_address_space_clone() -> bool {
+    unsafe {
+        let free_before = free_frames();
+        let parent = match address_space::create() {
+            None => return false,
+            Some(id) => id
+        };
+
+        // Kernel half is shared, so the new top level table must mirror it
+        let shared = {
+            let mut space = address_space::get(parent).unwrap();
+            let kernel_table = PAGE_TABLE.lock();
+            match (space.pager().pml4t(), kernel_table.pml4t()) {
+                (Some(u), Some(k)) => u[256..] == k[256..] && u[..256].iter().all(|&e| e == 0),
+                _ => false
+            }
+        };
+
+        let vaddr = 0x40_0000 as *const ();
+        let frame = {
+            let mut space = address_space::get(parent).unwrap();
+            let pager = space.pager();
+            pager.allocate_page(Some(vaddr)).and_then(|v| pager.as_phys_addr(Some(v)))
+        };
+        let frame = match frame {
+            None => {
+                address_space::destroy(parent);
+                return false
+            },
+            Some(p) => p
+        };
+        *(memory::phys_to_virt(frame) as *mut u64) = 0x1234_5678;
+
+        // The child gets its own frame holding the same contents
+        let child = address_space::clone(parent);
+        let copied = match child.and_then(|c| address_space::get(c).unwrap().pager().as_phys_addr(Some(vaddr))) {
+            Some(f) if f != frame => *(memory::phys_to_virt(f) as *const u64) == 0x1234_5678 && ref_count(frame) == 1,
+            _ => false
+        };
+        let destroyed = match child {
+            None => false,
+            Some(c) => address_space::destroy(c)
+        };
+
+        address_space::destroy(parent)
+            && shared
+            && copied
+            && destroyed
+            && address_space::get(parent).is_none()
+            && free_frames() == free_before
+    }
+}
+
+fn test_address_space_fork() -> bool {
+    unsafe {
+        let free_before = free_frames();
+        let parent = match address_space::create() {
+            None => return false,
+            Some(id) => id
+        };
+
+        let vaddr = 0x40_0000 as *const ();
+        let frame = {
+            let mut space = address_space::get(parent).unwrap();
+            let pager = space.pager();
+            pager.allocate_page(Some(vaddr)).and_then(|v| pager.as_phys_addr(Some(v)))
+        };
+        let frame = match frame {
+            None => {
+                address_space::destroy(parent);
+                return false
+            },
+            Some(p) => p
+        };
+        *(memory::phys_to_virt(frame) as *mut u64) = 1;
+
+        let child = match address_space::fork(parent) {
+            None => {
+                address_space::destroy(parent);
+                return false
+            },
+            Some(id) => id
+        };
+        let shared = ref_count(frame) == 2
+            && address_space::get(child).unwrap().pager().as_phys_addr(Some(vaddr)) == Some(frame);
+
+        // Writing in the parent must fault, copy the frame and leave the child's view untouched
+        address_space::switch_to(parent);
+        *(vaddr as *mut u64) = 2;
+        let written = *(vaddr as *const u64) == 2;
+        address_space::switch_to_kernel();
+
+        let copied = address_space::get(parent).unwrap().pager().as_phys_addr(Some(vaddr)) != Some(frame)
+            && *(memory::phys_to_virt(frame) as *const u64) == 1
+            && ref_count(frame) == 1;
+
+        address_space::destroy(child);
+        address_space::destroy(parent);
+        shared && written && copied && free_frames() == free_before
+    }
+}
+
+fn test_vma_table() -> bool {
+    let rw = vma::VMA_READ | vma::VMA_WRITE;
+    let mut t = VmaTable::new();
+    let ok = t.insert(Vma { start: 0x1000, end: 0x3000, prot: rw, backing: Backing::Anonymous })
+        // Adjacent compatible areas are joined, overlapping ones are refused
+        && t.insert(Vma { start: 0x3000, end: 0x5000, prot: rw, backing: Backing::Anonymous })
+        && !t.insert(Vma { start: 0x4000, end: 0x6000, prot: rw, backing: Backing::Anonymous })
+        && t.len() == 1
+        && t.protect(0x2000, 0x3000, vma::VMA_READ)
+        && t.len() == 3
+        && t.find(0x2800).map(|a| a.prot) == Some(vma::VMA_READ)
+        && t.protect(0x2000, 0x3000, rw)
+        && t.len() == 1
+        && t.remove(0x2000, 0x4000)
+        && t.len() == 2
+        && t.find_gap(0x2000, 0x1000, 0x10000) == Some(0x2000)
+        && t.find_gap(0x3000, 0x1000, 0x10000) == Some(0x5000)
+        && t.find_gap(0x3000, 0x1000, 0x7000).is_none();
+    ok && t.covers(0x1000, 0x2000) && !t.covers(0x1000, 0x5000)
+}
+
+fn test_pager_mmap() -> bool {
+    unsafe {
+        warm_up_heap_tables();
+        let free_before = free_frames();
+        let p = match PAGE_TABLE.lock().mmap(None, 4 * PAGE_SIZE, vma::VMA_READ | vma::VMA_WRITE, Backing::Anonymous) {
+            None => return false,
+            Some(p) => p
+        };
+
+        // Nothing is mapped until first touched, then only the touched page is. The fault handler
+        // takes the kernel page table's lock, so it must not be held while touching the area
+        let lazy = !PAGE_TABLE.lock().is_virtually_allocated(Some(p));
+        *((p as usize + PAGE_SIZE) as *mut u64) = 0xC0FFEE;
+        let mut pt = PAGE_TABLE.lock();
+        let populated = pt.is_virtually_allocated(Some((p as usize + PAGE_SIZE) as *const ()))
+            && !pt.is_virtually_allocated(Some(p))
+            && *((p as usize + PAGE_SIZE) as *const u64) == 0xC0FFEE;
+
+        let protected = pt.mprotect(p, 2 * PAGE_SIZE, vma::VMA_READ).is_some()
+            && pt.vmas().find(p as usize).map(|a| a.prot) == Some(vma::VMA_READ);
+
+        let unmapped = pt.munmap(p, 4 * PAGE_SIZE).is_some() && pt.vmas().find(p as usize).is_none();
+        drop(pt);
+        unmapped
+            && lazy
+            && populated
+            && protected
+            && free_frames() == free_before
+    }
+}
+
+fn test_ptdump_check() -> bool {
+    unsafe { ptdump::check(None, &PAGE_TABLE.lock()) == 0 }
+}
+
+fn test_stack_allocate() -> bool {
+    let s = match stack::allocate(StackOwner::Thread(usize::MAX)) {
+        None => return false,
+        Some(s) => s
+    };
+
+    // Stack itself is mapped, the pages around it aren't
+    let pt = PAGE_TABLE.lock();
+    let mapped = pt.is_virtually_allocated(Some(s.bottom() as *const ()))
+        && pt.is_virtually_allocated(Some((s.top() - PAGE_SIZE) as *const ()))
+        && !pt.is_virtually_allocated(Some((s.bottom() - PAGE_SIZE) as *const ()))
+        && !pt.is_virtually_allocated(Some(s.top() as *const ()));
+    drop(pt);
+    let guarded = stack::guard_owner(s.bottom() - 8) == Some(StackOwner::Thread(usize::MAX))
+        && stack::guard_owner(s.bottom()).is_none();
+
+    // Overflows are blamed on whoever the stack was handed to
+    stack::set_owner(&s, StackOwner::Cpu(usize::MAX));
+    let handed_over = s.owner() == Some(StackOwner::Cpu(usize::MAX))
+        && stack::guard_owner(s.bottom() - 8) == Some(StackOwner::Cpu(usize::MAX))
+        && stack::containing(s.top() - 8).map(|c| c.bottom()) == Some(s.bottom());
+
+    stack::free(s);
+    mapped && guarded && handed_over && stack::guard_owner(s.bottom() - 8).is_none()
+}
+
+fn test_random_next() -> bool {
+    let a = random::next_u64();
+    let b = random::next_u64();
+    a != b && (0..64).all(|_| random::below(10) < 10)
+}
+
+fn test_kaslr_layout() -> bool {
+    let aligned = |base: usize| base.is_multiple_of(kaslr::SLIDE_ALIGN);
+    let heap  = kaslr::heap_base();
+    let stack = kaslr::stack_base();
+    match kaslr::enabled() {
+        false => heap == KERNEL_HEAP_BASE && stack == KERNEL_STACK_BASE,
+        true => {
+            let direct = kaslr::direct_map_offset();
+            aligned(direct) && aligned(heap) && aligned(stack)
+                && (DIRECT_MAP_WINDOW_BASE..KERNEL_HEAP_BASE).contains(&direct)
+                && heap >= KERNEL_HEAP_BASE && heap + VMEM_MAX <= KERNEL_STACK_BASE
+                && stack >= KERNEL_STACK_BASE && stack + stack::REGION_SIZE <= KERNEL_STACK_END
+        }
+    }
+}
+
+fn test_usercopy_round_trip() -> bool {
+    unsafe {
+        let id = match address_space::create() {
+            None => return false,
+            Some(id) => id
+        };
+        let user = vma::VMA_READ | vma::VMA_USER;
+        let areas = {
+            let mut space = address_space::get(id).unwrap();
+            let pager = space.pager();
+            (pager.mmap(None, PAGE_SIZE, user | vma::VMA_WRITE, Backing::Anonymous),
+             pager.mmap(None, PAGE_SIZE, user, Backing::Anonymous))
+        };
+        let (rw, ro) = match areas {
+            (Some(rw), Some(ro)) => (rw as usize, ro as usize),
+            _ => {
+                address_space::destroy(id);
+                return false
+            }
+        };
+
+        address_space::switch_to(id);
+        let data = *b"deimos";
+        let mut back = [0u8; 6];
+        // Pages are populated by the copy itself, never by a fault in kernel mode
+        let copied = usercopy::copy_to_user(rw + PAGE_SIZE - 3, &data).is_err()
+            && usercopy::copy_to_user(rw + 8, &data).is_ok()
+            && usercopy::copy_from_user(&mut back, rw + 8).is_ok()
+            && back == data;
+        let refused = usercopy::copy_to_user(ro, &data) == Err(UserCopyError::NotMapped)
+            && usercopy::copy_from_user(&mut back, ro).is_ok()
+            && usercopy::copy_from_user(&mut back, KERNEL_HALF_BASE) == Err(UserCopyError::OutOfRange)
+            && usercopy::copy_from_user(&mut back, usize::MAX - 2) == Err(UserCopyError::OutOfRange);
+        address_space::switch_to_kernel();
+
+        address_space::destroy(id);
+        copied && refused && usercopy::copy_from_user(&mut back, rw) == Err(UserCopyError::NoAddressSpace)
+    }
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn sse2_add(a: [i32; 4], b: [i32; 4]) -> [i32; 4] {
+    use core::arch::x86_64::{__m128i, _mm_add_epi32, _mm_loadu_si128, _mm_storeu_si128};
+    let mut out = [0i32; 4];
+    let sum = _mm_add_epi32(_mm_loadu_si128(a.as_ptr() as *const __m128i), _mm_loadu_si128(b.as_ptr() as *const __m128i));
+    _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, sum);
+    out
+}
+
+fn test_kernel_fpu() -> bool {
+    let guard = fpu::kernel_fpu_begin();
+    let sum = unsafe { sse2_add([1, 2, 3, 4], [10, 20, 30, 40]) };
+    fpu::kernel_fpu_end(guard);
+
+    // Anything after the guard is gone must trap again
+    sum == [11, 22, 33, 44] && unsafe { read_cr0().contains(Cr0::TS) }
+}
+
+percpu! {
+    static TEST_COUNTER: u64 = 7;
+}
+
+fn test_percpu_variable() -> bool {
+    // Template value comes along, writes only reach this CPU's copy
+    let initial = TEST_COUNTER.get() == 7;
+    TEST_COUNTER.with(|c| *c += 1);
+    let ok = initial
+        && TEST_COUNTER.get() == 8
+        && TEST_COUNTER.on(percpu::cpu_id()) == Some(TEST_COUNTER.as_ptr())
+        && !core::ptr::addr_eq(TEST_COUNTER.as_ptr(), &TEST_COUNTER);
+
+    // Leave it as found so the shell can run this again
+    TEST_COUNTER.set(7);
+    ok
+}
+
+fn test_sync_locks() -> bool {
+    let lock = SpinLock::new(1);
+    let exclusive = {
+        let mut guard = lock.lock();
+        *guard += 1;
+        lock.is_locked() && lock.try_lock().is_none()
+    };
+
+    // IRQ-saving guard must put the interrupt flag back the way it found it
+    let enabled = unsafe { read_rflags() & RFLAGS_IF != 0 };
+    let irqsave = {
+        let guard = lock.lock_irqsave();
+        *guard == 2 && unsafe { read_rflags() & RFLAGS_IF == 0 }
+    };
+    let restored = unsafe { (read_rflags() & RFLAGS_IF != 0) == enabled };
+
+    let rw = RwSpinLock::new(3);
+    let readers = {
+        let a = rw.read();
+        let b = rw.read();
+        *a + *b == 6
+    };
+    *rw.write() += 1;
+
+    let once = Once::new();
+    let set = once.get().is_none() && once.set(5) && !once.set(6) && *once.call_once(|| 7) == 5;
+
+    exclusive && irqsave && restored && !lock.is_locked() && readers && *rw.read() == 4 && set
+}
+
+static THREAD_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_thread_run() {
+    thread::yield_now();
+    THREAD_RUNS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn test_thread_spawn_join() -> bool {
+    THREAD_RUNS.store(0, Ordering::SeqCst);
+    let ids = [thread::spawn(count_thread_run), thread::spawn(count_thread_run)];
+    let joined = ids.iter().all(|id| match id {
+        None => false,
+        Some(id) => thread::join(*id)
+    });
+
+    // The stack we run on is ours, even if it was a CPU's before being adopted
+    let here = 0u8;
+    let own_stack = stack::containing(core::ptr::addr_of!(here) as usize).and_then(|s| s.owner())
+        == thread::current().map(StackOwner::Thread);
+
+    // Joined threads give back their slot
+    joined && own_stack && THREAD_RUNS.load(Ordering::SeqCst) == 2
+        && ids.iter().all(|id| thread::state(id.unwrap()).is_none())
+}
+
+static PINNED_CPU: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+fn record_cpu() {
+    PINNED_CPU.store(smp::current_cpu(), Ordering::SeqCst);
+}
+
+fn test_sched_affinity() -> bool {
+    // Threads only run where their affinity allows, and need an online CPU there
+    let cpu = smp::online_mask().trailing_zeros() as usize;
+    let mut ran_there = true;
+    for priority in [Priority::RealTime, Priority::Normal, Priority::Idle] {
+        PINNED_CPU.store(usize::MAX, Ordering::SeqCst);
+        ran_there &= match thread::spawn_with(record_cpu, priority, 1 << cpu) {
+            None => false,
+            Some(id) => thread::join(id) && PINNED_CPU.load(Ordering::SeqCst) == cpu
+        };
+    }
+    ran_there && thread::spawn_with(record_cpu, Priority::Normal, !smp::online_mask()).is_none()
+}
+
+static RELEASE_FLAG: AtomicBool = AtomicBool::new(false);
+static RELEASE_QUEUE: WaitQueue = WaitQueue::new();
+
+fn record_cpu_when_released() {
+    RELEASE_QUEUE.wait_until(None, || RELEASE_FLAG.load(Ordering::SeqCst));
+    record_cpu();
+}
+
+fn test_thread_set_affinity() -> bool {
+    RELEASE_FLAG.store(false, Ordering::SeqCst);
+    PINNED_CPU.store(usize::MAX, Ordering::SeqCst);
+    let cpu = smp::online_mask().trailing_zeros() as usize;
+    let id = match thread::spawn(record_cpu_when_released) {
+        None => return false,
+        Some(id) => id
+    };
+
+    // Changes stick to a waiting thread, which runs under them once woken
+    let priority = thread::set_priority(id, Priority::Idle) && thread::priority(id) == Some(Priority::Idle);
+    let affinity = thread::set_affinity(id, 1 << cpu) && thread::affinity(id) == 1 << cpu
+        && !thread::set_affinity(id, !smp::online_mask());
+    RELEASE_FLAG.store(true, Ordering::SeqCst);
+    RELEASE_QUEUE.wake_all();
+    let ran_there = thread::join(id) && PINNED_CPU.load(Ordering::SeqCst) == cpu;
+
+    let missing = !thread::set_priority(id, Priority::Normal) && !thread::set_affinity(id, ALL_CPUS);
+    priority && affinity && ran_there && missing
+}
+
+fn test_thread_sleep() -> bool {
+    // Without a timer sleep returns at once
+    if !timer::running() {
+        return true
+    }
+    let start = timer::ticks();
+    thread::sleep(Duration::from_millis(30));
+    timer::ticks() - start >= timer::TICK_HZ * 30 / 1000
+}
+
+static WAIT_FLAG: AtomicBool = AtomicBool::new(false);
+static WAIT_QUEUE: WaitQueue = WaitQueue::new();
+
+fn wait_for_flag() {
+    WAIT_QUEUE.wait_until(None, || WAIT_FLAG.load(Ordering::SeqCst));
+    THREAD_RUNS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn test_wait_queue() -> bool {
+    WAIT_FLAG.store(false, Ordering::SeqCst);
+    THREAD_RUNS.store(0, Ordering::SeqCst);
+    let id = match thread::spawn(wait_for_flag) {
+        None => return false,
+        Some(id) => id
+    };
+    // The waiting thread can't be joined yet
+    let waited = !thread::join_timeout(id, Some(Duration::from_millis(10))) && THREAD_RUNS.load(Ordering::SeqCst) == 0;
+    WAIT_FLAG.store(true, Ordering::SeqCst);
+    WAIT_QUEUE.wake_all();
+    let woken = thread::join(id) && THREAD_RUNS.load(Ordering::SeqCst) == 1;
+
+    // A condition that never holds times out, as long as there is a timer to do so
+    let timed_out = !WAIT_QUEUE.wait_until(Some(Duration::from_millis(20)), || false);
+    (waited || !timer::running()) && woken && timed_out
+}
+
+static SEMAPHORE: Semaphore = Semaphore::new(0);
+
+fn semaphore_up() {
+    thread::sleep(Duration::from_millis(10));
+    SEMAPHORE.up();
+}
+
+fn test_wait_semaphore() -> bool {
+    let id = match thread::spawn(semaphore_up) {
+        None => return false,
+        Some(id) => id
+    };
+    let down = SEMAPHORE.down(None);
+    let joined = thread::join(id);
+    down && joined && SEMAPHORE.count() == 0 && !SEMAPHORE.down(Some(Duration::from_millis(10)))
+}
+
+static MUTEX_COUNTER: Mutex<usize> = Mutex::new(0);
+
+fn mutex_increment() {
+    for _ in 0..100 {
+        let mut counter = MUTEX_COUNTER.lock();
+        let value = *counter;
+        thread::yield_now();
+        *counter = value + 1;
+    }
+}
+
+fn test_wait_mutex() -> bool {
+    *MUTEX_COUNTER.lock() = 0;
+    let ids = [thread::spawn(mutex_increment), thread::spawn(mutex_increment), thread::spawn(mutex_increment)];
+    let joined = ids.iter().all(|id| match id {
+        None => false,
+        Some(id) => thread::join(*id)
+    });
+    let held = MUTEX_COUNTER.lock();
+    joined && *held == 300 && MUTEX_COUNTER.try_lock().is_none()
+}
+
+static DEFERRED_RUNS: AtomicUsize = AtomicUsize::new(0);
+static DEFERRED_DONE: WaitQueue = WaitQueue::new();
+
+fn count_deferred_run(_arg: usize) {
+    DEFERRED_RUNS.fetch_add(1, Ordering::SeqCst);
+    DEFERRED_DONE.wake_all();
+}
+
+fn wait_deferred_runs(runs: usize) -> bool {
+    DEFERRED_DONE.wait_until(Some(Duration::from_millis(200)), || DEFERRED_RUNS.load(Ordering::SeqCst) >= runs)
+}
+
+static TASKLET: Tasklet = Tasklet::new(count_deferred_run, 0);
+
+fn test_softirq_tasklet() -> bool {
+    // Tasklets run when an interrupt exits, which without a timer may be never
+    if !timer::running() {
+        return true
+    }
+    DEFERRED_RUNS.store(0, Ordering::SeqCst);
+
+    // Scheduling twice before it ran runs it once
+    let enabled = sync::save_and_disable_interrupts();
+    let scheduled = TASKLET.schedule() && !TASKLET.schedule();
+    sync::restore_interrupts(enabled);
+    scheduled && wait_deferred_runs(1) && !TASKLET.is_scheduled() && DEFERRED_RUNS.load(Ordering::SeqCst) == 1
+}
+
+static WORK: Work = Work::new(count_deferred_run, 0);
+static DELAYED_WORK: DelayedWork = DelayedWork::new(count_deferred_run, 0);
+static SLOW_WORK: Work = Work::new(sleep_deferred_run, 0);
+
+fn sleep_deferred_run(_arg: usize) {
+    thread::sleep(Duration::from_millis(30));
+}
+
+fn test_workqueue_queue() -> bool {
+    DEFERRED_RUNS.store(0, Ordering::SeqCst);
+    let queued = workqueue::queue_work(&WORK);
+    workqueue::SYSTEM.flush();
+    let ran = queued && DEFERRED_RUNS.load(Ordering::SeqCst) == 1 && !WORK.is_pending();
+
+    // Timeouts and delays below need the timer
+    if !timer::running() {
+        return ran
+    }
+
+    // A flush gives up on work outlasting its timeout
+    let gave_up = workqueue::queue_work(&SLOW_WORK) && !workqueue::SYSTEM.flush_timeout(Some(Duration::from_millis(5)))
+        && workqueue::SYSTEM.flush_timeout(Some(Duration::from_millis(200)));
+
+    let start = timer::ticks();
+    let delayed = workqueue::queue_delayed_work(&DELAYED_WORK, Duration::from_millis(20))
+        && !workqueue::queue_delayed_work(&DELAYED_WORK, Duration::from_millis(20));
+    let waited = wait_deferred_runs(2) && timer::ticks() - start >= timer::TICK_HZ * 20 / 1000;
+
+    // Cancelled work never runs
+    let cancelled = workqueue::queue_delayed_work(&DELAYED_WORK, Duration::from_millis(20)) && DELAYED_WORK.cancel();
+    ran && gave_up && delayed && waited && cancelled && !wait_deferred_runs(3)
+}
+
+fn test_user_demo() -> bool {
+    // The thread's address space goes away when it is joined
+    let spaces = address_space::ids().count();
+    let joined = match user::spawn(user::demo_program()) {
+        None => false,
+        Some(id) => thread::join(id)
+    };
+    joined && address_space::ids().count() == spaces
+}
+
+const DEMO_ELF_SIZE: usize = 512;
+// Demo program follows the file and program header
+const DEMO_ELF_CODE: usize = 64 + 56;
+
+// Build ELF executable loading the demo program, along with its headers, read-only and executable at
+// USER_CODE_BASE
+fn demo_elf(buf: &mut [u8; DEMO_ELF_SIZE]) -> Option<&[u8]> {
+    let code = user::demo_program();
+    let len = DEMO_ELF_CODE + code.len();
+    if len > DEMO_ELF_SIZE {
+        return None
+    }
+
+    buf.fill(0);
+    // Magic, 64-bit, little endian, version 1
+    buf[..7].copy_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1]);
+    // Executable for x86-64, version 1, entry, program headers right after the file header
+    buf[16..18].copy_from_slice(&2u16.to_le_bytes());
+    buf[18..20].copy_from_slice(&62u16.to_le_bytes());
+    buf[20..24].copy_from_slice(&1u32.to_le_bytes());
+    buf[24..32].copy_from_slice(&((user::USER_CODE_BASE + DEMO_ELF_CODE) as u64).to_le_bytes());
+    buf[32..40].copy_from_slice(&64u64.to_le_bytes());
+    buf[52..54].copy_from_slice(&64u16.to_le_bytes());
+    buf[54..56].copy_from_slice(&56u16.to_le_bytes());
+    buf[56..58].copy_from_slice(&1u16.to_le_bytes());
+
+    // One PT_LOAD segment with PF_R and PF_X, taking up the whole file
+    let ph = &mut buf[64..120];
+    ph[0..4].copy_from_slice(&1u32.to_le_bytes());
+    ph[4..8].copy_from_slice(&5u32.to_le_bytes());
+    ph[16..24].copy_from_slice(&(user::USER_CODE_BASE as u64).to_le_bytes());
+    ph[24..32].copy_from_slice(&(user::USER_CODE_BASE as u64).to_le_bytes());
+    ph[32..40].copy_from_slice(&(len as u64).to_le_bytes());
+    ph[40..48].copy_from_slice(&(len as u64).to_le_bytes());
+    ph[48..56].copy_from_slice(&(PAGE_SIZE as u64).to_le_bytes());
+
+    buf[DEMO_ELF_CODE..len].copy_from_slice(code);
+    Some(&buf[..len])
+}
+
+fn test_elf_validate() -> bool {
+    let mut buf = [0; DEMO_ELF_SIZE];
+    let len = match demo_elf(&mut buf) {
+        None => return false,
+        Some(image) => image.len()
+    };
+    let valid = elf::validate(&buf[..len]).is_ok();
+
+    let mut bad = buf;
+    bad[0] = 0;
+    let bad_magic = elf::validate(&bad[..len]) == Err(ElfError::BadMagic);
+
+    let truncated = elf::validate(&buf[..100]) == Err(ElfError::Truncated);
+
+    // Same segment as PT_INTERP
+    let mut bad = buf;
+    bad[64] = 3;
+    let interpreter = elf::validate(&bad[..len]) == Err(ElfError::Interpreter);
+
+    // Without PF_X
+    let mut bad = buf;
+    bad[68] = 4;
+    let entry = elf::validate(&bad[..len]) == Err(ElfError::BadEntry);
+
+    // With PF_W on top
+    let mut bad = buf;
+    bad[68] = 7;
+    let writable = elf::validate(&bad[..len]) == Err(ElfError::WritableCode);
+
+    // Reaching into the kernel half
+    let mut bad = buf;
+    bad[80..88].copy_from_slice(&(KERNEL_HALF_BASE as u64).to_le_bytes());
+    let segment = elf::validate(&bad[..len]) == Err(ElfError::BadSegment);
+
+    // Program headers said to be at the very top of the address space
+    let mut bad = buf;
+    bad[64] = 6;
+    bad[80..88].copy_from_slice(&u64::MAX.to_le_bytes());
+    let phdr = elf::validate(&bad[..len]) == Err(ElfError::BadProgramHeaders);
+
+    // Writable segment right behind the code, in the same page
+    let mut bad = buf;
+    bad[56..58].copy_from_slice(&2u16.to_le_bytes());
+    let ph = &mut bad[120..176];
+    ph.fill(0);
+    ph[0..4].copy_from_slice(&1u32.to_le_bytes());
+    ph[4..8].copy_from_slice(&6u32.to_le_bytes());
+    ph[16..24].copy_from_slice(&((user::USER_CODE_BASE + len) as u64).to_le_bytes());
+    ph[40..48].copy_from_slice(&16u64.to_le_bytes());
+    let writable_code = elf::validate(&bad) == Err(ElfError::WritableCode);
+
+    valid && bad_magic && truncated && interpreter && entry && writable && segment && phdr && writable_code
+}
+
+fn test_elf_load() -> bool {
+    let mut buf = [0; DEMO_ELF_SIZE];
+    let image = match demo_elf(&mut buf) {
+        None => return false,
+        Some(i) => i
+    };
+    let spaces = address_space::ids().count();
+
+    // Stack starts with argc and the argv pointers
+    let program = match elf::load(image, &["demo", "arg"], &["HOME=/"]) {
+        Err(_) => return false,
+        Ok(p) => p
+    };
+    let stack_ok = program.stack % 16 == 0 && address_space::with(program.space, || {
+        let mut words = [0u8; 16];
+        let mut arg0 = [0u8; 5];
+        usercopy::copy_from_user(&mut words, program.stack).is_ok()
+            && u64::from_le_bytes(words[..8].try_into().unwrap()) == 2
+            && usercopy::copy_from_user(&mut arg0, u64::from_le_bytes(words[8..].try_into().unwrap()) as usize).is_ok()
+            && &arg0 == b"demo\0"
+    }).unwrap_or(false);
+    let entry_ok = program.entry == user::USER_CODE_BASE + DEMO_ELF_CODE;
+    address_space::destroy(program.space);
+
+    // The program says hello and exits
+    let joined = match user::spawn_elf(image, &["demo"], &[]) {
+        Err(_) => false,
+        Ok(id) => thread::join(id)
+    };
+    stack_ok && entry_ok && joined && address_space::ids().count() == spaces
+}
+
+// Run every self-test, returns number of failures
+pub fn run_all() -> usize {
+    let mut failures: usize = 0;
+    for test in TESTS.iter() {
+        let passed = (test.run)();
+        if !passed {
+            failures += 1;
+        }
+        console::write_fmt(format_args!("selftest {}: {}\n", test.name, match passed {
+            true => "ok",
+            false => "FAILED"
+        }));
+    }
+    console::write_fmt(format_args!("selftest: {} run, {} failed\n", TESTS.len(), failures));
+    failures
+}
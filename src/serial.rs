@@ -0,0 +1,106 @@
+use crate::asm_wrappers::{inb, outb};
+use crate::console::ConsoleSink;
+
+pub const COM1_BASE: u16 = 0x03F8;
+
+// Register offsets from the UART base port
+const DATA:          u16 = 0;
+const INT_ENABLE:    u16 = 1;
+const FIFO_CONTROL:  u16 = 2;
+const LINE_CONTROL:  u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS:   u16 = 5;
+
+// Line status bits
+const LSR_DATA_READY:      u8 = 0x01;
+const LSR_TRANSMIT_EMPTY:  u8 = 0x20;
+
+// Divisor for 115200 baud (base clock 115200 Hz)
+const BAUD_DIVISOR: u16 = 1;
+
+// Upper bound on transmit-empty polling so a missing UART can't hang the kernel
+const TRANSMIT_SPIN_LIMIT: usize = 100_000;
+
+pub static mut COM1: SerialPort = SerialPort::new(COM1_BASE);
+
+// 16550-compatible UART
+pub struct SerialPort {
+    base: u16,
+    present: bool
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        SerialPort {
+            base,
+            present: false
+        }
+    }
+
+    // Program 115200 8N1 with FIFOs enabled and run a loopback self-test, returns whether UART is usable
+    pub fn init(&mut self) -> bool {
+        unsafe {
+            outb(self.base + INT_ENABLE,    0x00);                      // Disable interrupts
+            outb(self.base + LINE_CONTROL,  0x80);                      // Enable DLAB to set divisor
+            outb(self.base + DATA,          (BAUD_DIVISOR & 0xFF) as u8);
+            outb(self.base + INT_ENABLE,    (BAUD_DIVISOR >> 8)   as u8);
+            outb(self.base + LINE_CONTROL,  0x03);                      // 8 bits, no parity, one stop bit
+            outb(self.base + FIFO_CONTROL,  0xC7);                      // Enable and clear FIFOs, 14-byte threshold
+            outb(self.base + MODEM_CONTROL, 0x1E);                      // Loopback mode for self-test
+
+            outb(self.base + DATA, 0xAE);
+            if inb(self.base + DATA) != 0xAE {
+                self.present = false;
+                return false
+            }
+
+            outb(self.base + MODEM_CONTROL, 0x0F);                      // Normal operation, IRQs, RTS/DSR set
+        }
+        self.present = true;
+        true
+    }
+
+    // Check if UART passed self-test
+    pub const fn is_present(&self) -> bool {
+        self.present
+    }
+
+    // Write single byte, waiting for transmit holding register to empty
+    pub fn write_byte(&mut self, c: u8) {
+        unsafe {
+            let mut spins: usize = 0;
+            while inb(self.base + LINE_STATUS) & LSR_TRANSMIT_EMPTY == 0 {
+                spins += 1;
+                if spins > TRANSMIT_SPIN_LIMIT {
+                    return
+                }
+            }
+            outb(self.base + DATA, c);
+        }
+    }
+
+    // Read single byte if one is waiting
+    pub fn read_byte(&mut self) -> Option<u8> {
+        unsafe {
+            match inb(self.base + LINE_STATUS) & LSR_DATA_READY {
+                0 => None,
+                _ => Some(inb(self.base + DATA))
+            }
+        }
+    }
+}
+
+impl ConsoleSink for SerialPort {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write_str(&mut self, s: &str) {
+        for &c in s.as_bytes().iter() {
+            if c == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(c);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use core::ptr::{addr_of, addr_of_mut};
+
+use crate::address_space;
+use crate::console;
+use crate::cpu;
+use crate::elf;
+use crate::frame_allocator::FRAME_ALLOCATOR;
+use crate::module;
+use crate::pager::Pager;
+use crate::ptdump;
+use crate::sched;
+use crate::selftest;
+use crate::serial::COM1;
+use crate::softirq;
+use crate::thread;
+use crate::user;
+use crate::workqueue;
+use crate::PAGE_TABLE;
+
+const MAX_LINE_LEN: usize = 128;
+
+// Debug shell command, gets everything after its name
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&str)
+}
+
+const COMMANDS: &[Command] = &[
+    Command { name: "help",      help: "list commands",                                run: cmd_help },
+    Command { name: "maps",      help: "maps [id]: dump page table of address space",  run: cmd_maps },
+    Command { name: "vmas",      help: "vmas [id]: dump areas of address space",       run: cmd_vmas },
+    Command { name: "checkmaps", help: "check invariants of every page table",         run: cmd_checkmaps },
+    Command { name: "cpuinfo",   help: "show CPU identity, caches and features",       run: cmd_cpuinfo },
+    Command { name: "meminfo",   help: "show frame allocator usage",                   run: cmd_meminfo },
+    Command { name: "selftest",  help: "run boot-time self-tests",                     run: cmd_selftest },
+    Command { name: "threads",   help: "list threads and their state",                 run: cmd_threads },
+    Command { name: "sched",     help: "show scheduler statistics of every CPU",       run: cmd_sched },
+    Command { name: "softirqs",  help: "show softirqs run on every CPU",                run: cmd_softirqs },
+    Command { name: "workqueues", help: "show work queues and their workers",          run: cmd_workqueues },
+    Command { name: "nice",      help: "nice <id> <class>: set priority of thread",    run: cmd_nice },
+    Command { name: "taskset",   help: "taskset <id> <mask>: set CPUs thread may use", run: cmd_taskset },
+    Command { name: "userdemo",  help: "run demo program in user mode",                run: cmd_userdemo },
+    Command { name: "modules",   help: "list boot modules",                            run: cmd_modules },
+    Command { name: "exec",      help: "exec <module> [args]: run ELF boot module",    run: cmd_exec }
+];
+
+fn cmd_help(_args: &str) {
+    for c in COMMANDS.iter() {
+        console::write_fmt(format_args!("{:<10} {}\n", c.name, c.help));
+    }
+}
+
+// Run f on page table named by argument, the kernel's if there is none
+fn with_pager_arg(args: &str, f: impl FnOnce(&Pager)) {
+    match args.trim() {
+        "" | "kernel" => f(&PAGE_TABLE.lock()),
+        id => match id.parse::<usize>().ok().and_then(address_space::get) {
+            None => console::write_fmt(format_args!("no address space {}\n", id)),
+            Some(mut s) => f(s.pager())
+        }
+    }
+}
+
+fn cmd_maps(args: &str) {
+    with_pager_arg(args, |p| unsafe { ptdump::dump(p) });
+}
+
+fn cmd_vmas(args: &str) {
+    with_pager_arg(args, ptdump::dump_vmas);
+}
+
+fn cmd_checkmaps(_args: &str) {
+    unsafe {
+        ptdump::check_all();
+    }
+}
+
+fn cmd_cpuinfo(_args: &str) {
+    cpu::print_summary();
+}
+
+fn cmd_meminfo(_args: &str) {
+    let frames = FRAME_ALLOCATOR.lock();
+    console::write_fmt(format_args!("{} of {} frames free\n", frames.free_frames(), frames.total_frames()));
+}
+
+fn cmd_selftest(_args: &str) {
+    selftest::run_all();
+}
+
+fn cmd_threads(_args: &str) {
+    thread::print_summary();
+}
+
+fn cmd_sched(_args: &str) {
+    sched::print_stats();
+}
+
+fn cmd_softirqs(_args: &str) {
+    softirq::print_stats();
+}
+
+fn cmd_workqueues(_args: &str) {
+    workqueue::print_stats();
+}
+
+// Split arguments into a thread id and one more word
+fn thread_args(args: &str) -> Option<(usize, &str)> {
+    let mut words = args.split_whitespace();
+    let id = words.next()?.parse::<usize>().ok()?;
+    let value = words.next()?;
+    match words.next() {
+        None => Some((id, value)),
+        Some(_) => None
+    }
+}
+
+fn cmd_nice(args: &str) {
+    match thread_args(args).and_then(|(id, p)| Some((id, sched::Priority::from_name(p)?))) {
+        None => console::write_fmt(format_args!("usage: nice <id> <realtime|normal|idle>\n")),
+        Some((id, priority)) => if !thread::set_priority(id, priority) {
+            console::write_fmt(format_args!("no thread {} to change\n", id));
+        }
+    }
+}
+
+fn cmd_taskset(args: &str) {
+    let parsed = thread_args(args).and_then(|(id, mask)| {
+        let mask = match mask.strip_prefix("0x") {
+            None => mask.parse::<u64>().ok()?,
+            Some(hex) => u64::from_str_radix(hex, 16).ok()?
+        };
+        Some((id, mask))
+    });
+    match parsed {
+        None => console::write_fmt(format_args!("usage: taskset <id> <mask>\n")),
+        Some((id, mask)) => if !thread::set_affinity(id, mask) {
+            console::write_fmt(format_args!("no thread {} or no online CPU in {:#x}\n", id, mask));
+        }
+    }
+}
+
+fn cmd_userdemo(_args: &str) {
+    match user::spawn(user::demo_program()) {
+        None => console::write_fmt(format_args!("failed to start demo program\n")),
+        Some(id) => {
+            thread::join(id);
+        }
+    }
+}
+
+fn cmd_modules(_args: &str) {
+    module::print_modules();
+}
+
+fn cmd_exec(args: &str) {
+    let mut argv = [""; elf::MAX_ARGS];
+    let mut argc = 0;
+    for a in args.split_whitespace() {
+        if argc == elf::MAX_ARGS {
+            console::write_fmt(format_args!("too many arguments\n"));
+            return
+        }
+        argv[argc] = a;
+        argc += 1;
+    }
+
+    let m = match argc {
+        0 => {
+            console::write_fmt(format_args!("usage: exec <module> [args]\n"));
+            return
+        },
+        _ => match module::find(argv[0]) {
+            None => {
+                console::write_fmt(format_args!("no module {}\n", argv[0]));
+                return
+            },
+            Some(m) => m
+        }
+    };
+    match user::spawn_elf(m.data(), &argv[..argc], &[]) {
+        Err(e) => console::write_fmt(format_args!("failed to run {}: {}\n", m.path(), e)),
+        Ok(id) => {
+            thread::join(id);
+        }
+    }
+}
+
+// Run single command line, returns false if the command doesn't exist
+pub fn run_command(line: &str) -> bool {
+    let line = line.trim();
+    let (name, args) = match line.find(' ') {
+        None => (line, ""),
+        Some(i) => (&line[..i], &line[i + 1..])
+    };
+
+    if name.is_empty() {
+        return true
+    }
+
+    match COMMANDS.iter().find(|c| c.name == name) {
+        None => {
+            console::write_fmt(format_args!("unknown command {}, try help\n", name));
+            false
+        },
+        Some(c) => {
+            (c.run)(args);
+            true
+        }
+    }
+}
+
+// Read commands from serial port and run them until "exit" is entered
+pub fn run() {
+    unsafe {
+        if !(*addr_of!(COM1)).is_present() {
+            return
+        }
+    }
+
+    let mut line = [0u8; MAX_LINE_LEN];
+    let mut len: usize = 0;
+    console::write_str("debug shell, type help for commands\n> ");
+    loop {
+        let c = match unsafe { (*addr_of_mut!(COM1)).read_byte() } {
+            None => {
+                core::hint::spin_loop();
+                continue;
+            },
+            Some(c) => c
+        };
+
+        match c {
+            b'\r' | b'\n' => {
+                console::write_str("\n");
+                // Only printable ASCII is ever stored
+                let s = core::str::from_utf8(&line[..len]).unwrap_or("");
+                if s.trim() == "exit" {
+                    return
+                }
+                run_command(s);
+                len = 0;
+                console::write_str("> ");
+            },
+            // Backspace and delete
+            0x08 | 0x7F if len > 0 => {
+                len -= 1;
+                console::write_str("\x08 \x08");
+            },
+            0x20..=0x7E if len < MAX_LINE_LEN => {
+                line[len] = c;
+                len += 1;
+                let echo = [c];
+                console::write_str(core::str::from_utf8(&echo).unwrap_or(""));
+            },
+            _ => { }
+        }
+    }
+}
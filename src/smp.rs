@@ -0,0 +1,166 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use limine::{LimineSmpInfo, LimineSmpResponse};
+
+use crate::apic;
+use crate::asm_wrappers::{cli, rdtsc, sti, switch_stack};
+use crate::constants::MAX_CPUS;
+use crate::cpu;
+use crate::fpu;
+use crate::gdt;
+use crate::interrupts;
+use crate::percpu;
+use crate::stack::{self, StackOwner};
+use crate::sync::Once;
+use crate::syscall;
+use crate::thread;
+use crate::timer;
+use crate::tlb;
+use crate::PAGE_TABLE;
+
+// Limine response flag telling that x2APIC mode was enabled on every CPU
+const SMP_X2APIC: u32 = 1 << 0;
+
+// Give up on an AP that isn't online after this many TSC ticks, a few seconds on any real CPU
+const AP_START_TIMEOUT: u64 = 10_000_000_000;
+
+// APIC ID of every CPU found by kernel CPU number, the bootstrap processor is CPU 0
+struct CpuIds {
+    apic_ids: [u32; MAX_CPUS],
+    count: usize
+}
+
+static CPU_IDS: Once<CpuIds> = Once::new();
+static ONLINE: AtomicU64 = AtomicU64::new(1);
+
+// Get number of this CPU
+pub fn current_cpu() -> usize {
+    percpu::cpu_id()
+}
+
+// Find CPU by APIC ID, for CPUs that have no per-CPU area loaded yet
+fn cpu_of_apic(id: u32) -> Option<usize> {
+    let ids = CPU_IDS.get()?;
+    ids.apic_ids[..ids.count].iter().position(|a| *a == id)
+}
+
+// Get number of CPUs found, whether or not they were started
+pub fn cpu_count() -> usize {
+    CPU_IDS.get().map_or(1, |ids| ids.count)
+}
+
+// Get mask of CPUs that finished bring-up and take interrupts
+pub fn online_mask() -> u64 {
+    ONLINE.load(Ordering::Acquire)
+}
+
+pub fn online_count() -> usize {
+    online_mask().count_ones() as usize
+}
+
+pub fn apic_id(cpu: usize) -> Option<u32> {
+    let ids = CPU_IDS.get()?;
+    match cpu < ids.count {
+        true => Some(ids.apic_ids[cpu]),
+        false => None
+    }
+}
+
+// Set up local APIC of the bootstrap processor and start every AP one after another unless disabled,
+// returns the number of CPUs online
+pub unsafe fn init(response: Option<&mut LimineSmpResponse>, start_aps: bool) -> usize {
+    let x2apic = match &response {
+        None => false,
+        Some(r) => r.flags & SMP_X2APIC != 0
+    };
+    if !apic::init(x2apic) {
+        return 1
+    }
+    apic::init_cpu();
+    timer::init();
+
+    let response = match response {
+        None => return 1,
+        Some(r) => r
+    };
+
+    let bsp = response.bsp_lapic_id;
+    let cpus = match response.cpus() {
+        None => return 1,
+        Some(c) => c
+    };
+
+    let mut ids = CpuIds { apic_ids: [0; MAX_CPUS], count: 1 };
+    ids.apic_ids[0] = bsp;
+    for info in cpus.iter().filter(|c| c.lapic_id != bsp) {
+        if ids.count == MAX_CPUS {
+            break
+        }
+        ids.apic_ids[ids.count] = info.lapic_id;
+        ids.count += 1;
+    }
+    CPU_IDS.set(ids);
+
+    if !start_aps {
+        return 1
+    }
+
+    for info in cpus.iter_mut().filter(|c| c.lapic_id != bsp) {
+        let cpu = match cpu_of_apic(info.lapic_id) {
+            None => continue,
+            Some(n) => n
+        };
+        let s = match stack::allocate(StackOwner::Cpu(cpu)) {
+            None => break,
+            Some(s) => s
+        };
+        if !percpu::init(cpu) {
+            stack::free(s);
+            break
+        }
+
+        info.extra_argument = s.top() as u64;
+        let goto = &*(core::ptr::addr_of!(info.goto_address) as *const AtomicU64);
+        goto.store(ap_entry as *const () as usize as u64, Ordering::Release);
+
+        // An AP mapping its own stacks invalidates kernel pages, which waits on us, so take interrupts meanwhile
+        let start = rdtsc();
+        sti();
+        while ONLINE.load(Ordering::Acquire) & (1 << cpu) == 0 && rdtsc() - start < AP_START_TIMEOUT {
+            core::hint::spin_loop();
+        }
+        cli();
+    }
+    online_count()
+}
+
+// First kernel code an AP runs, still on the bootloader's stack and page table
+extern "C" fn ap_entry(info: &'static LimineSmpInfo) -> ! {
+    unsafe {
+        // The BSP only starts CPUs it knows. Our area isn't mapped until our page table is loaded below
+        percpu::load(cpu_of_apic(info.lapic_id).unwrap_or(0));
+        tlb::init();
+        cpu::enable_protections();
+        PAGE_TABLE.lock().activate();
+        switch_stack(info.extra_argument as usize, ap_main)
+    }
+}
+
+extern "C" fn ap_main() -> ! {
+    unsafe {
+        let cpu = current_cpu();
+        if !gdt::init(cpu) {
+            panic!("failed to allocate exception stack for CPU {}", cpu);
+        }
+        interrupts::load();
+        syscall::init_cpu();
+        fpu::init();
+        apic::init_cpu();
+        timer::init_cpu();
+        tlb::flush_all_global();
+
+        ONLINE.fetch_or(1 << cpu, Ordering::AcqRel);
+    }
+    // The boot stack becomes the idle thread's, which picks up threads as they become ready
+    thread::run_idle()
+}
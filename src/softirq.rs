@@ -0,0 +1,183 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::asm_wrappers::{cli, sti};
+use crate::console;
+use crate::constants::MAX_CPUS;
+use crate::percpu;
+use crate::smp;
+use crate::timer;
+
+// Work raised by interrupt handlers and run once the outermost handler is done with the hardware, with
+// interrupts enabled again. A softirq raised on a CPU runs on that CPU
+#[derive(Clone, Copy)]
+pub enum Softirq {
+    // Expired timers of the timer wheel
+    Timer,
+    Tasklet
+}
+
+const NUM_SOFTIRQS: usize = 2;
+
+// Handler of every softirq, in the order they run
+const HANDLERS: [fn(); NUM_SOFTIRQS] = [timer::run_softirq, run_tasklets];
+
+// Softirqs raised again while running are run again this often, after that they wait for the next interrupt
+const MAX_ROUNDS: usize = 4;
+
+impl Softirq {
+    const fn from_index(i: usize) -> Self {
+        match i {
+            0 => Softirq::Timer,
+            _ => Softirq::Tasklet
+        }
+    }
+}
+
+impl fmt::Display for Softirq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Softirq::Timer => "timer",
+            Softirq::Tasklet => "tasklet"
+        })
+    }
+}
+
+percpu! {
+    static PENDING: u32 = 0;
+    static COUNTS: [u64; NUM_SOFTIRQS] = [0; NUM_SOFTIRQS];
+    // Tasklets scheduled on this CPU, oldest first
+    static TASKLETS: TaskletList = TaskletList::new();
+}
+
+// Mark softirq pending on this CPU, it runs when the current or next interrupt handler exits
+pub fn raise(softirq: Softirq) {
+    PENDING.with(|p| *p |= 1 << softirq as usize);
+}
+
+// Run pending softirqs at the end of an interrupt handler, after its EOI. Only the outermost handler does,
+// and interrupts are enabled meanwhile, so nested handlers just raise more
+pub fn run_pending() {
+    if percpu::irq_depth() != 1 {
+        return
+    }
+
+    for _ in 0..MAX_ROUNDS {
+        let pending = PENDING.with(|p| core::mem::replace(p, 0));
+        if pending == 0 {
+            break
+        }
+
+        unsafe {
+            sti();
+        }
+        for (i, handler) in HANDLERS.iter().enumerate().filter(|(i, _)| pending & (1 << i) != 0) {
+            COUNTS.with(|c| c[i] += 1);
+            handler();
+        }
+        unsafe {
+            cli();
+        }
+    }
+}
+
+// Deferred function run in softirq context on the CPU that scheduled it. The same tasklet never runs on two
+// CPUs at once, and scheduling it again before it started runs it only once
+pub struct Tasklet {
+    func: fn(usize),
+    arg: usize,
+    scheduled: AtomicBool,
+    running: AtomicBool,
+    // Next tasklet in the list of the CPU it is scheduled on
+    next: AtomicPtr<Tasklet>
+}
+
+impl Tasklet {
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        Tasklet {
+            func,
+            arg,
+            scheduled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            next: AtomicPtr::new(core::ptr::null_mut())
+        }
+    }
+
+    // Queue tasklet on this CPU, returns false if it was already scheduled. Safe from interrupt handlers
+    pub fn schedule(&'static self) -> bool {
+        if self.scheduled.swap(true, Ordering::AcqRel) {
+            return false
+        }
+        TASKLETS.with(|l| l.push(self));
+        raise(Softirq::Tasklet);
+        true
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Acquire)
+    }
+}
+
+// Intrusive list of tasklets, only touched by its CPU with interrupts disabled
+struct TaskletList {
+    head: Option<&'static Tasklet>,
+    tail: Option<&'static Tasklet>
+}
+
+impl TaskletList {
+    const fn new() -> Self {
+        TaskletList { head: None, tail: None }
+    }
+
+    fn push(&mut self, tasklet: &'static Tasklet) {
+        tasklet.next.store(core::ptr::null_mut(), Ordering::Relaxed);
+        match self.tail {
+            None => self.head = Some(tasklet),
+            Some(t) => t.next.store(tasklet as *const Tasklet as *mut Tasklet, Ordering::Relaxed)
+        }
+        self.tail = Some(tasklet);
+    }
+
+    fn pop(&mut self) -> Option<&'static Tasklet> {
+        let tasklet = self.head?;
+        self.head = unsafe { tasklet.next.load(Ordering::Relaxed).as_ref() };
+        if self.head.is_none() {
+            self.tail = None;
+        }
+        Some(tasklet)
+    }
+}
+
+// Run tasklets scheduled on this CPU so far. One still running on another CPU is put back for the next round
+fn run_tasklets() {
+    let mut list = TASKLETS.with(|l| core::mem::replace(l, TaskletList::new()));
+    while let Some(t) = list.pop() {
+        if t.running.swap(true, Ordering::Acquire) {
+            TASKLETS.with(|l| l.push(t));
+            raise(Softirq::Tasklet);
+            continue
+        }
+        t.scheduled.store(false, Ordering::Release);
+        (t.func)(t.arg);
+        t.running.store(false, Ordering::Release);
+    }
+}
+
+pub fn print_stats() {
+    console::write_fmt(format_args!("cpu "));
+    for i in 0..NUM_SOFTIRQS {
+        console::write_fmt(format_args!(" {:>10}", Softirq::from_index(i)));
+    }
+    console::write_fmt(format_args!("\n"));
+    for cpu in (0..MAX_CPUS).filter(|&c| smp::online_mask() & (1 << c) != 0) {
+        let counts = match COUNTS.on(cpu) {
+            None => continue,
+            Some(c) => unsafe { core::ptr::read_volatile(c) }
+        };
+        console::write_fmt(format_args!("{:<4}", cpu));
+        for count in counts {
+            console::write_fmt(format_args!(" {:>10}", count));
+        }
+        console::write_fmt(format_args!("\n"));
+    }
+}
@@ -0,0 +1,128 @@
+use core::fmt;
+
+use crate::constants::*;
+use crate::kaslr;
+use crate::sync::SpinLock;
+use crate::PAGE_TABLE;
+
+pub const MAX_KERNEL_STACKS: usize = 512;
+
+// Every slot starts with an unmapped guard page, so each stack has one below it and the next slot's above it
+const SLOT_PAGES: usize = KERNEL_STACK_PAGES + 1;
+const SLOT_SIZE:  usize = SLOT_PAGES * PAGE_SIZE;
+
+// Size of kernel stack region including the guard page above the last slot
+pub const REGION_SIZE: usize = MAX_KERNEL_STACKS * SLOT_SIZE + PAGE_SIZE;
+
+// What a kernel stack is used by
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum StackOwner {
+    Thread(usize),
+    Cpu(usize),
+    // Interrupt stack table entry of CPU, for exceptions that can't trust the interrupted stack
+    Exception(usize)
+}
+
+impl fmt::Display for StackOwner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackOwner::Thread(id) => write!(f, "thread {}", id),
+            StackOwner::Cpu(id) => write!(f, "CPU {}", id),
+            StackOwner::Exception(id) => write!(f, "exception stack of CPU {}", id)
+        }
+    }
+}
+
+// Kernel stack with unmapped guard pages on both ends
+#[derive(Clone, Copy)]
+pub struct KernelStack {
+    slot: usize
+}
+
+impl KernelStack {
+    // Lowest usable address
+    pub fn bottom(&self) -> usize {
+        kaslr::stack_base() + self.slot * SLOT_SIZE + PAGE_SIZE
+    }
+
+    // Address just past highest usable byte, where the stack pointer starts
+    pub fn top(&self) -> usize {
+        self.bottom() + KERNEL_STACK_PAGES * PAGE_SIZE
+    }
+
+    pub fn owner(&self) -> Option<StackOwner> {
+        OWNERS.lock()[self.slot]
+    }
+}
+
+const NO_OWNER: Option<StackOwner> = None;
+
+static OWNERS: SpinLock<[Option<StackOwner>; MAX_KERNEL_STACKS]> = SpinLock::new([NO_OWNER; MAX_KERNEL_STACKS]);
+
+// Map stack in first free slot of kernel stack region
+pub fn allocate(owner: StackOwner) -> Option<KernelStack> {
+    // Claim slot first so the owners lock isn't held while mapping
+    let slot = {
+        let mut owners = OWNERS.lock();
+        let slot = owners.iter().position(|o| o.is_none())?;
+        owners[slot] = Some(owner);
+        slot
+    };
+
+    let stack = KernelStack { slot };
+    let mapped = unsafe { PAGE_TABLE.lock().allocate_pages(Some(stack.bottom() as *const ()), KERNEL_STACK_PAGES) };
+    match mapped {
+        None => {
+            OWNERS.lock()[slot] = None;
+            None
+        },
+        Some(_) => Some(stack)
+    }
+}
+
+// Unmap stack and return its frames, it must not be in use anywhere
+pub fn free(stack: KernelStack) {
+    let owned = OWNERS.lock()[stack.slot].take().is_some();
+    if owned {
+        unsafe {
+            PAGE_TABLE.lock().deallocate_pages(Some(stack.bottom() as *const ()), KERNEL_STACK_PAGES);
+        }
+    }
+}
+
+// Hand stack over to new owner
+pub fn set_owner(stack: &KernelStack, owner: StackOwner) {
+    let mut owners = OWNERS.lock();
+    if owners[stack.slot].is_some() {
+        owners[stack.slot] = Some(owner);
+    }
+}
+
+// Get stack whose usable pages contain address
+pub fn containing(vaddr: usize) -> Option<KernelStack> {
+    let base = kaslr::stack_base();
+    if vaddr < base || vaddr >= base + MAX_KERNEL_STACKS * SLOT_SIZE {
+        return None
+    }
+
+    let slot = (vaddr - base) / SLOT_SIZE;
+    match (vaddr - base) % SLOT_SIZE >= PAGE_SIZE && OWNERS.lock()[slot].is_some() {
+        true => Some(KernelStack { slot }),
+        false => None
+    }
+}
+
+// Get owner of stack whose lower guard page contains address, faults there mean the stack overflowed
+pub fn guard_owner(vaddr: usize) -> Option<StackOwner> {
+    let base = kaslr::stack_base();
+    if vaddr < base || vaddr >= base + MAX_KERNEL_STACKS * SLOT_SIZE {
+        return None
+    }
+
+    let slot   = (vaddr - base) / SLOT_SIZE;
+    let offset = (vaddr - base) % SLOT_SIZE;
+    match offset < PAGE_SIZE {
+        true => OWNERS.lock()[slot],
+        false => None
+    }
+}
@@ -0,0 +1,295 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use crate::asm_wrappers::{cli, read_rflags, sti, RFLAGS_IF};
+use crate::percpu;
+use crate::sched;
+use crate::tlb;
+
+// Disable interrupts on this CPU, returns whether they were enabled before
+pub fn save_and_disable_interrupts() -> bool {
+    unsafe {
+        let enabled = read_rflags() & RFLAGS_IF != 0;
+        cli();
+        enabled
+    }
+}
+
+pub fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe {
+            sti();
+        }
+    }
+}
+
+// Keep scheduler off this CPU while a lock is held, so nothing spins on a lock whose holder was preempted.
+// Only counted once the scheduler started, before that APs take locks without their per-CPU area mapped
+fn lock_preempt_disable() -> bool {
+    match sched::started() {
+        false => false,
+        true => {
+            percpu::preempt_disable();
+            true
+        }
+    }
+}
+
+fn lock_preempt_enable(counted: bool) {
+    if counted {
+        percpu::preempt_enable();
+    }
+}
+
+// Ticket spinlock, CPUs get the lock in the order they asked for it
+pub struct SpinLock<T> {
+    next: AtomicU32,
+    serving: AtomicU32,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> { }
+unsafe impl<T: Send> Send for SpinLock<T> { }
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock { next: AtomicU32::new(0), serving: AtomicU32::new(0), data: UnsafeCell::new(data) }
+    }
+
+    fn acquire(&self) {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            // The holder may be waiting for this CPU to flush, which the IPI can't ask for with interrupts
+            // disabled. Every CPU has its per-CPU area once the scheduler runs
+            if sched::started() {
+                tlb::poll_shootdown();
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn release(&self) {
+        self.serving.fetch_add(1, Ordering::Release);
+    }
+
+    // Lock with interrupts left as they are, for data no interrupt handler touches
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let preempt = lock_preempt_disable();
+        self.acquire();
+        SpinLockGuard { lock: self, preempt }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let preempt = lock_preempt_disable();
+        let serving = self.serving.load(Ordering::Relaxed);
+        match self.next.compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(SpinLockGuard { lock: self, preempt }),
+            Err(_) => {
+                lock_preempt_enable(preempt);
+                None
+            }
+        }
+    }
+
+    // Lock with interrupts disabled until the guard is dropped, for data shared with interrupt handlers
+    pub fn lock_irqsave(&self) -> SpinLockIrqGuard<'_, T> {
+        let enabled = save_and_disable_interrupts();
+        self.acquire();
+        SpinLockIrqGuard { lock: self, interrupts: enabled }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.next.load(Ordering::Relaxed) != self.serving.load(Ordering::Relaxed)
+    }
+
+    // Get data without locking, for panic paths where the holder will never come back
+    pub unsafe fn get_unchecked(&self) -> *mut T {
+        self.data.get()
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    preempt: bool
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+        lock_preempt_enable(self.preempt);
+    }
+}
+
+pub struct SpinLockIrqGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    interrupts: bool
+}
+
+impl<T> Deref for SpinLockIrqGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockIrqGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockIrqGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release();
+        restore_interrupts(self.interrupts);
+    }
+}
+
+// Reader-writer spinlock, any number of readers or a single writer. Writers wait for readers to drain
+pub struct RwSpinLock<T> {
+    // Number of readers, or WRITER while write locked
+    state: AtomicUsize,
+    data: UnsafeCell<T>
+}
+
+const WRITER: usize = usize::MAX;
+
+unsafe impl<T: Send + Sync> Sync for RwSpinLock<T> { }
+unsafe impl<T: Send> Send for RwSpinLock<T> { }
+
+impl<T> RwSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        RwSpinLock { state: AtomicUsize::new(0), data: UnsafeCell::new(data) }
+    }
+
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        let preempt = lock_preempt_disable();
+        loop {
+            let readers = self.state.load(Ordering::Relaxed);
+            if readers != WRITER && readers < WRITER - 1
+                && self.state.compare_exchange_weak(readers, readers + 1, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                return RwSpinLockReadGuard { lock: self, preempt }
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        let preempt = lock_preempt_disable();
+        while self.state.compare_exchange_weak(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            core::hint::spin_loop();
+        }
+        RwSpinLockWriteGuard { lock: self, preempt }
+    }
+}
+
+pub struct RwSpinLockReadGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+    preempt: bool
+}
+
+impl<T> Deref for RwSpinLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwSpinLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(1, Ordering::Release);
+        lock_preempt_enable(self.preempt);
+    }
+}
+
+pub struct RwSpinLockWriteGuard<'a, T> {
+    lock: &'a RwSpinLock<T>,
+    preempt: bool
+}
+
+impl<T> Deref for RwSpinLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwSpinLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwSpinLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        lock_preempt_enable(self.preempt);
+    }
+}
+
+const ONCE_EMPTY:   u8 = 0;
+const ONCE_RUNNING: u8 = 1;
+const ONCE_DONE:    u8 = 2;
+
+// Value set exactly once, then only read. Other CPUs wait while it is being set
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<Option<T>>
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> { }
+unsafe impl<T: Send> Send for Once<T> { }
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Once { state: AtomicU8::new(ONCE_EMPTY), value: UnsafeCell::new(None) }
+    }
+
+    // Get value, running f to create it if nobody did yet
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        match self.state.compare_exchange(ONCE_EMPTY, ONCE_RUNNING, Ordering::Acquire, Ordering::Acquire) {
+            Ok(_) => {
+                unsafe {
+                    *self.value.get() = Some(f());
+                }
+                self.state.store(ONCE_DONE, Ordering::Release);
+            },
+            Err(_) => while self.state.load(Ordering::Acquire) != ONCE_DONE {
+                core::hint::spin_loop();
+            }
+        }
+        self.get().unwrap()
+    }
+
+    // Set value, returns false if it was already set
+    pub fn set(&self, value: T) -> bool {
+        let mut value = Some(value);
+        self.call_once(|| value.take().unwrap());
+        value.is_none()
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            ONCE_DONE => unsafe { (*self.value.get()).as_ref() },
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use core::arch::global_asm;
+use core::fmt;
+
+use crate::asm_wrappers::{read_efer, wrmsr, write_efer, Efer, MSR_LSTAR, MSR_SFMASK, MSR_STAR, RFLAGS_IF};
+use crate::console;
+use crate::constants::USER_HALF_END;
+use crate::gdt;
+use crate::percpu;
+use crate::thread;
+use crate::usercopy::{self, UserCopyError};
+
+// Syscall numbers, also their index in SYSCALLS
+pub const SYS_EXIT:   u64 = 0;
+pub const SYS_WRITE:  u64 = 1;
+pub const SYS_YIELD:  u64 = 2;
+pub const SYS_SLEEP:  u64 = 3;
+pub const SYS_GETTID: u64 = 4;
+
+// Flags cleared on entry: interrupts stay off until the stub is on the kernel stack, and neither single
+// stepping, a set direction flag nor user access under SMAP leak into the kernel
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_AC: u64 = 1 << 18;
+const SYSCALL_FLAG_MASK: u64 = RFLAGS_IF | RFLAGS_TF | RFLAGS_DF | RFLAGS_AC;
+
+// Longest write copied at once, longer ones go in pieces
+const WRITE_CHUNK: usize = 256;
+// Longest single write, so a program can't keep the console for long
+const MAX_WRITE: usize = 64 * 1024;
+
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    NoSys,
+    BadFd,
+    Fault,
+    Invalid
+}
+
+impl SyscallError {
+    // Returned to user mode negated, with the numbers Linux uses
+    const fn errno(self) -> i64 {
+        match self {
+            SyscallError::NoSys => 38,
+            SyscallError::BadFd => 9,
+            SyscallError::Fault => 14,
+            SyscallError::Invalid => 22
+        }
+    }
+}
+
+impl fmt::Display for SyscallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyscallError::NoSys => write!(f, "no such syscall"),
+            SyscallError::BadFd => write!(f, "bad file descriptor"),
+            SyscallError::Fault => write!(f, "bad user address"),
+            SyscallError::Invalid => write!(f, "invalid argument")
+        }
+    }
+}
+
+impl From<UserCopyError> for SyscallError {
+    fn from(_: UserCopyError) -> Self {
+        SyscallError::Fault
+    }
+}
+
+// User registers saved by the entry stub, in the order it pushes them. Arguments go in rdi, rsi, rdx, r10,
+// r8 and r9 as on Linux, rcx and r11 hold the return address and flags
+#[repr(C)]
+pub struct SyscallFrame {
+    // Syscall number, replaced by the result
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8:  u64,
+    pub r9:  u64,
+    pub rip: u64,
+    pub rflags: u64,
+    pub rsp: u64
+}
+
+impl SyscallFrame {
+    pub const fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+struct Syscall {
+    number: u64,
+    name: &'static str,
+    run: fn([u64; 6]) -> Result<u64, SyscallError>
+}
+
+const SYSCALLS: [Syscall; 5] = [
+    Syscall { number: SYS_EXIT,   name: "exit",   run: sys_exit },
+    Syscall { number: SYS_WRITE,  name: "write",  run: sys_write },
+    Syscall { number: SYS_YIELD,  name: "yield",  run: sys_yield },
+    Syscall { number: SYS_SLEEP,  name: "sleep",  run: sys_sleep },
+    Syscall { number: SYS_GETTID, name: "gettid", run: sys_gettid }
+];
+
+// Numbers match the index, but looking them up keeps the table honest
+fn lookup(number: u64) -> Option<&'static Syscall> {
+    SYSCALLS.get(number as usize).filter(|s| s.number == number)
+}
+
+// exit(): end the calling thread, its address space goes away once the thread is joined
+fn sys_exit(_args: [u64; 6]) -> Result<u64, SyscallError> {
+    thread::exit()
+}
+
+// write(fd, buf, len): write to console, fd 1 and 2 both go there. Returns bytes written
+fn sys_write(args: [u64; 6]) -> Result<u64, SyscallError> {
+    let (fd, buf, len) = (args[0], args[1] as usize, args[2] as usize);
+    if fd != STDOUT && fd != STDERR {
+        return Err(SyscallError::BadFd)
+    }
+    if len > MAX_WRITE {
+        return Err(SyscallError::Invalid)
+    }
+    match buf.checked_add(len) {
+        Some(end) if end <= USER_HALF_END => { },
+        _ => return Err(SyscallError::Fault)
+    }
+
+    let mut chunk = [0u8; WRITE_CHUNK];
+    let mut done: usize = 0;
+    while done < len {
+        let n = core::cmp::min(WRITE_CHUNK, len - done);
+        usercopy::copy_from_user(&mut chunk[..n], buf + done)?;
+        write_bytes(&chunk[..n]);
+        done += n;
+    }
+    Ok(len as u64)
+}
+
+// Write bytes to console, anything that isn't UTF-8 shows up as replacement characters
+fn write_bytes(mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        match core::str::from_utf8(bytes) {
+            Ok(s) => {
+                console::write_str(s);
+                break
+            },
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                console::write_str(unsafe { core::str::from_utf8_unchecked(valid) });
+                console::write_str("\u{FFFD}");
+                bytes = &rest[e.error_len().unwrap_or(rest.len())..];
+            }
+        }
+    }
+}
+
+fn sys_yield(_args: [u64; 6]) -> Result<u64, SyscallError> {
+    thread::yield_now();
+    Ok(0)
+}
+
+// sleep(ms)
+fn sys_sleep(args: [u64; 6]) -> Result<u64, SyscallError> {
+    thread::sleep(core::time::Duration::from_millis(args[0]));
+    Ok(0)
+}
+
+fn sys_gettid(_args: [u64; 6]) -> Result<u64, SyscallError> {
+    thread::current().map(|id| id as u64).ok_or(SyscallError::Invalid)
+}
+
+// Called by the entry stub on the thread's kernel stack, with interrupts enabled again
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let syscall = lookup(frame.rax);
+    let result = match syscall {
+        None => Err(SyscallError::NoSys),
+        Some(s) => (s.run)(frame.args())
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(e) => (-e.errno()) as u64
+    };
+
+    // sysret to a non-canonical address faults in kernel mode with the user stack already loaded, which a
+    // syscall at the very end of the user half would get us to. Such a thread is ended instead
+    if frame.rip >= USER_HALF_END as u64 {
+        console::write_fmt(format_args!("thread {} made {} syscall at end of user half, ending it\n",
+                                        thread::current().unwrap_or(0), syscall.map_or("an unknown", |s| s.name)));
+        thread::exit();
+    }
+}
+
+// Entry point of syscall. The CPU left the user stack and GS base in place and interrupts off, so swap in
+// the kernel GS base, park the user stack pointer in the per-CPU area and move to the thread's kernel stack,
+// found at percpu::SYSCALL_USER_RSP and SYSCALL_KERNEL_RSP. The SyscallFrame built there is also where
+// the results come back from
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[{user_rsp}], rsp",
+    "mov rsp, gs:[{kernel_rsp}]",
+    "push qword ptr gs:[{user_rsp}]",
+    "push r11",
+    "push rcx",
+    "push r9",
+    "push r8",
+    "push r10",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rax",
+    "mov rdi, rsp",
+    "sti",
+    "call syscall_dispatch",
+    "cli",
+    "pop rax",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop r10",
+    "pop r8",
+    "pop r9",
+    "pop rcx",
+    "pop r11",
+    "pop rsp",
+    "swapgs",
+    "sysretq",
+    user_rsp = const percpu::SYSCALL_USER_RSP,
+    kernel_rsp = const percpu::SYSCALL_KERNEL_RSP
+);
+
+// Leave kernel for user code at rip with stack rsp and interrupts enabled, with every other register
+// cleared so nothing of the kernel leaks
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    "cli",
+    "mov rcx, rdi",
+    "mov rsp, rsi",
+    "mov r11, 0x202",
+    "xor eax, eax",
+    "xor ebx, ebx",
+    "xor edx, edx",
+    "xor esi, esi",
+    "xor edi, edi",
+    "xor ebp, ebp",
+    "xor r8d, r8d",
+    "xor r9d, r9d",
+    "xor r10d, r10d",
+    "xor r12d, r12d",
+    "xor r13d, r13d",
+    "xor r14d, r14d",
+    "xor r15d, r15d",
+    "swapgs",
+    "sysretq"
+);
+
+extern "C" {
+    fn syscall_entry();
+    pub fn enter_user(rip: usize, rsp: usize) -> !;
+}
+
+// sysret expects user code right after user data
+const _: () = assert!(gdt::USER_CODE_SELECTOR == gdt::USER_DATA_SELECTOR + 8);
+
+// Point syscall at the entry stub on this CPU. The GDT must have the user segments where STAR says
+pub unsafe fn init_cpu() {
+    // syscall loads CS from bits 32-47 and SS 8 above it. sysret loads SS 8 and CS 16 above bits 48-63
+    let sysret_base = (gdt::USER_DATA_SELECTOR & !3) - 8;
+    let star = ((sysret_base as u64) << 48) | ((gdt::KERNEL_CODE_SELECTOR as u64) << 32);
+    wrmsr(MSR_STAR, star);
+    wrmsr(MSR_LSTAR, syscall_entry as *const () as usize as u64);
+    wrmsr(MSR_SFMASK, SYSCALL_FLAG_MASK);
+    write_efer(read_efer().with(Efer::SCE));
+}
@@ -0,0 +1,51 @@
+use limine::LimineTerminalResponse;
+
+use crate::console::ConsoleSink;
+
+pub static mut LIMINE_TERMINAL: LimineTerminalSink = LimineTerminalSink::new();
+
+// Console sink writing to the first Limine terminal, only valid while bootloader memory is intact
+pub struct LimineTerminalSink {
+    response: Option<&'static LimineTerminalResponse>
+}
+
+impl LimineTerminalSink {
+    pub const fn new() -> Self {
+        LimineTerminalSink {
+            response: None
+        }
+    }
+
+    // Attach terminal response, returns false if it provides no usable terminal
+    pub fn attach(&mut self, response: &'static LimineTerminalResponse) -> bool {
+        if response.terminal_count == 0 || response.write().is_none() {
+            return false
+        }
+        self.response = Some(response);
+        true
+    }
+}
+
+impl ConsoleSink for LimineTerminalSink {
+    fn name(&self) -> &'static str {
+        "limine-terminal"
+    }
+
+    fn write_str(&mut self, s: &str) {
+        let r = match self.response {
+            None => return,
+            Some(r) => r
+        };
+
+        match r.write() {
+            None => { },
+            Some(w) => match r.terminals() {
+                None => { },
+                Some(a) => match a.first() {
+                    None => { },
+                    Some(t) => w(t, s)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,650 @@
+use core::fmt;
+use core::time::Duration;
+
+use crate::address_space;
+use crate::asm_wrappers::{cli, enable_and_hlt, rdtsc, sti, switch_context, SWITCH_CONTEXT_REGISTERS};
+use crate::console;
+use crate::fpu::{self, FpuState};
+use crate::gdt;
+use crate::percpu;
+use crate::sched::{self, Priority, ALL_CPUS};
+use crate::smp;
+use crate::stack::{self, KernelStack, StackOwner};
+use crate::sync::{self, SpinLock};
+use crate::syscall;
+use crate::timer;
+use crate::wait::WaitQueue;
+
+pub const MAX_THREADS: usize = 128;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    // Called exit, its stack is still in use until some other thread runs on its CPU
+    Exiting,
+    // Off its stack for good, waiting for join to free the stack and the slot
+    Exited
+}
+
+impl fmt::Display for ThreadState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Exiting => "exiting",
+            ThreadState::Exited => "exited"
+        })
+    }
+}
+
+struct Thread {
+    state: ThreadState,
+    // Saved stack pointer while not running, switch_context keeps everything else on the stack
+    rsp: usize,
+    // None for threads adopted from a CPU's boot context, whose stack is never freed
+    stack: Option<KernelStack>,
+    entry: Option<fn()>,
+    // Where user threads start out in user mode, instruction and stack pointer
+    user_start: Option<(usize, usize)>,
+    // Address space loaded while it runs, None for the kernel page table. User threads own theirs
+    address_space: Option<usize>,
+    // Idle threads only run on their CPU and never go in a run queue
+    idle: bool,
+    priority: Priority,
+    // CPUs the thread may run on
+    affinity: u64,
+    // CPU it last ran on, preferred when it becomes ready again
+    cpu: usize,
+    // Its stack is in use by some CPU, until the thread after it finished switching in
+    on_cpu: bool,
+    // Timer ticks left before others of its class get a turn
+    slice: u32,
+    // TSC when last switched in, and TSC ticks spent running before that
+    switched_in: u64,
+    runtime: u64,
+    // Times it was switched to
+    switches: u64,
+    fpu: FpuState
+}
+
+impl Thread {
+    fn new(state: ThreadState, entry: Option<fn()>, idle: bool, priority: Priority, affinity: u64) -> Self {
+        Thread {
+            state,
+            rsp: 0,
+            stack: None,
+            entry,
+            user_start: None,
+            address_space: None,
+            idle,
+            priority,
+            affinity,
+            cpu: smp::current_cpu(),
+            on_cpu: state == ThreadState::Running,
+            slice: priority.slice(),
+            switched_in: unsafe { rdtsc() },
+            runtime: 0,
+            switches: 0,
+            fpu: FpuState::new()
+        }
+    }
+}
+
+const NO_THREAD: Option<Thread> = None;
+
+// Slots never move, so raw pointers into a thread stay valid until it is joined
+static THREADS: SpinLock<[Option<Thread>; MAX_THREADS]> = SpinLock::new([NO_THREAD; MAX_THREADS]);
+
+// Woken whenever a thread finished exiting
+static EXITED: WaitQueue = WaitQueue::new();
+
+percpu! {
+    // Thread running on this CPU, None until the CPU adopted its boot context
+    static CURRENT: Option<usize> = None;
+    static IDLE: Option<usize> = None;
+    // Thread switched away from, finished by whichever thread runs next
+    static PREVIOUS: Option<usize> = None;
+}
+
+// Get id of thread running on this CPU
+pub fn current() -> Option<usize> {
+    CURRENT.get()
+}
+
+pub fn state(id: usize) -> Option<ThreadState> {
+    THREADS.lock_irqsave().get(id)?.as_ref().map(|t| t.state)
+}
+
+pub fn priority(id: usize) -> Option<Priority> {
+    THREADS.lock_irqsave().get(id)?.as_ref().map(|t| t.priority)
+}
+
+// Get CPUs thread may run on, none if it doesn't exist
+pub fn affinity(id: usize) -> u64 {
+    match THREADS.lock_irqsave().get(id) {
+        Some(Some(t)) => t.affinity,
+        _ => 0
+    }
+}
+
+// Put thread in a free slot, returns its id
+fn insert(thread: Thread) -> Option<usize> {
+    let mut threads = THREADS.lock_irqsave();
+    let id = threads.iter().position(|t| t.is_none())?;
+    threads[id] = Some(thread);
+    Some(id)
+}
+
+// Register context running on this CPU as a thread, it keeps whatever stack it is on. Idle threads stay on their CPU
+fn adopt(idle: bool) -> Option<usize> {
+    let (priority, affinity) = match idle {
+        true => (Priority::Idle, 1 << smp::current_cpu()),
+        false => (Priority::Normal, ALL_CPUS)
+    };
+    let id = insert(Thread::new(ThreadState::Running, None, idle, priority, affinity))?;
+
+    // The stack was the CPU's until now, overflows of it are the thread's from here on
+    let here = 0u8;
+    if let Some(s) = stack::containing(core::ptr::addr_of!(here) as usize) {
+        stack::set_owner(&s, StackOwner::Thread(id));
+    }
+
+    CURRENT.set(Some(id));
+    if idle {
+        IDLE.set(Some(id));
+    }
+    sched::switched(match idle {
+        true => None,
+        false => Some(priority)
+    });
+    unsafe {
+        fpu::switch_to(Some(fpu_of(id)));
+    }
+    Some(id)
+}
+
+fn fpu_of(id: usize) -> *mut FpuState {
+    &mut THREADS.lock_irqsave()[id].as_mut().unwrap().fpu as *mut FpuState
+}
+
+// Create thread that isn't runnable yet, with a stack that returns into thread_start
+fn create(entry: fn(), idle: bool, priority: Priority, affinity: u64) -> Option<usize> {
+    let id = insert(Thread::new(ThreadState::Blocked, Some(entry), idle, priority, affinity))?;
+    let s = match stack::allocate(StackOwner::Thread(id)) {
+        None => {
+            THREADS.lock_irqsave()[id] = None;
+            return None
+        },
+        Some(s) => s
+    };
+
+    // Callee-saved registers start out zero, above them the return address and a fake one for
+    // thread_start, which leaves the stack aligned as if thread_start had been called
+    let frame = (s.top() - (SWITCH_CONTEXT_REGISTERS + 2) * 8) as *mut usize;
+    unsafe {
+        for i in 0..SWITCH_CONTEXT_REGISTERS {
+            *frame.add(i) = 0;
+        }
+        *frame.add(SWITCH_CONTEXT_REGISTERS) = thread_start as *const () as usize;
+        *frame.add(SWITCH_CONTEXT_REGISTERS + 1) = 0;
+    }
+
+    let mut threads = THREADS.lock_irqsave();
+    let t = threads[id].as_mut().unwrap();
+    t.rsp   = frame as usize;
+    t.stack = Some(s);
+    Some(id)
+}
+
+// Spawn thread of normal priority running entry on any CPU, returns its id
+pub fn spawn(entry: fn()) -> Option<usize> {
+    spawn_with(entry, Priority::Normal, ALL_CPUS)
+}
+
+// Spawn thread running entry with priority on the CPUs in affinity, fails if none of them is online
+pub fn spawn_with(entry: fn(), priority: Priority, affinity: u64) -> Option<usize> {
+    if affinity & smp::online_mask() == 0 {
+        return None
+    }
+
+    let id = create(entry, false, priority, affinity)?;
+    make_ready(id);
+    Some(id)
+}
+
+// Spawn thread running in address space at user rip with user stack rsp. The thread owns the address space,
+// which is destroyed when the thread is joined
+pub fn spawn_user(space: usize, rip: usize, rsp: usize) -> Option<usize> {
+    let id = create(enter_user_mode, false, Priority::Normal, ALL_CPUS)?;
+    if let Some(t) = THREADS.lock_irqsave()[id].as_mut() {
+        t.user_start    = Some((rip, rsp));
+        t.address_space = Some(space);
+    }
+    make_ready(id);
+    Some(id)
+}
+
+// Entry of user threads, their address space is loaded by the time they first run
+fn enter_user_mode() {
+    let start = current().and_then(|id| THREADS.lock_irqsave()[id].as_ref().and_then(|t| t.user_start));
+    if let Some((rip, rsp)) = start {
+        unsafe {
+            syscall::enter_user(rip, rsp);
+        }
+    }
+}
+
+// Change priority of thread, taking effect the next time it is queued
+pub fn set_priority(id: usize, priority: Priority) -> bool {
+    match THREADS.lock_irqsave().get_mut(id) {
+        Some(Some(t)) if !t.idle => {
+            t.priority = priority;
+            true
+        },
+        _ => false
+    }
+}
+
+// Restrict thread to CPUs in mask, a running thread moves away at its next yield. Fails unless one is online
+pub fn set_affinity(id: usize, mask: u64) -> bool {
+    if mask & smp::online_mask() == 0 {
+        return false
+    }
+
+    match THREADS.lock_irqsave().get_mut(id) {
+        Some(Some(t)) if !t.idle => {
+            t.affinity = mask;
+            true
+        },
+        _ => false
+    }
+}
+
+// Queue thread on a CPU it may run on
+fn make_ready(id: usize) {
+    let (priority, affinity, cpu) = match THREADS.lock_irqsave()[id].as_mut() {
+        None => return,
+        Some(t) => {
+            t.state = ThreadState::Ready;
+            (t.priority, t.affinity, t.cpu)
+        }
+    };
+    sched::enqueue(id, priority, affinity, cpu);
+}
+
+// Switch from current thread to next, interrupts must be disabled. Returns once something switches back
+unsafe fn switch_to(next: usize) {
+    let prev = CURRENT.get().unwrap();
+    let now  = rdtsc();
+    let cpu  = smp::current_cpu();
+
+    let (prev_rsp, next_rsp, next_fpu, next_priority, next_stack, next_space) = {
+        let mut threads = THREADS.lock();
+        let p = threads[prev].as_mut().unwrap();
+        p.runtime += now - p.switched_in;
+        // Kernel threads may have loaded an address space of their own
+        p.address_space = address_space::current();
+        let prev_rsp = &mut p.rsp as *mut usize;
+
+        let n = threads[next].as_mut().unwrap();
+        n.state        = ThreadState::Running;
+        n.cpu          = cpu;
+        n.on_cpu       = true;
+        n.switched_in  = now;
+        n.switches    += 1;
+        if n.slice == 0 {
+            n.slice = n.priority.slice();
+        }
+        let next_priority = match n.idle {
+            true => None,
+            false => Some(n.priority)
+        };
+        (prev_rsp, n.rsp, &mut n.fpu as *mut FpuState, next_priority, n.stack.as_ref().map(|s| s.top()), n.address_space)
+    };
+
+    // Entering the kernel from user mode lands on top of the thread's kernel stack
+    if let Some(top) = next_stack {
+        gdt::set_kernel_stack(cpu, top);
+        percpu::set_syscall_stack(top);
+    }
+    match next_space {
+        Some(id) if address_space::current() != Some(id) => {
+            address_space::switch_to(id);
+        },
+        None if address_space::current().is_some() => address_space::switch_to_kernel(),
+        _ => { }
+    }
+
+    PREVIOUS.set(Some(prev));
+    CURRENT.set(Some(next));
+    sched::switched(next_priority);
+    fpu::switch_to(Some(next_fpu));
+    switch_context(prev_rsp, next_rsp);
+    finish_switch();
+}
+
+// Runs on the new thread right after a switch. Only now that its stack is no longer in use may the
+// previous thread be picked up by another CPU, or be joined
+fn finish_switch() {
+    let prev = match PREVIOUS.with(|p| p.take()) {
+        None => return,
+        Some(p) => p
+    };
+
+    // A thread woken while still on its CPU is left for us to queue, see wake
+    let (state, idle) = match THREADS.lock_irqsave()[prev].as_mut() {
+        None => return,
+        Some(t) => {
+            t.on_cpu = false;
+            (t.state, t.idle)
+        }
+    };
+    match state {
+        ThreadState::Ready if !idle => make_ready(prev),
+        ThreadState::Exiting => {
+            // Freeing the stack takes the page table lock and shoots down other CPUs, which join does with
+            // interrupts enabled
+            unsafe {
+                fpu::release(fpu_of(prev));
+            }
+            if let Some(t) = THREADS.lock_irqsave()[prev].as_mut() {
+                t.state = ThreadState::Exited;
+            }
+            EXITED.wake_all();
+        },
+        _ => { }
+    }
+}
+
+// First code of every spawned thread, switch_to returns here instead of to its caller
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    unsafe {
+        sti();
+    }
+
+    let entry = current().and_then(|id| THREADS.lock_irqsave()[id].as_ref().and_then(|t| t.entry));
+    if let Some(f) = entry {
+        f();
+    }
+    exit()
+}
+
+// Switch to most important ready thread of at least the current one's priority, interrupts must be
+// disabled. The current thread keeps running with a new slice if there is none, unless its affinity
+// no longer allows this CPU
+unsafe fn reschedule() {
+    let cur = match current() {
+        None => return,
+        Some(c) => c
+    };
+
+    let cpu = smp::current_cpu();
+    let (min, allowed) = match THREADS.lock()[cur].as_ref() {
+        None => return,
+        Some(t) => (t.priority, t.affinity & (1 << cpu) != 0)
+    };
+
+    let next = match sched::pick_next(min) {
+        Some(n) => n,
+        None if allowed => {
+            if let Some(t) = THREADS.lock()[cur].as_mut() {
+                t.slice = t.priority.slice();
+            }
+            return
+        },
+        None => match sched::pick_next(Priority::Idle).or_else(|| IDLE.get()) {
+            None => return,
+            Some(n) => n
+        }
+    };
+
+    if let Some(t) = THREADS.lock()[cur].as_mut() {
+        t.state = ThreadState::Ready;
+        t.slice = 0;
+    }
+    switch_to(next);
+}
+
+// Let another ready thread of the same or higher priority run, the current one goes to the back of its queue
+pub fn yield_now() {
+    let enabled = sync::save_and_disable_interrupts();
+    unsafe {
+        reschedule();
+    }
+    sync::restore_interrupts(enabled);
+}
+
+// Called at the end of a timer interrupt once the running thread should give way
+pub fn reschedule_from_interrupt() {
+    unsafe {
+        reschedule();
+    }
+}
+
+// Charge timer tick to running thread, returns true once its slice is used up
+pub fn tick() -> bool {
+    let cur = match current() {
+        None => return false,
+        Some(c) => c
+    };
+
+    match THREADS.lock_irqsave()[cur].as_mut() {
+        Some(t) if !t.idle => {
+            t.slice = t.slice.saturating_sub(1);
+            t.slice == 0
+        },
+        _ => false
+    }
+}
+
+// Mark current thread blocked ahead of block, interrupts must stay disabled until then. Whoever is
+// to wake it must be able to find it from now on, and wakeups from here on are not lost
+pub unsafe fn prepare_block() {
+    if let Some(cur) = current() {
+        if let Some(t) = THREADS.lock()[cur].as_mut() {
+            t.state = ThreadState::Blocked;
+        }
+    }
+}
+
+// Undo prepare_block instead of blocking, returns true if the thread was woken meanwhile
+pub unsafe fn cancel_block() -> bool {
+    let cur = match current() {
+        None => return false,
+        Some(c) => c
+    };
+
+    match THREADS.lock()[cur].as_mut() {
+        None => false,
+        Some(t) => {
+            let woken = t.state == ThreadState::Ready;
+            t.state = ThreadState::Running;
+            woken
+        }
+    }
+}
+
+// Switch away from current thread after prepare_block until it is woken, interrupts must be disabled.
+// Returns at once if it was woken already. Idle threads never block, they keep running instead
+pub unsafe fn block() {
+    let cur = match current() {
+        None => return,
+        Some(c) => c
+    };
+
+    let blocked = match THREADS.lock()[cur].as_ref() {
+        None => false,
+        Some(t) => t.state == ThreadState::Blocked && !t.idle
+    };
+    let next = match blocked {
+        false => None,
+        true => sched::pick_next(Priority::Idle).or_else(|| IDLE.get())
+    };
+    match next {
+        Some(n) if n != cur => switch_to(n),
+        _ => {
+            cancel_block();
+        }
+    }
+}
+
+// Make blocked thread ready, returns false if it wasn't blocked. Safe from interrupt handlers
+pub fn wake(id: usize) -> bool {
+    let enqueue = match THREADS.lock_irqsave().get_mut(id) {
+        Some(Some(t)) if t.state == ThreadState::Blocked => {
+            t.state = ThreadState::Ready;
+            // Not off its old stack yet, the thread switched to next queues it in finish_switch
+            !t.on_cpu
+        },
+        _ => return false
+    };
+    if enqueue {
+        make_ready(id);
+    }
+    true
+}
+
+fn wake_from_timer(id: usize) {
+    wake(id);
+}
+
+// Block current thread for at least duration. Returns early if the timer isn't ticking
+pub fn sleep(duration: Duration) {
+    let deadline = timer::deadline(duration);
+    let cur = match current() {
+        None => return,
+        Some(c) => c
+    };
+
+    while timer::ticks() < deadline {
+        let enabled = sync::save_and_disable_interrupts();
+        unsafe {
+            prepare_block();
+            match timer::add(deadline, wake_from_timer, cur) {
+                None => {
+                    cancel_block();
+                    sync::restore_interrupts(enabled);
+                    return
+                },
+                Some(t) => {
+                    block();
+                    timer::cancel(t);
+                }
+            }
+        }
+        sync::restore_interrupts(enabled);
+    }
+}
+
+// End current thread, its slot stays around until joined
+pub fn exit() -> ! {
+    let cur = current().unwrap();
+    unsafe {
+        cli();
+        match THREADS.lock()[cur].as_mut() {
+            Some(t) if !t.idle => t.state = ThreadState::Exiting,
+            _ => panic!("idle thread of CPU {} exited", smp::current_cpu())
+        }
+        let next = sched::pick_next(Priority::Idle).or_else(|| IDLE.get());
+        switch_to(next.unwrap());
+    }
+    unreachable!()
+}
+
+// Wait for thread to exit and free its slot, returns false if there is no such thread
+pub fn join(id: usize) -> bool {
+    join_timeout(id, None)
+}
+
+// Join, giving up once timeout passed. The thread is left alone then and can be joined again
+pub fn join_timeout(id: usize, timeout: Option<Duration>) -> bool {
+    if current() == Some(id) {
+        return false
+    }
+
+    EXITED.wait_until(timeout, || match state(id) {
+        None | Some(ThreadState::Exited) => true,
+        Some(_) => false
+    });
+    let thread = match state(id) {
+        Some(ThreadState::Exited) => THREADS.lock_irqsave()[id].take(),
+        _ => None
+    };
+    match thread {
+        None => false,
+        Some(t) => {
+            if let Some(s) = t.stack {
+                stack::free(s);
+            }
+            if let (Some(space), Some(_)) = (t.address_space, t.user_start) {
+                address_space::destroy(space);
+            }
+            true
+        }
+    }
+}
+
+fn idle_loop() {
+    loop {
+        unsafe {
+            // Announce that we are about to halt before looking for work, so nothing queued in between
+            // goes unnoticed. Interrupts stay off until the halt, so the wakeup can't slip in before it
+            cli();
+            sched::set_halted(true);
+            match sched::has_work() {
+                true => sti(),
+                false => enable_and_hlt()
+            }
+            sched::set_halted(false);
+        }
+        yield_now();
+    }
+}
+
+// Turn boot context of the bootstrap processor into the first thread and give the CPU an idle thread
+pub fn init() -> bool {
+    match adopt(false) {
+        None => false,
+        Some(_) => match create(idle_loop, true, Priority::Idle, 1 << smp::current_cpu()) {
+            None => false,
+            Some(id) => {
+                IDLE.set(Some(id));
+                true
+            }
+        }
+    }
+}
+
+// Turn boot context of an AP into its idle thread and start running threads
+pub fn run_idle() -> ! {
+    if adopt(true).is_none() {
+        panic!("no thread slot for idle thread of CPU {}", smp::current_cpu());
+    }
+    idle_loop();
+    unreachable!()
+}
+
+pub fn print_summary() {
+    let now = unsafe { rdtsc() };
+    let cur = current();
+    console::write_fmt(format_args!("id   state    priority cpu  runtime ms switches\n"));
+    let threads = THREADS.lock_irqsave();
+    for (id, t) in threads.iter().enumerate() {
+        if let Some(t) = t {
+            // The running thread's current stretch isn't in its runtime yet
+            let runtime = match Some(id) == cur {
+                true => t.runtime + now - t.switched_in,
+                false => t.runtime
+            };
+            console::write_fmt(format_args!("{:<4} {:<8} {:<8} {:<4} {:<10} {}{}\n", id, t.state, t.priority, t.cpu,
+                                            timer::tsc_to_ns(runtime) / 1_000_000, t.switches, match t.idle {
+                                                true => " (idle thread)",
+                                                false => ""
+                                            }));
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::apic;
+use crate::asm_wrappers::{inb, outb, rdtsc};
+use crate::sched;
+use crate::smp;
+use crate::softirq::{self, Softirq};
+use crate::sync::{Once, SpinLock};
+
+// Timer interrupts per second on every CPU
+pub const TICK_HZ: u64 = 100;
+
+const PIT_FREQUENCY:  u64 = 1_193_182;
+const PIT_CHANNEL2:   u16 = 0x42;
+const PIT_COMMAND:    u16 = 0x43;
+// Bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 reads its output
+const PIT_GATE:       u16 = 0x61;
+const GATE_ENABLE:    u8 = 1 << 0;
+const GATE_SPEAKER:   u8 = 1 << 1;
+const GATE_OUTPUT:    u8 = 1 << 5;
+// Channel 2, low then high byte, mode 0 (output goes high at terminal count), binary
+const PIT_CHANNEL2_ONESHOT: u8 = 0xB0;
+
+const CALIBRATION_MS: u64 = 10;
+// Give up on a PIT that never counts down, far more reads than 10 ms take anywhere
+const CALIBRATION_MAX_POLLS: usize = 100_000_000;
+
+// Rates measured against the PIT at boot
+#[derive(Clone, Copy)]
+struct Calibration {
+    apic_per_ms: u64,
+    tsc_per_ms: u64
+}
+
+static CALIBRATION: Once<Calibration> = Once::new();
+
+// Ticks since the boot CPU's timer started, only the boot CPU counts them
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+const MAX_TIMERS:  usize = 256;
+const WHEEL_SLOTS: usize = 64;
+
+// Handle of a pending timer, stays unique after the timer fired and its entry got reused
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: u16,
+    generation: u32
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    expires: u64,
+    callback: fn(usize),
+    arg: usize,
+    // Next timer in the same wheel slot
+    next: Option<u16>
+}
+
+const NO_TIMER: Option<Timer> = None;
+
+// Timers hashed by expiry tick into slots. Each tick only looks at its own slot, timers further than a
+// full turn away stay in it until their round comes
+struct Wheel {
+    timers: [Option<Timer>; MAX_TIMERS],
+    generations: [u32; MAX_TIMERS],
+    slots: [Option<u16>; WHEEL_SLOTS],
+    // Last tick whose slot was run
+    done: u64
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Wheel { timers: [NO_TIMER; MAX_TIMERS], generations: [0; MAX_TIMERS], slots: [None; WHEEL_SLOTS], done: 0 }
+    }
+
+    fn add(&mut self, expires: u64, callback: fn(usize), arg: usize) -> Option<TimerId> {
+        let index = self.timers.iter().position(|t| t.is_none())?;
+        // A timer already due fires on the next tick
+        let expires = expires.max(self.done + 1);
+        let slot = expires as usize % WHEEL_SLOTS;
+        self.timers[index] = Some(Timer { expires, callback, arg, next: self.slots[slot] });
+        self.slots[slot] = Some(index as u16);
+        Some(TimerId { index: index as u16, generation: self.generations[index] })
+    }
+
+    // Unlink timer from its slot and free its entry
+    fn remove(&mut self, index: usize) -> Option<Timer> {
+        let timer = self.timers[index]?;
+        let slot = timer.expires as usize % WHEEL_SLOTS;
+        match self.slots[slot] {
+            Some(i) if i as usize == index => self.slots[slot] = timer.next,
+            mut i => while let Some(j) = i {
+                let t = self.timers[j as usize].as_mut().unwrap();
+                if t.next == Some(index as u16) {
+                    t.next = timer.next;
+                    break
+                }
+                i = t.next;
+            }
+        }
+        self.timers[index] = None;
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        Some(timer)
+    }
+
+    fn cancel(&mut self, id: TimerId) -> bool {
+        match self.generations[id.index as usize] == id.generation {
+            false => false,
+            true => self.remove(id.index as usize).is_some()
+        }
+    }
+
+    // Remove a timer that is due by tick now, moving on to later slots once one has none left
+    fn take_expired(&mut self, now: u64) -> Option<Timer> {
+        while self.done < now {
+            let tick = self.done + 1;
+            let mut i = self.slots[tick as usize % WHEEL_SLOTS];
+            while let Some(j) = i {
+                let t = self.timers[j as usize].unwrap();
+                if t.expires <= tick {
+                    return self.remove(j as usize)
+                }
+                i = t.next;
+            }
+            self.done = tick;
+        }
+        None
+    }
+}
+
+// Only the boot CPU runs timers, the callbacks run in its timer softirq
+static WHEEL: SpinLock<Wheel> = SpinLock::new(Wheel::new());
+
+// Count down PIT channel 2 for a few milliseconds and see how far the APIC timer and TSC got meanwhile
+unsafe fn calibrate() -> Option<Calibration> {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+    let gate  = inb(PIT_GATE) & !(GATE_ENABLE | GATE_SPEAKER);
+    outb(PIT_GATE, gate);
+    outb(PIT_COMMAND, PIT_CHANNEL2_ONESHOT);
+    outb(PIT_CHANNEL2, count as u8);
+    outb(PIT_CHANNEL2, (count >> 8) as u8);
+
+    apic::start_timer(None, u32::MAX, false);
+    let tsc_start = rdtsc();
+    outb(PIT_GATE, gate | GATE_ENABLE);
+
+    let mut polls: usize = 0;
+    while inb(PIT_GATE) & GATE_OUTPUT == 0 {
+        polls += 1;
+        if polls == CALIBRATION_MAX_POLLS {
+            apic::stop_timer();
+            return None
+        }
+    }
+
+    let apic_elapsed = (u32::MAX - apic::timer_count()) as u64;
+    let tsc_elapsed  = rdtsc() - tsc_start;
+    apic::stop_timer();
+    outb(PIT_GATE, gate);
+
+    match apic_elapsed / CALIBRATION_MS {
+        0 => None,
+        apic_per_ms => Some(Calibration { apic_per_ms, tsc_per_ms: tsc_elapsed / CALIBRATION_MS })
+    }
+}
+
+// Calibrate timers and start ticking on the bootstrap processor, the local APIC must be set up
+pub unsafe fn init() -> bool {
+    match calibrate() {
+        None => false,
+        Some(c) => {
+            CALIBRATION.set(c);
+            init_cpu();
+            true
+        }
+    }
+}
+
+// Start periodic timer of this CPU, does nothing if calibration failed
+pub fn init_cpu() {
+    if let Some(c) = CALIBRATION.get() {
+        apic::start_timer(Some(apic::VECTOR_TIMER), (c.apic_per_ms * 1000 / TICK_HZ) as u32, true);
+    }
+}
+
+// Called by timer interrupt of every CPU
+pub fn handle_tick() {
+    if smp::current_cpu() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        softirq::raise(Softirq::Timer);
+    }
+    sched::tick();
+}
+
+// Call every timer due by now, without the wheel locked so callbacks may add timers
+pub fn run_softirq() {
+    let now = ticks();
+    loop {
+        let timer = WHEEL.lock_irqsave().take_expired(now);
+        match timer {
+            None => break,
+            Some(t) => (t.callback)(t.arg)
+        }
+    }
+}
+
+// Call callback with arg from the timer softirq once the tick count reaches expires. Fails if the
+// timer isn't ticking or too many timers are pending
+pub fn add(expires: u64, callback: fn(usize), arg: usize) -> Option<TimerId> {
+    match running() {
+        false => None,
+        true => WHEEL.lock_irqsave().add(expires, callback, arg)
+    }
+}
+
+// Stop pending timer, returns false if it already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    WHEEL.lock_irqsave().cancel(id)
+}
+
+// Check if calibration worked and ticks are counted
+pub fn running() -> bool {
+    CALIBRATION.get().is_some()
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos_per_tick = 1_000_000_000 / TICK_HZ as u128;
+    duration.as_nanos().div_ceil(nanos_per_tick) as u64
+}
+
+// Get tick count by which at least duration will have passed. The current tick is partly over already,
+// so it doesn't count
+pub fn deadline(duration: Duration) -> u64 {
+    ticks() + duration_to_ticks(duration) + 1
+}
+
+// TSC ticks per millisecond, zero if calibration failed
+pub fn tsc_per_ms() -> u64 {
+    CALIBRATION.get().map(|c| c.tsc_per_ms).unwrap_or(0)
+}
+
+pub fn tsc_to_ns(tsc: u64) -> u64 {
+    match tsc_per_ms() {
+        0 => 0,
+        rate => (tsc as u128 * 1_000_000 / rate as u128) as u64
+    }
+}
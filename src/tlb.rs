@@ -0,0 +1,216 @@
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering};
+
+use crate::apic;
+use crate::asm_wrappers::{invlpg, invpcid, lcr3, rcr3, read_cr4, write_cr4, Cr4};
+use crate::constants::{KERNEL_HALF_BASE, PAGE_SIZE};
+use crate::cpu::{self, Feature};
+use crate::smp;
+
+// Ranges longer than this are cheaper to flush by reloading CR3 than page by page
+const FLUSH_ALL_THRESHOLD: usize = 32;
+
+const INVPCID_ADDRESS:     u64 = 0;
+const INVPCID_CONTEXT:     u64 = 1;
+const INVPCID_ALL_GLOBAL:  u64 = 2;
+
+static PCID_ENABLED:      AtomicBool = AtomicBool::new(false);
+static INVPCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// Page count asking remote CPUs to flush the whole PCID
+const SHOOTDOWN_ALL: usize = usize::MAX;
+
+// Request of the one shootdown in flight, PENDING holds the CPUs that haven't flushed yet
+static SHOOTDOWN_LOCK:    AtomicBool  = AtomicBool::new(false);
+static SHOOTDOWN_PCID:    AtomicU16   = AtomicU16::new(0);
+static SHOOTDOWN_VADDR:   AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_PAGES:   AtomicUsize = AtomicUsize::new(0);
+static SHOOTDOWN_PENDING: AtomicU64   = AtomicU64::new(0);
+
+// Detect PCID and INVPCID support and enable process-context identifiers if available
+pub unsafe fn init() {
+    let pcid = cpu::has(Feature::Pcid);
+    INVPCID_SUPPORTED.store(cpu::has(Feature::Invpcid), Ordering::Relaxed);
+
+    // PCIDE may only be set while the current PCID is zero
+    if pcid && rcr3() & 0xFFF == 0 {
+        write_cr4(read_cr4() | Cr4::PCIDE);
+        PCID_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+// Check if CR3 loads carry a PCID
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+fn invpcid_supported() -> bool {
+    INVPCID_SUPPORTED.load(Ordering::Relaxed)
+}
+
+// Get PCID of currently loaded address space
+pub fn current_pcid() -> u16 {
+    match pcid_enabled() {
+        true => unsafe { (rcr3() & 0xFFF) as u16 },
+        false => 0
+    }
+}
+
+// Invalidate single page of current address space
+pub fn flush_page(vaddr: *const ()) {
+    unsafe {
+        invlpg(vaddr as usize);
+    }
+}
+
+// Invalidate range of pages of current address space, falling back to a full flush for long ranges
+pub fn flush_range(vaddr: *const (), num_pages: usize) {
+    // Kernel half translations are cached under every PCID, and invlpg only reaches the loaded one and
+    // global entries. Not everything in the kernel half is global, so every PCID has to go
+    if pcid_enabled() && vaddr as usize >= KERNEL_HALF_BASE {
+        flush_all_global();
+        return
+    }
+
+    if num_pages > FLUSH_ALL_THRESHOLD {
+        // Parts of the kernel half are mapped global, which CR3 reloads leave alone
+        match vaddr as usize >= KERNEL_HALF_BASE {
+            true => flush_all_global(),
+            false => flush_all()
+        }
+        return
+    }
+
+    for i in 0..num_pages {
+        flush_page((vaddr as usize + i * PAGE_SIZE) as *const ());
+    }
+}
+
+// Invalidate all non-global translations of current address space
+pub fn flush_all() {
+    unsafe {
+        lcr3(rcr3());
+    }
+}
+
+// Invalidate every translation including global ones
+pub fn flush_all_global() {
+    unsafe {
+        match invpcid_supported() && pcid_enabled() {
+            true => invpcid(INVPCID_ALL_GLOBAL, 0, 0),
+            // Toggling PGE flushes everything, for every PCID
+            false => {
+                let cr4 = read_cr4();
+                write_cr4(cr4.without(Cr4::PGE));
+                write_cr4(cr4);
+            }
+        }
+    }
+}
+
+// Invalidate every translation tagged with PCID
+pub fn flush_pcid(pcid: u16) {
+    // Without PCIDs every cached translation belongs to the loaded address space
+    if !pcid_enabled() || pcid == current_pcid() {
+        flush_all();
+        return
+    }
+
+    unsafe {
+        match invpcid_supported() {
+            true => invpcid(INVPCID_CONTEXT, pcid, 0),
+            false => flush_all_global()
+        }
+    }
+}
+
+// Invalidate range of pages in address space tagged with PCID, which needn't be the loaded one. Kernel half
+// ranges are flushed from every PCID
+pub fn flush_range_pcid(pcid: u16, vaddr: *const (), num_pages: usize) {
+    if !pcid_enabled() || pcid == current_pcid() || vaddr as usize >= KERNEL_HALF_BASE {
+        flush_range(vaddr, num_pages);
+        return
+    }
+
+    unsafe {
+        match invpcid_supported() && num_pages <= FLUSH_ALL_THRESHOLD {
+            true => for i in 0..num_pages {
+                invpcid(INVPCID_ADDRESS, pcid, vaddr as usize + i * PAGE_SIZE);
+            },
+            false => flush_pcid(pcid)
+        }
+    }
+}
+
+// Get bit of this CPU in CPU masks
+pub fn current_cpu_mask() -> u64 {
+    1 << smp::current_cpu()
+}
+
+// Flush on other online CPUs in mask through an IPI and wait until all of them did
+fn shootdown_remote(cpu_mask: u64, pcid: u16, vaddr: *const (), num_pages: usize) {
+    let targets = cpu_mask & smp::online_mask() & !current_cpu_mask();
+    if targets == 0 {
+        return
+    }
+
+    // Whoever holds the lock or is slow to flush may be waiting for us in turn, with interrupts disabled on
+    // either side, so requests aimed at this CPU are carried out while waiting
+    while SHOOTDOWN_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        poll_shootdown();
+        core::hint::spin_loop();
+    }
+    SHOOTDOWN_PCID.store(pcid, Ordering::Relaxed);
+    SHOOTDOWN_VADDR.store(vaddr as usize, Ordering::Relaxed);
+    SHOOTDOWN_PAGES.store(num_pages, Ordering::Relaxed);
+    SHOOTDOWN_PENDING.store(targets, Ordering::Release);
+
+    for cpu in (0..64).filter(|c| targets & (1 << c) != 0) {
+        if let Some(id) = smp::apic_id(cpu) {
+            apic::send_ipi(id, apic::VECTOR_TLB_SHOOTDOWN);
+        }
+    }
+    while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+        poll_shootdown();
+        core::hint::spin_loop();
+    }
+    SHOOTDOWN_LOCK.store(false, Ordering::Release);
+}
+
+// Carry out shootdown request on this CPU, called from the IPI handler
+pub fn handle_shootdown() {
+    // The request may have been polled already, and the next one needn't include us
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & current_cpu_mask() == 0 {
+        return
+    }
+    let pcid  = SHOOTDOWN_PCID.load(Ordering::Relaxed);
+    let vaddr = SHOOTDOWN_VADDR.load(Ordering::Relaxed) as *const ();
+    match SHOOTDOWN_PAGES.load(Ordering::Relaxed) {
+        SHOOTDOWN_ALL => flush_pcid(pcid),
+        num_pages => flush_range_pcid(pcid, vaddr, num_pages)
+    }
+    SHOOTDOWN_PENDING.fetch_and(!current_cpu_mask(), Ordering::AcqRel);
+}
+
+// Carry out shootdown aimed at this CPU if there is one. For loops spinning with interrupts disabled, where
+// the IPI can't get through
+pub fn poll_shootdown() {
+    if SHOOTDOWN_PENDING.load(Ordering::Relaxed) != 0 {
+        handle_shootdown();
+    }
+}
+
+// Invalidate range on every CPU in mask that may hold translations of the address space tagged with PCID
+pub fn shootdown(cpu_mask: u64, pcid: u16, vaddr: *const (), num_pages: usize) {
+    if cpu_mask & current_cpu_mask() != 0 {
+        flush_range_pcid(pcid, vaddr, num_pages);
+    }
+    shootdown_remote(cpu_mask, pcid, vaddr, num_pages);
+}
+
+// Invalidate every translation tagged with PCID on every CPU in mask
+pub fn shootdown_pcid(cpu_mask: u64, pcid: u16) {
+    if cpu_mask & current_cpu_mask() != 0 {
+        flush_pcid(pcid);
+    }
+    shootdown_remote(cpu_mask, pcid, core::ptr::null(), SHOOTDOWN_ALL);
+}
@@ -0,0 +1,108 @@
+use core::arch::global_asm;
+
+use crate::address_space;
+use crate::constants::{PAGE_SIZE, USER_HALF_END};
+use crate::elf::{self, ElfError};
+use crate::syscall;
+use crate::thread;
+use crate::usercopy;
+use crate::vma::{self, Backing};
+
+// Where user programs are placed, and their stack at the top of the user half below a guard gap
+pub const USER_CODE_BASE:  usize = 0x0000_0000_0040_0000;
+pub const USER_STACK_TOP:  usize = USER_HALF_END - 16 * PAGE_SIZE;
+pub const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+// Program printing a greeting through write and leaving through exit, position independent so it runs
+// wherever it is copied to
+global_asm!(
+    ".global user_demo_start",
+    ".global user_demo_end",
+    "user_demo_start:",
+    "mov eax, {write}",
+    "mov edi, 1",
+    "lea rsi, [rip + user_demo_message]",
+    "lea rdx, [rip + user_demo_end]",
+    "sub rdx, rsi",
+    "syscall",
+    "mov eax, {exit}",
+    "xor edi, edi",
+    "syscall",
+    "ud2",
+    "user_demo_message:",
+    ".ascii \"Hello from user mode!\\n\"",
+    "user_demo_end:",
+    write = const syscall::SYS_WRITE,
+    exit = const syscall::SYS_EXIT
+);
+
+extern "C" {
+    static user_demo_start: u8;
+    static user_demo_end: u8;
+}
+
+// Create address space holding a stack and code at USER_CODE_BASE, returns its id
+pub fn create_address_space(code: &[u8]) -> Option<usize> {
+    let id = address_space::create()?;
+    let code_len = (code.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let mapped = unsafe {
+        let mut space = address_space::get(id)?;
+        let pager = space.pager();
+        // Writable until the code is copied in
+        pager.mmap(Some(USER_CODE_BASE as *const ()), code_len, vma::VMA_READ | vma::VMA_WRITE | vma::VMA_USER,
+                   Backing::Anonymous).is_some()
+            && pager.mmap(Some((USER_STACK_TOP - USER_STACK_SIZE) as *const ()), USER_STACK_SIZE,
+                          vma::VMA_READ | vma::VMA_WRITE | vma::VMA_USER, Backing::Anonymous).is_some()
+    };
+    if !mapped || !load_code(id, code) {
+        address_space::destroy(id);
+        return None
+    }
+    Some(id)
+}
+
+// Copy code into address space and make it read-only and executable
+fn load_code(id: usize, code: &[u8]) -> bool {
+    let copied = address_space::with(id, || usercopy::copy_to_user(USER_CODE_BASE, code).is_ok()).unwrap_or(false);
+
+    let code_len = (code.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    copied && unsafe {
+        address_space::get(id).map(|mut s| {
+            s.pager().mprotect(USER_CODE_BASE as *const (), code_len, vma::VMA_READ | vma::VMA_EXEC | vma::VMA_USER)
+                .is_some()
+        }).unwrap_or(false)
+    }
+}
+
+// Start thread running code in a new address space in user mode, returns the thread id
+pub fn spawn(code: &[u8]) -> Option<usize> {
+    let space = create_address_space(code)?;
+    match thread::spawn_user(space, USER_CODE_BASE, USER_STACK_TOP) {
+        None => {
+            address_space::destroy(space);
+            None
+        },
+        Some(id) => Some(id)
+    }
+}
+
+// Start thread running ELF program in a new address space in user mode, returns the thread id
+pub fn spawn_elf(image: &[u8], argv: &[&str], envp: &[&str]) -> Result<usize, ElfError> {
+    let program = elf::load(image, argv, envp)?;
+    match thread::spawn_user(program.space, program.entry, program.stack) {
+        None => {
+            address_space::destroy(program.space);
+            Err(ElfError::NoMemory)
+        },
+        Some(id) => Ok(id)
+    }
+}
+
+// Get code of the demo program
+pub fn demo_program() -> &'static [u8] {
+    unsafe {
+        let start = core::ptr::addr_of!(user_demo_start);
+        let end   = core::ptr::addr_of!(user_demo_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
@@ -0,0 +1,77 @@
+use core::fmt;
+
+use crate::address_space::{self, AddressSpaceGuard};
+use crate::asm_wrappers::{clac, stac};
+use crate::constants::USER_HALF_END;
+use crate::cpu;
+
+// Why a user copy was refused, the copy itself never faults
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    // Range wraps around or reaches outside the user half
+    OutOfRange,
+    // Part of range isn't mapped for user access, or isn't writable when copying to it
+    NotMapped,
+    // No user address space is loaded
+    NoAddressSpace
+}
+
+impl fmt::Display for UserCopyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserCopyError::OutOfRange => write!(f, "range outside user half"),
+            UserCopyError::NotMapped => write!(f, "range not mapped for user access"),
+            UserCopyError::NoAddressSpace => write!(f, "no user address space loaded")
+        }
+    }
+}
+
+// Check range against the current address space and map whatever its areas still owe. The returned lock
+// keeps munmap and mprotect from taking the range away before the copy is done
+unsafe fn prepare(vaddr: usize, len: usize, write: bool) -> Result<AddressSpaceGuard, UserCopyError> {
+    match vaddr.checked_add(len) {
+        Some(end) if end <= USER_HALF_END => { },
+        _ => return Err(UserCopyError::OutOfRange)
+    }
+
+    let mut space = match address_space::current().and_then(address_space::get) {
+        None => return Err(UserCopyError::NoAddressSpace),
+        Some(s) => s
+    };
+    match space.pager().prepare_user_range(vaddr, len, write) {
+        true => Ok(space),
+        false => Err(UserCopyError::NotMapped)
+    }
+}
+
+// Run f with supervisor access to user pages allowed
+unsafe fn with_user_access<T>(f: impl FnOnce() -> T) -> T {
+    let smap = cpu::smap_enabled();
+    if smap {
+        stac();
+    }
+    let r = f();
+    if smap {
+        clac();
+    }
+    r
+}
+
+// Copy from user address in the current address space into kernel buffer
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), UserCopyError> {
+    unsafe {
+        // Every page is present now, so the copy doesn't fault into the lock held
+        let _space = prepare(src, dst.len(), false)?;
+        with_user_access(|| core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()));
+        Ok(())
+    }
+}
+
+// Copy kernel buffer to user address in the current address space
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), UserCopyError> {
+    unsafe {
+        let _space = prepare(dst, src.len(), true)?;
+        with_user_access(|| core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()));
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use crate::constants::PAGE_SIZE;
+
+pub const MAX_VMAS: usize = 128;
+
+// Area permissions
+pub const VMA_READ:  u64 = 1 << 0;
+pub const VMA_WRITE: u64 = 1 << 1;
+pub const VMA_EXEC:  u64 = 1 << 2;
+pub const VMA_USER:  u64 = 1 << 3;
+
+// What provides the contents of an area
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backing {
+    // Zero-filled memory allocated on first access
+    Anonymous,
+    // File contents starting at offset, populated by the file layer
+    File { file: usize, offset: usize },
+    // Physical device range such as MMIO, mapped uncached when the area is created
+    Device { paddr: usize },
+    // Physical frames shared with other address spaces, mapped when the area is created
+    Shared { paddr: usize }
+}
+
+impl Backing {
+    // Get backing of the part of an area starting provided number of bytes later
+    pub const fn advanced(&self, bytes: usize) -> Backing {
+        match *self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::File { file, offset } => Backing::File { file, offset: offset + bytes },
+            Backing::Device { paddr } => Backing::Device { paddr: paddr + bytes },
+            Backing::Shared { paddr } => Backing::Shared { paddr: paddr + bytes }
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Backing::Anonymous => "anonymous",
+            Backing::File { .. } => "file",
+            Backing::Device { .. } => "device",
+            Backing::Shared { .. } => "shared"
+        }
+    }
+}
+
+// Virtual memory area covering [start, end)
+#[derive(Clone, Copy, Debug)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: u64,
+    pub backing: Backing
+}
+
+impl Vma {
+    pub const fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub const fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    // Check if area directly followed by other one can be joined with it
+    fn can_merge(&self, next: &Vma) -> bool {
+        self.end == next.start
+            && self.prot == next.prot
+            && self.backing.advanced(self.len()) == next.backing
+    }
+}
+
+const NO_VMA: Vma = Vma { start: 0, end: 0, prot: 0, backing: Backing::Anonymous };
+
+// Areas of one address space, kept sorted by start address and never overlapping
+#[derive(Clone, Copy)]
+pub struct VmaTable {
+    areas: [Vma; MAX_VMAS],
+    len: usize
+}
+
+impl VmaTable {
+    pub const fn new() -> Self {
+        VmaTable {
+            areas: [NO_VMA; MAX_VMAS],
+            len: 0
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas[..self.len].iter()
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    // Index of first area ending after address
+    fn index_after(&self, addr: usize) -> usize {
+        self.areas[..self.len].partition_point(|a| a.end <= addr)
+    }
+
+    // Find area containing address
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        let i = self.index_after(addr);
+        match i < self.len && self.areas[i].contains(addr) {
+            false => None,
+            true => Some(&self.areas[i])
+        }
+    }
+
+    // Check if every byte of range belongs to some area
+    pub fn covers(&self, start: usize, end: usize) -> bool {
+        let mut cursor = start;
+        for a in self.areas[self.index_after(start)..self.len].iter() {
+            if cursor >= end || a.start > cursor {
+                break;
+            }
+            cursor = a.end;
+        }
+        cursor >= end
+    }
+
+    fn insert_at(&mut self, i: usize, vma: Vma) -> bool {
+        if self.len == MAX_VMAS {
+            return false
+        }
+
+        self.areas.copy_within(i..self.len, i + 1);
+        self.areas[i] = vma;
+        self.len += 1;
+        true
+    }
+
+    fn remove_at(&mut self, i: usize) {
+        self.areas.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+    }
+
+    // Add area, joining it with compatible neighbours, fails if it overlaps another one or the table is full
+    pub fn insert(&mut self, vma: Vma) -> bool {
+        if vma.start >= vma.end || !vma.start.is_multiple_of(PAGE_SIZE) || !vma.end.is_multiple_of(PAGE_SIZE) {
+            return false
+        }
+
+        let i = self.index_after(vma.start);
+        if i < self.len && self.areas[i].start < vma.end {
+            return false
+        }
+
+        let merge_prev = i > 0 && self.areas[i - 1].can_merge(&vma);
+        let merge_next = i < self.len && vma.can_merge(&self.areas[i]);
+        match (merge_prev, merge_next) {
+            (true, true) => {
+                self.areas[i - 1].end = self.areas[i].end;
+                self.remove_at(i);
+            },
+            (true, false) => self.areas[i - 1].end = vma.end,
+            (false, true) => {
+                self.areas[i].start   = vma.start;
+                self.areas[i].backing = vma.backing;
+            },
+            (false, false) => return self.insert_at(i, vma)
+        }
+        true
+    }
+
+    // Make address an area boundary, splitting the area containing it
+    pub fn split(&mut self, addr: usize) -> bool {
+        let i = self.index_after(addr);
+        if i == self.len || self.areas[i].start >= addr {
+            return true
+        }
+
+        let a = self.areas[i];
+        let upper = Vma { start: addr, end: a.end, prot: a.prot, backing: a.backing.advanced(addr - a.start) };
+        if !self.insert_at(i + 1, upper) {
+            return false
+        }
+        self.areas[i].end = addr;
+        true
+    }
+
+    // Join every pair of adjacent compatible areas
+    fn merge_all(&mut self) {
+        let mut i = 1;
+        while i < self.len {
+            match self.areas[i - 1].can_merge(&self.areas[i]) {
+                true => {
+                    self.areas[i - 1].end = self.areas[i].end;
+                    self.remove_at(i);
+                },
+                false => i += 1
+            }
+        }
+    }
+
+    // Remove range from areas, splitting ones that only partly overlap it
+    pub fn remove(&mut self, start: usize, end: usize) -> bool {
+        if !self.split(start) || !self.split(end) {
+            return false
+        }
+
+        let first = self.index_after(start);
+        let last  = self.index_after(end);
+        self.areas.copy_within(last..self.len, first);
+        self.len -= last - first;
+        true
+    }
+
+    // Change protection of areas in range, splitting ones that only partly overlap it
+    pub fn protect(&mut self, start: usize, end: usize, prot: u64) -> bool {
+        if !self.split(start) || !self.split(end) {
+            return false
+        }
+
+        let first = self.index_after(start);
+        let last  = self.index_after(end);
+        for a in self.areas[first..last].iter_mut() {
+            a.prot = prot;
+        }
+        self.merge_all();
+        true
+    }
+
+    // Find lowest page aligned gap of provided length between lo and hi
+    pub fn find_gap(&self, len: usize, lo: usize, hi: usize) -> Option<usize> {
+        let mut cursor = lo;
+        for a in self.areas[self.index_after(lo)..self.len].iter() {
+            if a.start >= hi {
+                break;
+            }
+            if a.start >= cursor && a.start - cursor >= len {
+                return Some(cursor)
+            }
+            cursor = core::cmp::max(cursor, a.end);
+        }
+
+        match cursor <= hi && hi - cursor >= len {
+            false => None,
+            true => Some(cursor)
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::percpu;
+use crate::sync::{self, SpinLock};
+use crate::thread::{self, MAX_THREADS};
+use crate::timer;
+
+// Threads waiting on a queue, oldest first. A thread is in it at most once
+struct Waiters {
+    ids: [u16; MAX_THREADS],
+    len: usize
+}
+
+impl Waiters {
+    const fn new() -> Self {
+        Waiters { ids: [0; MAX_THREADS], len: 0 }
+    }
+
+    fn push(&mut self, id: usize) {
+        self.ids[self.len] = id as u16;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        match self.len {
+            0 => None,
+            _ => {
+                let id = self.ids[0] as usize;
+                self.remove(id);
+                Some(id)
+            }
+        }
+    }
+
+    fn remove(&mut self, id: usize) {
+        if let Some(i) = self.ids[..self.len].iter().position(|w| *w as usize == id) {
+            self.ids.copy_within(i + 1..self.len, i);
+            self.len -= 1;
+        }
+    }
+}
+
+// Threads sleeping until some condition holds, woken by whoever makes it true, threads or interrupt
+// handlers. Waiters check their condition again after every wakeup, so a wakeup too many does no harm
+pub struct WaitQueue {
+    waiters: SpinLock<Waiters>
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: SpinLock::new(Waiters::new()) }
+    }
+
+    // Sleep until cond returns true or timeout passes, returns whether cond held. Cond runs with interrupts
+    // disabled. Timeouts need the timer, without it a wait with a timeout gives up as soon as it would sleep
+    pub fn wait_until(&self, timeout: Option<Duration>, mut cond: impl FnMut() -> bool) -> bool {
+        let deadline = timeout.map(timer::deadline);
+        loop {
+            if cond() {
+                return true
+            }
+            let expired = match deadline {
+                None => false,
+                Some(d) => timer::ticks() >= d
+            };
+            let cur = match (thread::current(), expired) {
+                (_, true) => return false,
+                // Nothing to block yet this early in boot, keep looking
+                (None, false) => continue,
+                (Some(c), false) => c
+            };
+            // Sleeping with a spinlock held or inside a handler would deadlock whoever is meant to wake us
+            if !percpu::preemptible() {
+                panic!("thread {} would sleep with preemption disabled, irq depth {}", cur, percpu::irq_depth());
+            }
+
+            // Blocked before going in the queue, so a wakeup coming after cond looked is never lost
+            let enabled = sync::save_and_disable_interrupts();
+            unsafe {
+                thread::prepare_block();
+            }
+            self.waiters.lock().push(cur);
+            let satisfied = cond();
+            let timer = match (satisfied, deadline) {
+                (false, Some(d)) => timer::add(d, wake_from_timer, cur),
+                _ => None
+            };
+            let give_up = !satisfied && deadline.is_some() && timer.is_none();
+
+            // A wakeup taken without needing it is passed on to the next waiter
+            let pass_on = match satisfied || give_up {
+                true => unsafe { thread::cancel_block() },
+                false => {
+                    unsafe {
+                        thread::block();
+                    }
+                    false
+                }
+            };
+            if let Some(t) = timer {
+                timer::cancel(t);
+            }
+            self.waiters.lock().remove(cur);
+            sync::restore_interrupts(enabled);
+
+            if pass_on {
+                self.wake_one();
+            }
+            if satisfied || give_up {
+                return satisfied
+            }
+        }
+    }
+
+    // Wake oldest waiter still asleep, returns false if there was none
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let id = self.waiters.lock_irqsave().pop();
+            match id {
+                None => return false,
+                Some(id) => if thread::wake(id) {
+                    return true
+                }
+            }
+        }
+    }
+
+    // Wake every waiter, returns how many were asleep
+    pub fn wake_all(&self) -> usize {
+        let mut woken: usize = 0;
+        loop {
+            let id = self.waiters.lock_irqsave().pop();
+            match id {
+                None => return woken,
+                Some(id) => woken += thread::wake(id) as usize
+            }
+        }
+    }
+}
+
+fn wake_from_timer(id: usize) {
+    thread::wake(id);
+}
+
+// Counting semaphore whose waiters sleep
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore { count: AtomicUsize::new(count), waiters: WaitQueue::new() }
+    }
+
+    pub fn try_down(&self) -> bool {
+        self.count.fetch_update(Ordering::Acquire, Ordering::Relaxed, |c| c.checked_sub(1)).is_ok()
+    }
+
+    // Take one unit, sleeping until one is available or timeout passes. Returns false on timeout
+    pub fn down(&self, timeout: Option<Duration>) -> bool {
+        self.waiters.wait_until(timeout, || self.try_down())
+    }
+
+    // Give back one unit, safe from interrupt handlers
+    pub fn up(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+// Lock whose waiters sleep instead of spinning, for long critical sections in thread context only
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> { }
+unsafe impl<T: Send> Send for Mutex<T> { }
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Mutex { locked: AtomicBool::new(false), waiters: WaitQueue::new(), data: UnsafeCell::new(data) }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        match self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(MutexGuard { mutex: self }),
+            Err(_) => None
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.lock_timeout(None).unwrap()
+    }
+
+    // Lock, giving up once timeout passed
+    pub fn lock_timeout(&self, timeout: Option<Duration>) -> Option<MutexGuard<'_, T>> {
+        let acquired = self.waiters.wait_until(timeout, || {
+            self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        });
+        match acquired {
+            false => None,
+            true => Some(MutexGuard { mutex: self })
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
@@ -0,0 +1,241 @@
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::console;
+use crate::sched::{Priority, ALL_CPUS};
+use crate::smp;
+use crate::sync::SpinLock;
+use crate::thread;
+use crate::timer::{self, TimerId};
+use crate::wait::WaitQueue;
+
+// Workers per pool at most, pools get one per online CPU up to this
+const MAX_WORKERS: usize = 4;
+
+// Function run by a worker thread, so it may sleep. Queueing it again before it started runs it only once
+pub struct Work {
+    func: fn(usize),
+    arg: usize,
+    pending: AtomicBool,
+    // Next work in the queue it is pending on
+    next: AtomicPtr<Work>
+}
+
+impl Work {
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        Work { func, arg, pending: AtomicBool::new(false), next: AtomicPtr::new(core::ptr::null_mut()) }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+// Work queued once a delay passed
+pub struct DelayedWork {
+    pub work: Work,
+    queue: AtomicPtr<WorkQueue>,
+    timer: SpinLock<Option<TimerId>>
+}
+
+impl DelayedWork {
+    pub const fn new(func: fn(usize), arg: usize) -> Self {
+        DelayedWork { work: Work::new(func, arg), queue: AtomicPtr::new(core::ptr::null_mut()), timer: SpinLock::new(None) }
+    }
+
+    // Stop work whose delay hasn't passed yet, returns false if it was queued already or never was
+    pub fn cancel(&self) -> bool {
+        let timer = self.timer.lock_irqsave().take();
+        match timer {
+            None => false,
+            Some(t) => timer::cancel(t)
+        }
+    }
+}
+
+// Intrusive list of pending work, oldest first
+struct WorkList {
+    head: Option<&'static Work>,
+    tail: Option<&'static Work>
+}
+
+impl WorkList {
+    const fn new() -> Self {
+        WorkList { head: None, tail: None }
+    }
+
+    fn push(&mut self, work: &'static Work) {
+        work.next.store(core::ptr::null_mut(), Ordering::Relaxed);
+        match self.tail {
+            None => self.head = Some(work),
+            Some(w) => w.next.store(work as *const Work as *mut Work, Ordering::Relaxed)
+        }
+        self.tail = Some(work);
+    }
+
+    fn pop(&mut self) -> Option<&'static Work> {
+        let work = self.head?;
+        self.head = unsafe { work.next.load(Ordering::Relaxed).as_ref() };
+        if self.head.is_none() {
+            self.tail = None;
+        }
+        Some(work)
+    }
+}
+
+// Pool of worker threads taking work off a shared queue
+pub struct WorkQueue {
+    name: &'static str,
+    priority: Priority,
+    work: SpinLock<WorkList>,
+    // Workers wait here for work, flush waits here for the queue to drain
+    more_work: WaitQueue,
+    drained: WaitQueue,
+    // Work queued or running
+    busy: AtomicUsize,
+    workers: AtomicUsize,
+    completed: AtomicU64
+}
+
+impl WorkQueue {
+    const fn new(name: &'static str, priority: Priority) -> Self {
+        WorkQueue {
+            name,
+            priority,
+            work: SpinLock::new(WorkList::new()),
+            more_work: WaitQueue::new(),
+            drained: WaitQueue::new(),
+            busy: AtomicUsize::new(0),
+            workers: AtomicUsize::new(0),
+            completed: AtomicU64::new(0)
+        }
+    }
+
+    // Queue work for the next free worker, returns false if it was pending already. Safe from interrupt handlers
+    pub fn queue(&self, work: &'static Work) -> bool {
+        if work.pending.swap(true, Ordering::AcqRel) {
+            return false
+        }
+        self.busy.fetch_add(1, Ordering::AcqRel);
+        self.work.lock_irqsave().push(work);
+        self.more_work.wake_one();
+        true
+    }
+
+    // Queue work once delay passed, returns false if it is pending or waiting for its delay already.
+    // Without a timer it is queued at once
+    pub fn queue_delayed(&'static self, work: &'static DelayedWork, delay: Duration) -> bool {
+        if work.work.is_pending() {
+            return false
+        }
+
+        let mut timer = work.timer.lock_irqsave();
+        if timer.is_some() {
+            return false
+        }
+        work.queue.store(self as *const WorkQueue as *mut WorkQueue, Ordering::Release);
+        match timer::add(timer::deadline(delay), queue_expired, work as *const DelayedWork as usize) {
+            None => {
+                drop(timer);
+                self.queue(&work.work)
+            },
+            Some(t) => {
+                *timer = Some(t);
+                true
+            }
+        }
+    }
+
+    // Wait until every work queued so far has run
+    pub fn flush(&self) {
+        self.flush_timeout(None);
+    }
+
+    // Flush, giving up once timeout passed. Returns false if work was still queued or running then
+    pub fn flush_timeout(&self, timeout: Option<Duration>) -> bool {
+        self.drained.wait_until(timeout, || self.busy.load(Ordering::Acquire) == 0)
+    }
+
+    fn run_worker(&self) {
+        loop {
+            let mut next: Option<&'static Work> = None;
+            self.more_work.wait_until(None, || {
+                next = self.work.lock_irqsave().pop();
+                next.is_some()
+            });
+
+            if let Some(w) = next {
+                // Cleared before running, so the work can queue itself again
+                w.pending.store(false, Ordering::Release);
+                (w.func)(w.arg);
+                self.completed.fetch_add(1, Ordering::Relaxed);
+                if self.busy.fetch_sub(1, Ordering::AcqRel) == 1 {
+                    self.drained.wake_all();
+                }
+            }
+        }
+    }
+
+    // Start workers, one per online CPU up to MAX_WORKERS, returns how many run
+    fn start(&self, entry: fn()) -> usize {
+        let wanted = smp::online_count().min(MAX_WORKERS);
+        while self.workers.load(Ordering::Relaxed) < wanted {
+            match thread::spawn_with(entry, self.priority, ALL_CPUS) {
+                None => break,
+                Some(_) => self.workers.fetch_add(1, Ordering::Relaxed)
+            };
+        }
+        self.workers.load(Ordering::Relaxed)
+    }
+
+    fn print_stats(&self) {
+        console::write_fmt(format_args!("{:<8} {:<8} {:<7} {:<7} {}\n", self.name, self.priority,
+                                        self.workers.load(Ordering::Relaxed), self.busy.load(Ordering::Relaxed),
+                                        self.completed.load(Ordering::Relaxed)));
+    }
+}
+
+// Timer callback of delayed work, arg is the work
+fn queue_expired(arg: usize) {
+    let work = unsafe { &*(arg as *const DelayedWork) };
+    let queue = work.queue.load(Ordering::Acquire);
+    // The delay is over, so the work may be delayed again
+    work.timer.lock_irqsave().take();
+    if let Some(q) = unsafe { queue.as_ref() } {
+        q.queue(&work.work);
+    }
+}
+
+// Queue for anything that can wait a bit
+pub static SYSTEM: WorkQueue = WorkQueue::new("system", Priority::Normal);
+// Queue for work that latency matters for, such as finishing device interrupts
+pub static HIGHPRI: WorkQueue = WorkQueue::new("highpri", Priority::RealTime);
+
+fn system_worker() {
+    SYSTEM.run_worker();
+}
+
+fn highpri_worker() {
+    HIGHPRI.run_worker();
+}
+
+// Queue work on the system queue
+pub fn queue_work(work: &'static Work) -> bool {
+    SYSTEM.queue(work)
+}
+
+pub fn queue_delayed_work(work: &'static DelayedWork, delay: Duration) -> bool {
+    SYSTEM.queue_delayed(work, delay)
+}
+
+// Start worker threads of every pool, needs the scheduler and every CPU up. Returns false if a pool got none
+pub fn init() -> bool {
+    SYSTEM.start(system_worker) != 0 && HIGHPRI.start(highpri_worker) != 0
+}
+
+pub fn print_stats() {
+    console::write_fmt(format_args!("queue    priority workers busy    completed\n"));
+    for q in [&SYSTEM, &HIGHPRI] {
+        q.print_stats();
+    }
+}